@@ -1,3 +1,6 @@
 fn main() {
-    bare_test_macros::build_test_setup!();
+    // the bare-metal test links with the bare-test script, host unit tests do not
+    if std::env::var("CARGO_CFG_TARGET_OS").as_deref() == Ok("none") {
+        bare_test_macros::build_test_setup!();
+    }
 }
@@ -1,4 +1,8 @@
 use alloc::string::String;
+use pci_types::BarWriteError;
+use rdif_intc::KError;
+
+use crate::PowerState;
 
 #[derive(Debug)]
 pub enum Error {
@@ -7,3 +11,81 @@ pub enum Error {
 }
 
 pub type Result<T = ()> = core::result::Result<T, Error>;
+
+/// Why the BARs of an endpoint could not be assigned. The endpoint is then
+/// left with decoding disabled and its BARs as found.
+#[derive(thiserror::Error, Debug, Clone, Copy)]
+pub enum BarAllocError {
+    #[error("no space for BAR{slot} of size {size:#x}")]
+    NoSpace { slot: u8, size: u64 },
+    #[error("write BAR{slot} fail: {err:?}")]
+    Write { slot: u8, err: BarWriteError },
+}
+
+/// Why MSI or MSI-X could not be set up on an endpoint. The endpoint is
+/// then left on INTx.
+#[derive(thiserror::Error, Debug)]
+pub enum MsiError {
+    #[error("no {0} capability")]
+    NoCapability(&'static str),
+    #[error("{requested} vectors requested, {supported} supported")]
+    TooMany { requested: usize, supported: usize },
+    #[error("message signaled interrupts already enabled")]
+    AlreadyEnabled,
+    #[error("message signaled interrupts not enabled")]
+    NotEnabled,
+    #[error("MSI-X table in BAR{0} cannot be mapped")]
+    TableUnmapped(u8),
+    #[error("message address {0:#x} out of reach of 32-bit MSI")]
+    AddressTooHigh(u64),
+    #[error("vector {0} cannot be masked")]
+    MaskUnsupported(usize),
+    #[error("allocate vectors fail: {0}")]
+    Alloc(KError),
+}
+
+/// Why a function could not be reset.
+#[derive(thiserror::Error, Debug, Clone, Copy)]
+pub enum ResetError {
+    #[error("{0} not supported")]
+    Unsupported(&'static str),
+    #[error("no bridge above the function")]
+    NoParentBridge,
+    #[error("function not ready after reset")]
+    NotReady,
+    #[error("no way to wait for the function")]
+    NoSleep,
+}
+
+/// Why a function could not be put in a power state.
+#[derive(thiserror::Error, Debug, Clone, Copy)]
+pub enum PowerStateError {
+    #[error("no power management capability")]
+    NoCapability,
+    #[error("{0:?} not supported")]
+    Unsupported(PowerState),
+    #[error("{from:?} to {to:?} needs a transition through D0")]
+    InvalidTransition { from: PowerState, to: PowerState },
+    #[error("no way to wait for the function")]
+    NoSleep,
+}
+
+/// Why the virtual functions of a physical function could not be enabled.
+/// The VFs are then left disabled.
+#[derive(thiserror::Error, Debug, Clone, Copy)]
+pub enum SriovError {
+    #[error("no SR-IOV capability")]
+    NoCapability,
+    #[error("virtual functions already enabled")]
+    AlreadyEnabled,
+    #[error("virtual functions not enabled")]
+    NotEnabled,
+    #[error("{requested} VFs requested, {total} supported")]
+    TooMany { requested: u16, total: u16 },
+    #[error("VF {index} would be on another bus than its PF")]
+    BusOutOfRange { index: u16 },
+    #[error("no space for VF BAR{slot} of {size:#x} per VF")]
+    NoSpace { slot: u8, size: u64 },
+    #[error("no way to wait for the virtual functions")]
+    NoSleep,
+}
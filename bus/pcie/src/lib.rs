@@ -8,14 +8,20 @@ extern crate log;
 mod bar_alloc;
 mod chip;
 pub mod err;
+#[cfg(test)]
+mod mock;
 mod root;
+mod sriov;
 mod types;
+mod window;
 
 pub use chip::PcieGeneric;
 pub use rdif_pcie::Interface as Controller;
-pub use rdif_pcie::{PciMem32, PciMem64, PcieController};
+pub use rdif_pcie::{BarOwner, BarPolicy, PciIo, PciMem32, PciMem64, PcieController, VfBars};
 
 pub use bar_alloc::*;
+pub use err::{BarAllocError, MsiError, PowerStateError, ResetError, SriovError};
 pub use types::*;
 
-pub use root::enumerate_by_controller;
+pub use root::{enumerate_by_controller, enumerate_by_controller_with, function_present};
+pub use sriov::{disable_vfs, enable_vfs, release_vfs, virtual_functions};
@@ -0,0 +1,207 @@
+//! In-memory config space for unit tests.
+//!
+//! Functions are looked up by address alone, bridges do not route: a
+//! function placed on bus 1 answers once a walk reaches bus 1. Registers
+//! keep the bits not set in their writable mask, which is what BAR sizing
+//! relies on. Writes are logged per function.
+
+extern crate std;
+
+use alloc::{collections::btree_map::BTreeMap, sync::Arc, vec::Vec};
+use std::sync::Mutex;
+
+use rdif_pcie::{DriverGeneric, Interface, KError, PciMem32, PciMem64};
+
+use crate::{chip::PcieController, PciAddress};
+
+const DWORDS: usize = 0x1000 / 4;
+
+/// Config space of one function.
+pub struct Function {
+    regs: [u32; DWORDS],
+    writable: [u32; DWORDS],
+    written: Vec<(u16, u32)>,
+}
+
+impl Function {
+    fn new(id: u32, class: u32, header_type: u8) -> Self {
+        let mut f = Self {
+            regs: [0; DWORDS],
+            writable: [!0; DWORDS],
+            written: Vec::new(),
+        };
+        f.regs[0] = id;
+        f.writable[0] = 0;
+        f.regs[2] = class;
+        f.writable[2] = 0;
+        f.regs[3] = (header_type as u32) << 16;
+        f.writable[3] = 0xff00_ffff;
+        // no BARs until added
+        f.writable[4..10].fill(0);
+        f
+    }
+
+    /// An Ethernet controller.
+    pub fn endpoint(vid: u16, did: u16) -> Self {
+        Self::new((did as u32) << 16 | vid as u32, 0x0200_0000, 0)
+    }
+
+    /// A PCI-PCI bridge with 16-bit I/O and 64-bit prefetchable windows.
+    pub fn bridge() -> Self {
+        let mut f = Self::new(0x0001_1b36, 0x0604_0000, 1);
+        f.writable[6] = 0x00ff_ffff;
+        f.writable[7] = 0x0000_f0f0;
+        f.writable[8] = 0xfff0_fff0;
+        f.regs[9] = 0x0001_0001;
+        f.writable[9] = 0xfff0_fff0;
+        f
+    }
+
+    /// A CardBus bridge, whose only BAR is the socket registers at 0x10.
+    pub fn cardbus_bridge() -> Self {
+        let mut f = Self::new(0x1234_104c, 0x0607_0000, 2);
+        f.writable[5..10].fill(!0);
+        f
+    }
+
+    /// A virtual function, its IDs read all ones.
+    pub fn virtual_function() -> Self {
+        Self::new(!0, 0x0200_0000, 0)
+    }
+
+    pub fn bar32(mut self, slot: usize, size: u32, prefetchable: bool) -> Self {
+        self.regs[4 + slot] = (prefetchable as u32) << 3;
+        self.writable[4 + slot] = !(size - 1) & !0xf;
+        self
+    }
+
+    pub fn bar64(mut self, slot: usize, size: u32, prefetchable: bool) -> Self {
+        self.regs[4 + slot] = 0b100 | (prefetchable as u32) << 3;
+        self.writable[4 + slot] = !(size - 1) & !0xf;
+        self.writable[5 + slot] = !0;
+        self
+    }
+
+    /// Add the legacy capability `id` at `offset` to the end of the list.
+    pub fn capability(mut self, offset: u16, id: u8) -> Self {
+        // Capabilities List in the status
+        self.regs[1] |= 1 << 20;
+        let mut link = 0x34;
+        while self.regs[link / 4] >> ((link % 4) * 8) & 0xfc != 0 {
+            link = (self.regs[link / 4] >> ((link % 4) * 8) & 0xfc) as usize + 1;
+        }
+        self.regs[link / 4] |= (offset as u32) << ((link % 4) * 8);
+        self.regs[offset as usize / 4] |= id as u32;
+        self
+    }
+
+    /// Add the extended capability `id` at `offset` to the end of the list,
+    /// which starts at 0x100.
+    pub fn ext_capability(mut self, offset: u16, id: u16) -> Self {
+        let mut at = 0x100;
+        assert!(
+            self.regs[at / 4] != 0 || offset == 0x100,
+            "the first extended capability is at 0x100"
+        );
+        if offset != 0x100 {
+            while self.regs[at / 4] >> 20 != 0 {
+                at = (self.regs[at / 4] >> 20) as usize;
+            }
+            self.regs[at / 4] |= (offset as u32) << 20;
+        }
+        self.regs[offset as usize / 4] = 1 << 16 | id as u32;
+        self
+    }
+
+    /// Set the dword at `offset`.
+    pub fn set(mut self, offset: u16, value: u32) -> Self {
+        self.regs[offset as usize / 4] = value;
+        self
+    }
+
+    /// Set the bits of the dword at `offset` writes may change.
+    pub fn writable(mut self, offset: u16, mask: u32) -> Self {
+        self.writable[offset as usize / 4] = mask;
+        self
+    }
+}
+
+/// Functions by address, shared with the controller it backs.
+#[derive(Clone, Default)]
+pub struct ConfigSpace(Arc<Mutex<BTreeMap<PciAddress, Function>>>);
+
+impl ConfigSpace {
+    pub fn add(&self, bus: u8, device: u8, function: u8, f: Function) -> PciAddress {
+        let address = PciAddress::new(0, bus, device, function);
+        self.0.lock().unwrap().insert(address, f);
+        address
+    }
+
+    /// A controller over the config space, with a 32-bit window at
+    /// 0x1000_0000 and a prefetchable 64-bit one at 0x80_0000_0000.
+    pub fn controller(&self) -> PcieController {
+        let mut controller = PcieController::new(self.clone());
+        controller.set_mem32(
+            PciMem32 {
+                address: 0x1000_0000,
+                size: 0x1000_0000,
+            },
+            false,
+        );
+        controller.set_mem64(
+            PciMem64 {
+                address: 0x80_0000_0000,
+                size: 0x1_0000_0000,
+            },
+            true,
+        );
+        controller
+    }
+
+    pub fn read(&self, address: PciAddress, offset: u16) -> u32 {
+        self.0
+            .lock()
+            .unwrap()
+            .get(&address)
+            .map_or(!0, |f| f.regs[offset as usize / 4])
+    }
+
+    pub fn write(&self, address: PciAddress, offset: u16, value: u32) {
+        if let Some(f) = self.0.lock().unwrap().get_mut(&address) {
+            f.written.push((offset, value));
+            let dw = offset as usize / 4;
+            f.regs[dw] = (f.regs[dw] & !f.writable[dw]) | (value & f.writable[dw]);
+        }
+    }
+}
+
+impl ConfigSpace {
+    /// Writes to the function at `address` so far, `(offset, value)`.
+    pub fn written(&self, address: PciAddress) -> Vec<(u16, u32)> {
+        self.0
+            .lock()
+            .unwrap()
+            .get(&address)
+            .map_or(Vec::new(), |f| f.written.clone())
+    }
+}
+
+impl DriverGeneric for ConfigSpace {
+    fn open(&mut self) -> Result<(), KError> {
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<(), KError> {
+        Ok(())
+    }
+}
+
+impl Interface for ConfigSpace {
+    fn read(&mut self, address: PciAddress, offset: u16) -> u32 {
+        ConfigSpace::read(self, address, offset)
+    }
+
+    fn write(&mut self, address: PciAddress, offset: u16, value: u32) {
+        ConfigSpace::write(self, address, offset, value)
+    }
+}
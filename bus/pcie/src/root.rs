@@ -1,9 +1,10 @@
-use alloc::vec::Vec;
+use alloc::{collections::btree_map::BTreeMap, vec::Vec};
 
 use crate::chip::PcieController;
 use crate::PciAddress;
-use crate::{Endpoint, PciConfigSpace, PciHeaderBase, PciPciBridge};
-use core::hint::spin_loop;
+use crate::{CardBusBridge, Endpoint, PciConfigSpace, PciHeaderBase, PciPciBridge, Unknown};
+use core::{hint::spin_loop, ops::Range};
+use rdif_pcie::SimpleBarAllocator;
 
 const MAX_DEVICE: u8 = 31;
 const MAX_FUNCTION: u8 = 7;
@@ -12,22 +13,50 @@ pub fn enumerate_by_controller<'a>(
     controller: &'a mut PcieController,
     range: Option<core::ops::Range<usize>>,
 ) -> impl Iterator<Item = Endpoint> + 'a {
-    let range = range.unwrap_or_else(|| 0..0x100);
+    enumerate_by_controller_with(controller, range, |_| true)
+}
 
-    PciIterator {
-        root: controller,
-        segment: 0,
-        bus_max: (range.end - 1) as _,
-        function: 0,
-        is_mulitple_function: false,
-        is_finish: false,
-        stack: alloc::vec![Bridge::root(range.start as _)],
+/// Like [`enumerate_by_controller`], but BARs are only assigned to endpoints
+/// for which `realloc` returns `true`. Used to rescan a bus without moving
+/// the BARs of functions already in use.
+pub fn enumerate_by_controller_with<'a>(
+    controller: &'a mut PcieController,
+    range: Option<core::ops::Range<usize>>,
+    realloc: impl FnMut(PciAddress) -> bool + 'a,
+) -> impl Iterator<Item = Endpoint> + 'a {
+    let range = range
+        .or_else(|| controller.bus_range.clone())
+        .unwrap_or(0..0x100);
+
+    let mut realloc = realloc;
+    let mut kept = BTreeMap::new();
+    if controller.bar_allocator.is_some() {
+        kept = crate::window::assign(controller, range.clone(), &mut realloc);
     }
+
+    let mut iter = PciIterator::new(controller, range, realloc);
+    iter.kept = kept;
+    iter
+}
+
+/// Whether a function responds at `address`, its vendor id reads `0xffff`
+/// once the device is gone.
+pub fn function_present(controller: &mut PcieController, address: PciAddress) -> bool {
+    PciHeaderBase::new(controller, address).is_some()
 }
 
-pub(crate) struct PciIterator<'a> {
+pub(crate) struct PciIterator<'a, F> {
     root: &'a mut PcieController,
+    realloc: F,
     segment: u16,
+    bus_start: u8,
+    /// Bridges found so far, as `(address, secondary bus)`.
+    pub(crate) bridges: Vec<(PciAddress, u8)>,
+    /// BARs already reserved where the firmware put them, by endpoint.
+    pub(crate) kept: BTreeMap<PciAddress, u8>,
+    /// Whether functions that are skipped are logged, off for walks that
+    /// are repeated by the enumeration.
+    pub(crate) log_skipped: bool,
     stack: Vec<Bridge>,
     bus_max: u8,
     function: u8,
@@ -35,7 +64,7 @@ pub(crate) struct PciIterator<'a> {
     is_finish: bool,
 }
 
-impl<'a> Iterator for PciIterator<'a> {
+impl<'a, F: FnMut(PciAddress) -> bool> Iterator for PciIterator<'a, F> {
     type Item = Endpoint;
 
     fn next(&mut self) -> Option<Self::Item> {
@@ -43,15 +72,21 @@ impl<'a> Iterator for PciIterator<'a> {
             if let Some(value) = self.get_current_valid() {
                 match value {
                     PciConfigSpace::PciPciBridge(pci_pci_bridge) => {
-                        self.next(Some(pci_pci_bridge));
+                        self.bridges.push((
+                            pci_pci_bridge.address(),
+                            pci_pci_bridge.secondary_bus_number(),
+                        ));
+                        self.next(Some(Upstream::Pci(pci_pci_bridge)));
                     }
                     PciConfigSpace::Endpoint(ep) => {
                         let item = ep;
                         self.next(None);
                         return Some(item);
                     }
-                    PciConfigSpace::CardBusBridge(_) | PciConfigSpace::Unknown(_) => {
-                        // Not handled for iteration; skip
+                    PciConfigSpace::CardBusBridge(card_bus_bridge) => {
+                        self.next(Some(Upstream::CardBus(card_bus_bridge)));
+                    }
+                    PciConfigSpace::Unknown(_) => {
                         self.next(None);
                     }
                 }
@@ -63,7 +98,39 @@ impl<'a> Iterator for PciIterator<'a> {
     }
 }
 
-impl<'a> PciIterator<'a> {
+impl<'a, F: FnMut(PciAddress) -> bool> PciIterator<'a, F> {
+    pub(crate) fn new(root: &'a mut PcieController, range: Range<usize>, realloc: F) -> Self {
+        Self {
+            segment: root.segment,
+            root,
+            realloc,
+            bus_start: range.start as _,
+            bridges: Vec::new(),
+            kept: BTreeMap::new(),
+            log_skipped: true,
+            bus_max: range.end.saturating_sub(1) as _,
+            function: 0,
+            is_mulitple_function: false,
+            // nothing to walk, and `bus_max` would not bound it
+            is_finish: range.is_empty(),
+            stack: alloc::vec![Bridge::root(range.start as _)],
+        }
+    }
+
+    pub(crate) fn controller(&self) -> &PcieController {
+        self.root
+    }
+
+    /// Allocator for BARs of endpoints on `bus`: the controller's for the
+    /// root bus, else the one over the windows of the bridge leading to it.
+    fn allocator(&mut self, bus: u8) -> Option<&mut SimpleBarAllocator> {
+        if bus == self.bus_start {
+            self.root.bar_allocator.as_mut()
+        } else {
+            self.root.bridge_allocators.get_mut(&bus)
+        }
+    }
+
     fn get_current_valid(&mut self) -> Option<PciConfigSpace> {
         let address = self.address();
         let header_base = PciHeaderBase::new(self.root, address)?;
@@ -71,40 +138,69 @@ impl<'a> PciIterator<'a> {
 
         match header_base.header_type() {
             pci_types::HeaderType::Endpoint => {
-                let bl = self.root.bar_allocator.as_mut();
-                let ep = Endpoint::new(header_base, bl);
+                let kept = self.kept.remove(&address).unwrap_or(0);
+                let bl = if (self.realloc)(address) {
+                    self.allocator(address.bus())
+                } else {
+                    None
+                };
+                let mut ep = Endpoint::new(header_base, bl, kept);
+                ep.upstream = self.stack[1..]
+                    .iter()
+                    .map(|parent| parent.bridge.address())
+                    .collect();
+                ep.parent = ep
+                    .upstream
+                    .last()
+                    .map(|&bridge| self.root.config_access(bridge));
                 Some(PciConfigSpace::Endpoint(ep))
             }
             pci_types::HeaderType::PciPciBridge => {
+                let secondary_bus = self.next_bus()?;
                 let mut bridge = PciPciBridge::new(header_base);
-                let primary_bus = address.bus();
-                let secondary_bus;
-
-                if let Some(parent) = self.stack.last_mut() {
-                    if parent.bridge.subordinate_bus_number() == self.bus_max {
-                        return None;
-                    }
-
-                    secondary_bus = parent.bridge.subordinate_bus_number() + 1;
-                } else {
-                    panic!("no parent");
-                }
-                let subordinate_bus = secondary_bus;
                 bridge.update_bus_number(|mut bus| {
-                    bus.primary = primary_bus;
+                    bus.primary = address.bus();
                     bus.secondary = secondary_bus;
-                    bus.subordinate = subordinate_bus;
+                    bus.subordinate = secondary_bus;
                     bus
                 });
 
                 Some(PciConfigSpace::PciPciBridge(bridge))
             }
-            pci_types::HeaderType::CardBusBridge => todo!(),
-            pci_types::HeaderType::Unknown(_) => todo!(),
+            pci_types::HeaderType::CardBusBridge => {
+                let secondary_bus = self.next_bus()?;
+                let mut bridge = CardBusBridge::new(header_base);
+                bridge.update_bus_number(|mut bus| {
+                    bus.primary = address.bus();
+                    bus.secondary = secondary_bus;
+                    bus.subordinate = secondary_bus;
+                    bus
+                });
+
+                Some(PciConfigSpace::CardBusBridge(bridge))
+            }
+            pci_types::HeaderType::Unknown(header_type) => {
+                if self.log_skipped {
+                    log::warn!("{address}: unknown header type {header_type:#x}, skipped");
+                }
+                Some(PciConfigSpace::Unknown(Unknown::new(header_base)))
+            }
             _ => unreachable!(),
         }
     }
 
+    /// Secondary bus for a bridge just found: the bus after the last one
+    /// taken, `None` once the range is used up.
+    fn next_bus(&self) -> Option<u8> {
+        let Some(parent) = self.stack.last() else {
+            panic!("no parent");
+        };
+        if parent.bridge.subordinate_bus_number() == self.bus_max {
+            return None;
+        }
+        Some(parent.bridge.subordinate_bus_number() + 1)
+    }
+
     fn address(&self) -> PciAddress {
         let parent = self.stack.last().unwrap();
         let bus = parent.bridge.secondary_bus_number();
@@ -152,15 +248,12 @@ impl<'a> PciIterator<'a> {
         false
     }
 
-    fn next(&mut self, current_bridge: Option<PciPciBridge>) {
+    fn next(&mut self, current_bridge: Option<Upstream>) {
         if let Some(bridge) = current_bridge {
             for parent in &mut self.stack {
                 // parent.header.subordinate_bus += 1;
 
-                parent.bridge.update_bus_number(|mut bus| {
-                    bus.subordinate += 1;
-                    bus
-                });
+                parent.bridge.grow_subordinate();
             }
 
             self.stack.push(Bridge { bridge, device: 0 });
@@ -178,15 +271,58 @@ impl<'a> PciIterator<'a> {
 }
 
 struct Bridge {
-    bridge: PciPciBridge,
+    bridge: Upstream,
     device: u8,
 }
 
 impl Bridge {
     fn root(bus_start: u8) -> Self {
         Self {
-            bridge: PciPciBridge::root(),
-            device: bus_start,
+            bridge: Upstream::Pci(PciPciBridge::root(bus_start)),
+            device: 0,
+        }
+    }
+}
+
+/// A bridge the walk descends through.
+enum Upstream {
+    Pci(PciPciBridge),
+    CardBus(CardBusBridge),
+}
+
+impl Upstream {
+    fn address(&self) -> PciAddress {
+        match self {
+            Self::Pci(bridge) => bridge.address(),
+            Self::CardBus(bridge) => bridge.address(),
+        }
+    }
+
+    fn secondary_bus_number(&self) -> u8 {
+        match self {
+            Self::Pci(bridge) => bridge.secondary_bus_number(),
+            Self::CardBus(bridge) => bridge.secondary_bus_number(),
+        }
+    }
+
+    fn subordinate_bus_number(&self) -> u8 {
+        match self {
+            Self::Pci(bridge) => bridge.subordinate_bus_number(),
+            Self::CardBus(bridge) => bridge.subordinate_bus_number(),
+        }
+    }
+
+    /// Count one more bus behind the bridge.
+    fn grow_subordinate(&mut self) {
+        match self {
+            Self::Pci(bridge) => bridge.update_bus_number(|mut bus| {
+                bus.subordinate += 1;
+                bus
+            }),
+            Self::CardBus(bridge) => bridge.update_bus_number(|mut bus| {
+                bus.subordinate += 1;
+                bus
+            }),
         }
     }
 }
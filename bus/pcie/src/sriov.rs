@@ -0,0 +1,625 @@
+//! Virtual functions of SR-IOV physical functions.
+//!
+//! VF `n` of a PF answers at the routing ID of the PF plus First VF Offset
+//! plus `n` times VF Stride. Its vendor and device IDs read all ones and its
+//! BARs 0: the IDs come from the PF and its SR-IOV capability, each BAR is a
+//! slice of the matching VF BAR of the capability, which covers all VFs.
+//!
+//! [`enable_vfs`] takes the memory of the VF BARs from the allocator of the
+//! PF's bus and records it in [`PcieController::vf_bars`]. Bridge windows
+//! are sized with room for the VF BARs of every VF a PF supports.
+
+use alloc::{boxed::Box, vec::Vec};
+use core::time::Duration;
+
+use bit_field::BitField;
+use log::{debug, warn};
+use pci_types::{Bar, HeaderType};
+use rdif_pcie::VfBars;
+
+use crate::{chip::PcieController, err::SriovError, Endpoint, PciAddress, PciHeaderBase, SrIov};
+
+const VF_ENABLE: usize = 0;
+const VF_MEMORY_ENABLE: usize = 3;
+/// Before config requests to VFs just enabled.
+const VF_ENABLE_DELAY: Duration = Duration::from_millis(100);
+/// Before the VFs may be enabled again after disabling them.
+const VF_DISABLE_DELAY: Duration = Duration::from_secs(1);
+
+/// Where a VF comes from, and its BARs.
+pub(crate) struct VirtFn {
+    pf: PciAddress,
+    index: u16,
+    pub(crate) bars: [Option<Bar>; 6],
+}
+
+/// Memory the VF BAR at `slot` takes for all VFs.
+pub(crate) struct VfRegion {
+    pub slot: u8,
+    pub size: u64,
+    pub is_64bit: bool,
+    pub prefetchable: bool,
+}
+
+impl Endpoint {
+    /// Whether the endpoint is a virtual function.
+    pub fn is_virtfn(&self) -> bool {
+        self.virtfn.is_some()
+    }
+
+    /// The physical function of a virtual function.
+    pub fn physical_function(&self) -> Option<PciAddress> {
+        self.virtfn.as_ref().map(|virtfn| virtfn.pf)
+    }
+
+    /// Index of a virtual function among the VFs of its PF.
+    pub fn vf_index(&self) -> Option<u16> {
+        self.virtfn.as_ref().map(|virtfn| virtfn.index)
+    }
+}
+
+/// Enable `num_vfs` virtual functions of `pf`, assigning their BARs from
+/// the allocator of the bus `pf` sits on. `sleep` waits for the given time,
+/// returning `false` if it cannot, which fails before `pf` is touched.
+///
+/// The VFs are found with [`virtual_functions`] afterwards.
+pub fn enable_vfs(
+    controller: &mut PcieController,
+    pf: &Endpoint,
+    num_vfs: u16,
+    sleep: impl Fn(Duration) -> bool,
+) -> Result<(), SriovError> {
+    let sriov = pf.sriov().ok_or(SriovError::NoCapability)?;
+    if sriov.vf_enabled() {
+        return Err(SriovError::AlreadyEnabled);
+    }
+    let total = sriov.total_vfs();
+    if num_vfs == 0 || num_vfs > total {
+        return Err(SriovError::TooMany {
+            requested: num_vfs,
+            total,
+        });
+    }
+    if !sleep(Duration::ZERO) {
+        return Err(SriovError::NoSleep);
+    }
+
+    // VF BARs are sized and aligned in pages of 4 KiB, always supported.
+    pf.write(sriov.offset + 0x20, 1);
+    // First VF Offset and VF Stride depend on NumVFs.
+    set_num_vfs(pf, sriov, num_vfs);
+    if let Some(index) = (0..num_vfs).find(|&index| vf_address(pf, sriov, index).is_none()) {
+        set_num_vfs(pf, sriov, 0);
+        return Err(SriovError::BusOutOfRange { index });
+    }
+
+    let mut bars = size_vf_bars(pf, sriov);
+    let owner = controller.bar_owner(pf.address().bus());
+    let mut allocator = controller.allocator(owner);
+    let mut taken = Vec::new();
+    for region in regions(&bars, num_vfs) {
+        let address = allocator.as_mut().and_then(|allocator| {
+            if region.is_64bit {
+                allocator.alloc_memory64(region.size, region.prefetchable)
+            } else {
+                let size = u32::try_from(region.size).ok()?;
+                allocator
+                    .alloc_memory32(size, region.prefetchable)
+                    .map(u64::from)
+            }
+        });
+        let Some(address) = address else {
+            if let Some(allocator) = allocator {
+                for (address, size) in taken {
+                    allocator.free(address, size);
+                }
+            }
+            set_num_vfs(pf, sriov, 0);
+            return Err(SriovError::NoSpace {
+                slot: region.slot,
+                size: bar_size(bars[region.slot as usize]),
+            });
+        };
+        taken.push((address, region.size));
+
+        let offset = vf_bar_offset(sriov, region.slot);
+        let low = pf.read(offset) & 0xf;
+        pf.write(offset, low | address as u32);
+        if region.is_64bit {
+            pf.write(offset + 4, (address >> 32) as u32);
+        }
+        bars[region.slot as usize] = bars[region.slot as usize].map(|bar| match bar {
+            Bar::Memory32 {
+                size, prefetchable, ..
+            } => Bar::Memory32 {
+                address: address as u32,
+                size,
+                prefetchable,
+            },
+            Bar::Memory64 {
+                size, prefetchable, ..
+            } => Bar::Memory64 {
+                address,
+                size,
+                prefetchable,
+            },
+            bar => bar,
+        });
+    }
+    controller.vf_bars.insert(
+        pf.address(),
+        VfBars {
+            num_vfs,
+            bars,
+            owner: Some(owner),
+        },
+    );
+
+    let mut control = sriov.control();
+    control.set_bit(VF_ENABLE, true);
+    control.set_bit(VF_MEMORY_ENABLE, true);
+    set_control(pf, sriov, control);
+    sleep(VF_ENABLE_DELAY);
+    debug!("{} enabled {num_vfs} VFs", pf.address());
+    Ok(())
+}
+
+/// Disable the virtual functions of `pf` and give their BARs back, `sleep`
+/// as for [`enable_vfs`].
+pub fn disable_vfs(
+    controller: &mut PcieController,
+    pf: &Endpoint,
+    sleep: impl Fn(Duration) -> bool,
+) -> Result<(), SriovError> {
+    let sriov = pf.sriov().ok_or(SriovError::NoCapability)?;
+    if !sriov.vf_enabled() {
+        return Err(SriovError::NotEnabled);
+    }
+    if !sleep(Duration::ZERO) {
+        return Err(SriovError::NoSleep);
+    }
+    let mut control = sriov.control();
+    control.set_bit(VF_ENABLE, false);
+    control.set_bit(VF_MEMORY_ENABLE, false);
+    set_control(pf, sriov, control);
+    sleep(VF_DISABLE_DELAY);
+    set_num_vfs(pf, sriov, 0);
+    release_vfs(controller, pf.address());
+    Ok(())
+}
+
+/// Give back the memory of the VF BARs of `pf` without touching its config
+/// space, for a PF that is gone.
+pub fn release_vfs(controller: &mut PcieController, pf: PciAddress) {
+    let Some(VfBars {
+        num_vfs,
+        bars,
+        owner: Some(owner),
+    }) = controller.vf_bars.remove(&pf)
+    else {
+        return;
+    };
+    for region in regions(&bars, num_vfs) {
+        let address = match bars[region.slot as usize] {
+            Some(Bar::Memory32 { address, .. }) => address as u64,
+            Some(Bar::Memory64 { address, .. }) => address,
+            _ => continue,
+        };
+        if !controller.free_bar(owner, address, region.size) {
+            warn!(
+                "VF BAR{} {address:#x} of {pf} was not allocated",
+                region.slot
+            );
+        }
+    }
+}
+
+/// The enabled virtual functions of `pf`, none if it has no SR-IOV
+/// capability or its VFs are disabled.
+///
+/// VF BARs not assigned by [`enable_vfs`] are sized on the PF the first
+/// time, with VF memory decoding off meanwhile, and recorded in
+/// [`PcieController::vf_bars`].
+pub fn virtual_functions(controller: &mut PcieController, pf: PciAddress) -> Vec<Endpoint> {
+    let Some(base) = PciHeaderBase::new(controller, pf) else {
+        return Vec::new();
+    };
+    let Some(sriov) = base.sriov().filter(|sriov| sriov.vf_enabled()) else {
+        return Vec::new();
+    };
+    let bars = match controller.vf_bars.get(&pf) {
+        Some(vf_bars) => vf_bars.bars,
+        None => {
+            let control = sriov.control();
+            let mut off = control;
+            off.set_bit(VF_MEMORY_ENABLE, false);
+            set_control(&base, sriov, off);
+            let bars = size_vf_bars(&base, sriov);
+            set_control(&base, sriov, control);
+            controller.vf_bars.insert(
+                pf,
+                VfBars {
+                    num_vfs: sriov.num_vfs(),
+                    bars,
+                    owner: None,
+                },
+            );
+            bars
+        }
+    };
+    let vid = base.vendor_id();
+    let did = sriov.vf_device_id();
+
+    (0..sriov.num_vfs())
+        .filter_map(|index| {
+            let address = vf_address(&base, sriov, index)?;
+            let vf = PciHeaderBase::virtual_function(controller, address, vid, did);
+            if vf.header_type() != HeaderType::Endpoint {
+                warn!(
+                    "VF {address} of {pf} has header type {:?}",
+                    vf.header_type()
+                );
+                return None;
+            }
+            let mut ep = Endpoint::new(vf, None, 0);
+            ep.virtfn = Some(Box::new(VirtFn {
+                pf,
+                index,
+                bars: bars.map(|bar| bar.map(|bar| vf_bar(bar, index))),
+            }));
+            Some(ep)
+        })
+        .collect()
+}
+
+/// Memory the VF BARs of `pf` need for every VF it supports, for sizing the
+/// bridge windows. None once VFs are enabled, their BARs are then in use.
+pub(crate) fn max_regions(pf: &PciHeaderBase) -> Vec<VfRegion> {
+    let Some(sriov) = pf.sriov() else {
+        return Vec::new();
+    };
+    if sriov.vf_enabled() {
+        return Vec::new();
+    }
+    regions(&size_vf_bars(pf, sriov), sriov.total_vfs())
+}
+
+/// The VF BARs as the same BAR repeated for `num_vfs` VFs. A region is a
+/// power of two, like BARs, and so aligned to the BAR of one VF.
+fn regions(bars: &[Option<Bar>; 6], num_vfs: u16) -> Vec<VfRegion> {
+    bars.iter()
+        .enumerate()
+        .filter_map(|(slot, bar)| {
+            let (is_64bit, prefetchable) = match (*bar)? {
+                Bar::Memory32 { prefetchable, .. } => (false, prefetchable),
+                Bar::Memory64 { prefetchable, .. } => (true, prefetchable),
+                Bar::Io { .. } => return None,
+            };
+            Some(VfRegion {
+                slot: slot as u8,
+                size: (bar_size(*bar) * num_vfs as u64).next_power_of_two(),
+                is_64bit,
+                prefetchable,
+            })
+        })
+        .collect()
+}
+
+/// BAR `bar` of the first VF moved to VF `index`.
+fn vf_bar(bar: Bar, index: u16) -> Bar {
+    let skip = bar_size(Some(bar)) * index as u64;
+    match bar {
+        Bar::Memory32 {
+            address,
+            size,
+            prefetchable,
+        } => Bar::Memory32 {
+            address: address + skip as u32,
+            size,
+            prefetchable,
+        },
+        Bar::Memory64 {
+            address,
+            size,
+            prefetchable,
+        } => Bar::Memory64 {
+            address: address + skip,
+            size,
+            prefetchable,
+        },
+        bar => bar,
+    }
+}
+
+fn bar_size(bar: Option<Bar>) -> u64 {
+    match bar {
+        Some(Bar::Memory32 { size, .. }) => size as u64,
+        Some(Bar::Memory64 { size, .. }) => size,
+        _ => 0,
+    }
+}
+
+/// The VF BARs, with the address programmed and the size of one VF. VF
+/// memory decoding must be off.
+fn size_vf_bars(pf: &PciHeaderBase, sriov: SrIov<'_>) -> [Option<Bar>; 6] {
+    let mut bars = [None; 6];
+    let mut slot = 0;
+    while slot < 6 {
+        let offset = vf_bar_offset(sriov, slot as u8);
+        let low = pf.read(offset);
+        let prefetchable = low.get_bit(3);
+        let is_64bit = low.get_bits(1..3) == 0b10 && slot < 5;
+
+        pf.write(offset, 0xffff_ffff);
+        let mut mask = (pf.read(offset) & !0xf) as u64;
+        pf.write(offset, low);
+        let mut address = (low & !0xf) as u64;
+        if is_64bit {
+            let high = pf.read(offset + 4);
+            pf.write(offset + 4, 0xffff_ffff);
+            mask |= (pf.read(offset + 4) as u64) << 32;
+            pf.write(offset + 4, high);
+            address |= (high as u64) << 32;
+        }
+
+        if mask != 0 {
+            let size = 1u64 << mask.trailing_zeros();
+            bars[slot] = Some(if is_64bit {
+                Bar::Memory64 {
+                    address,
+                    size,
+                    prefetchable,
+                }
+            } else {
+                Bar::Memory32 {
+                    address: address as u32,
+                    size: size as u32,
+                    prefetchable,
+                }
+            });
+        }
+        slot += if is_64bit { 2 } else { 1 };
+    }
+    bars
+}
+
+/// Address of VF `index`, `None` if it lands on another bus than `pf`:
+/// the bus numbers after the PF's belong to the bridges found there.
+fn vf_address(pf: &PciHeaderBase, sriov: SrIov<'_>, index: u16) -> Option<PciAddress> {
+    let address = pf.address();
+    let routing_id =
+        (address.bus() as u32) << 8 | (address.device() as u32) << 3 | address.function() as u32;
+    let routing_id =
+        routing_id + sriov.first_vf_offset() as u32 + sriov.vf_stride() as u32 * index as u32;
+    if routing_id >> 8 != address.bus() as u32 {
+        return None;
+    }
+    Some(PciAddress::new(
+        address.segment(),
+        address.bus(),
+        routing_id.get_bits(3..8) as u8,
+        routing_id.get_bits(0..3) as u8,
+    ))
+}
+
+fn vf_bar_offset(sriov: SrIov<'_>, slot: u8) -> u16 {
+    sriov.offset + 0x24 + slot as u16 * 4
+}
+
+/// NumVFs, the upper half of its dword is read only.
+fn set_num_vfs(pf: &PciHeaderBase, sriov: SrIov<'_>, num_vfs: u16) {
+    pf.write(sriov.offset + 0x10, num_vfs as u32);
+}
+
+/// SR-IOV Control, the status in the upper half is write 1 to clear and
+/// left alone.
+fn set_control(pf: &PciHeaderBase, sriov: SrIov<'_>, control: u16) {
+    pf.write(sriov.offset + 0x08, control as u32);
+}
+
+#[cfg(test)]
+mod tests {
+    use rdif_pcie::BarOwner;
+
+    use super::*;
+    use crate::mock::*;
+
+    const VF_BAR0_SIZE: u32 = 0x4000;
+    const VF_BAR2_SIZE: u32 = 0x10_0000;
+
+    /// A PF at 00:00.0 with up to 4 VFs at offset 1, stride 1, a 32-bit
+    /// VF BAR0 and a 64-bit prefetchable VF BAR2 at `bar0` and `bar2`.
+    fn pf(space: &ConfigSpace, control: u16, num_vfs: u16, bar0: u32, bar2: u64) -> PciAddress {
+        let address = space.add(
+            0,
+            0,
+            0,
+            Function::endpoint(0x8086, 0x10fb)
+                .ext_capability(0x100, 0x0010)
+                .set(0x108, control as u32)
+                .set(0x10c, 4 << 16 | 4)
+                .set(0x110, num_vfs as u32)
+                .set(0x114, 1 << 16 | 1)
+                .set(0x118, 0x10ed << 16)
+                .set(0x124, bar0)
+                .writable(0x124, !(VF_BAR0_SIZE - 1) & !0xf)
+                .writable(0x128, 0)
+                .set(0x12c, bar2 as u32 | 0b1100)
+                .writable(0x12c, !(VF_BAR2_SIZE - 1) & !0xf)
+                .set(0x130, (bar2 >> 32) as u32)
+                .writable(0x134, 0)
+                .writable(0x138, 0),
+        );
+        for function in 1..5 {
+            space.add(0, 0, function, Function::virtual_function());
+        }
+        address
+    }
+
+    fn endpoint(controller: &mut PcieController, address: PciAddress) -> Endpoint {
+        Endpoint::new(PciHeaderBase::new(controller, address).unwrap(), None, 0)
+    }
+
+    fn bar_address(bar: Option<Bar>) -> u64 {
+        match bar {
+            Some(Bar::Memory32 { address, .. }) => address as u64,
+            Some(Bar::Memory64 { address, .. }) => address,
+            _ => panic!("not a memory BAR: {bar:?}"),
+        }
+    }
+
+    #[test]
+    fn test_vf_routing_ids() {
+        let space = ConfigSpace::default();
+        let address = space.add(
+            0,
+            2,
+            0,
+            Function::endpoint(0x8086, 0x10fb)
+                .ext_capability(0x100, 0x0010)
+                .set(0x114, 2 << 16 | 4),
+        );
+        let base = PciHeaderBase::new(&mut space.controller(), address).unwrap();
+        let sriov = base.sriov().unwrap();
+        let vfs: Vec<_> = (0..3)
+            .map(|index| vf_address(&base, sriov, index).unwrap())
+            .collect();
+        assert_eq!(
+            vfs,
+            [
+                PciAddress::new(0, 0, 2, 4),
+                PciAddress::new(0, 0, 2, 6),
+                PciAddress::new(0, 0, 3, 0),
+            ]
+        );
+
+        // routing ID 0xf8 + 8 is on bus 1
+        let address = space.add(
+            0,
+            31,
+            0,
+            Function::endpoint(0x8086, 0x10fb)
+                .ext_capability(0x100, 0x0010)
+                .set(0x114, 1 << 16 | 7),
+        );
+        let base = PciHeaderBase::new(&mut space.controller(), address).unwrap();
+        let sriov = base.sriov().unwrap();
+        assert_eq!(
+            vf_address(&base, sriov, 0),
+            Some(PciAddress::new(0, 0, 31, 7))
+        );
+        assert_eq!(vf_address(&base, sriov, 1), None);
+    }
+
+    #[test]
+    fn test_vf_bar_slicing() {
+        let bar = Bar::Memory32 {
+            address: 0x2000_0000,
+            size: 0x4000,
+            prefetchable: false,
+        };
+        assert_eq!(bar_address(Some(vf_bar(bar, 0))), 0x2000_0000);
+        assert_eq!(bar_address(Some(vf_bar(bar, 3))), 0x2000_c000);
+        let bar = Bar::Memory64 {
+            address: 0x80_0000_0000,
+            size: 0x10_0000,
+            prefetchable: true,
+        };
+        assert_eq!(bar_address(Some(vf_bar(bar, 2))), 0x80_0020_0000);
+
+        // 3 VFs of 1 MiB take a 4 MiB region
+        let regions = regions(&[Some(bar), None, None, None, None, None], 3);
+        assert_eq!(regions.len(), 1);
+        assert_eq!(regions[0].size, 0x40_0000);
+        assert!(regions[0].is_64bit && regions[0].prefetchable);
+    }
+
+    #[test]
+    fn test_firmware_vfs_sized_once() {
+        let space = ConfigSpace::default();
+        // VF Enable and VF MSE set by the firmware
+        let pf = pf(&space, 0x9, 2, 0x2000_0000, 0x80_0000_0000);
+        let mut controller = space.controller();
+
+        let vfs = virtual_functions(&mut controller, pf);
+        assert_eq!(vfs.len(), 2);
+        for (index, vf) in vfs.iter().enumerate() {
+            assert_eq!(vf.address(), PciAddress::new(0, 0, 0, index as u8 + 1));
+            assert_eq!(vf.vendor_id(), 0x8086);
+            assert_eq!(vf.device_id(), 0x10ed);
+            assert_eq!(vf.physical_function(), Some(pf));
+            assert_eq!(vf.vf_index(), Some(index as u16));
+            let bars = vf.bars();
+            assert_eq!(
+                bar_address(bars[0]),
+                0x2000_0000 + index as u64 * VF_BAR0_SIZE as u64
+            );
+            assert_eq!(
+                bar_address(bars[2]),
+                0x80_0000_0000 + index as u64 * VF_BAR2_SIZE as u64
+            );
+            assert!(bars[1].is_none() && bars[4].is_none());
+        }
+        // decoding back on after sizing
+        assert_eq!(space.read(pf, 0x108) & 0xffff, 0x9);
+        let vf_bars = controller.vf_bars[&pf];
+        assert_eq!(vf_bars.num_vfs, 2);
+        assert!(vf_bars.owner.is_none());
+
+        // not sized again
+        space.write(pf, 0x124, 0x3000_0000);
+        let vfs = virtual_functions(&mut controller, pf);
+        assert_eq!(bar_address(vfs[1].bar(0)), 0x2000_4000);
+        release_vfs(&mut controller, pf);
+        assert!(controller.vf_bars.is_empty());
+    }
+
+    #[test]
+    fn test_enable_vfs() {
+        let space = ConfigSpace::default();
+        let pf = pf(&space, 0, 0, 0, 0);
+        let mut controller = space.controller();
+        let ep = endpoint(&mut controller, pf);
+        assert!(virtual_functions(&mut controller, pf).is_empty());
+        assert!(matches!(
+            enable_vfs(&mut controller, &ep, 5, |_| true),
+            Err(SriovError::TooMany {
+                requested: 5,
+                total: 4
+            })
+        ));
+
+        assert!(matches!(
+            enable_vfs(&mut controller, &ep, 3, |_| false),
+            Err(SriovError::NoSleep)
+        ));
+        assert_eq!(space.read(pf, 0x110) & 0xffff, 0);
+        enable_vfs(&mut controller, &ep, 3, |_| true).unwrap();
+        assert_eq!(space.read(pf, 0x108) & 0xffff, 0x9);
+        assert_eq!(space.read(pf, 0x110) & 0xffff, 3);
+        let vf_bars = controller.vf_bars[&pf];
+        assert_eq!(vf_bars.owner, Some(BarOwner::Root));
+        let bar0 = bar_address(vf_bars.bars[0]);
+        let bar2 = bar_address(vf_bars.bars[2]);
+        // 3 VFs take regions sized for 4, aligned to their size
+        assert_eq!(bar0 % (4 * VF_BAR0_SIZE as u64), 0);
+        assert_eq!(bar2 % (4 * VF_BAR2_SIZE as u64), 0);
+        assert!(bar2 >= 0x80_0000_0000);
+        assert_eq!(space.read(pf, 0x124) as u64, bar0);
+
+        let vfs = virtual_functions(&mut controller, pf);
+        assert_eq!(vfs.len(), 3);
+        assert_eq!(bar_address(vfs[2].bar(0)), bar0 + 2 * VF_BAR0_SIZE as u64);
+        assert!(matches!(
+            enable_vfs(&mut controller, &ep, 1, |_| true),
+            Err(SriovError::AlreadyEnabled)
+        ));
+
+        disable_vfs(&mut controller, &ep, |_| true).unwrap();
+        assert_eq!(space.read(pf, 0x108) & 0xffff, 0);
+        assert!(controller.vf_bars.is_empty());
+        // the regions were given back
+        enable_vfs(&mut controller, &ep, 3, |_| true).unwrap();
+        assert_eq!(bar_address(controller.vf_bars[&pf].bars[0]), bar0);
+    }
+}
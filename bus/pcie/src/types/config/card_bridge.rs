@@ -1,16 +1,58 @@
 use core::ops::Deref;
 
-use super::PciHeaderBase;
+use bit_field::BitField;
 
+use super::{BusNumber, PciHeaderBase};
+
+/// A CardBus bridge. Its bus numbers sit where the ones of a PCI-PCI bridge
+/// do, its windows do not and are left as found.
 #[derive(Debug)]
 pub struct CardBusBridge {
     base: PciHeaderBase,
 }
 
 impl CardBusBridge {
+    pub(crate) fn new(base: PciHeaderBase) -> Self {
+        Self { base }
+    }
+
     fn header(&self) -> &PciHeaderBase {
         &self.base
     }
+
+    pub fn primary_bus_number(&self) -> u8 {
+        self.bus_number().primary
+    }
+
+    /// The CardBus bus number.
+    pub fn secondary_bus_number(&self) -> u8 {
+        self.bus_number().secondary
+    }
+
+    pub fn subordinate_bus_number(&self) -> u8 {
+        self.bus_number().subordinate
+    }
+
+    pub fn update_bus_number<F>(&mut self, f: F)
+    where
+        F: FnOnce(BusNumber) -> BusNumber,
+    {
+        let mut data = self.base.read(0x18);
+        let new_bus = f(self.bus_number());
+        data.set_bits(16..24, new_bus.subordinate.into());
+        data.set_bits(8..16, new_bus.secondary.into());
+        data.set_bits(0..8, new_bus.primary.into());
+        self.base.write(0x18, data);
+    }
+
+    fn bus_number(&self) -> BusNumber {
+        let data = self.base.read(0x18);
+        BusNumber {
+            primary: data.get_bits(0..8) as u8,
+            secondary: data.get_bits(8..16) as u8,
+            subordinate: data.get_bits(16..24) as u8,
+        }
+    }
 }
 
 impl Deref for CardBusBridge {
@@ -20,3 +62,51 @@ impl Deref for CardBusBridge {
         self.header()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::*;
+    use crate::{enumerate_by_controller, mock::*, PciAddress};
+
+    #[test]
+    fn test_update_bus_number() {
+        let space = ConfigSpace::default();
+        // latency timer in the top byte
+        let address = space.add(0, 1, 0, Function::cardbus_bridge().set(0x18, 0x4000_0000));
+        let base = PciHeaderBase::new(&mut space.controller(), address).unwrap();
+        let mut bridge = CardBusBridge::new(base);
+        bridge.update_bus_number(|_| BusNumber {
+            primary: 0,
+            secondary: 3,
+            subordinate: 5,
+        });
+        assert_eq!(space.read(address, 0x18), 0x4005_0300);
+        assert_eq!(bridge.primary_bus_number(), 0);
+        assert_eq!(bridge.secondary_bus_number(), 3);
+        assert_eq!(bridge.subordinate_bus_number(), 5);
+    }
+
+    #[test]
+    fn test_walk_behind_cardbus() {
+        let space = ConfigSpace::default();
+        let cardbus = space.add(0, 1, 0, Function::cardbus_bridge());
+        space.add(1, 0, 0, Function::endpoint(0x8086, 1));
+        let bridge = space.add(0, 2, 0, Function::bridge());
+        space.add(2, 0, 0, Function::endpoint(0x8086, 2));
+        // unknown header type
+        space.add(0, 3, 0, Function::endpoint(0x8086, 3).set(0x0c, 0x7f << 16));
+        let mut controller = space.controller();
+
+        let found: Vec<PciAddress> = enumerate_by_controller(&mut controller, None)
+            .map(|ep| ep.address())
+            .collect();
+        assert_eq!(
+            found,
+            [PciAddress::new(0, 1, 0, 0), PciAddress::new(0, 2, 0, 0)]
+        );
+        assert_eq!(space.read(cardbus, 0x18) & 0xff_ffff, 0x01_0100);
+        assert_eq!(space.read(bridge, 0x18) & 0xff_ffff, 0x02_0200);
+    }
+}
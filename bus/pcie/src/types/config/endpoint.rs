@@ -1,34 +1,65 @@
 use core::{
+    cmp::Reverse,
     fmt::{Debug, Display},
     ops::{Deref, DerefMut, Range},
 };
 
-use alloc::vec::Vec;
+use alloc::{boxed::Box, vec::Vec};
 use pci_types::{
     capability::PciCapability, device_type::DeviceType, Bar, BarWriteError, CommandRegister,
-    EndpointHeader,
+    EndpointHeader, PciAddress,
 };
 use rdif_pcie::{ConfigAccess, SimpleBarAllocator};
 
+use crate::err::BarAllocError;
+
 pub struct Endpoint {
     base: super::PciHeaderBase,
     header: EndpointHeader,
+    bar_error: Option<BarAllocError>,
+    pub(super) msi: Option<super::msi::MsiState>,
+    /// MSI-X table mapped by a previous enable, `(physical, virtual)`.
+    pub(super) msix_table: Option<(u64, usize)>,
+    /// Bridges between the root bus and the endpoint, nearest last.
+    pub(crate) upstream: Vec<PciAddress>,
+    /// Config space of the nearest bridge in `upstream`.
+    pub(crate) parent: Option<ConfigAccess>,
+    pub(crate) virtfn: Option<Box<crate::sriov::VirtFn>>,
 }
 
 impl Endpoint {
+    /// With `bar_allocator` the BARs are assigned from it, except the ones
+    /// whose bit is set in `kept`: those were already reserved where the
+    /// firmware placed them.
     pub(crate) fn new(
         base: super::PciHeaderBase,
         bar_allocator: Option<&mut SimpleBarAllocator>,
+        kept: u8,
     ) -> Self {
         let header = EndpointHeader::from_header(base.header(), &base.root)
             .expect("EndpointHeader::from_header failed");
-        let mut s = Self { base, header };
+        let mut s = Self {
+            base,
+            header,
+            bar_error: None,
+            msi: None,
+            msix_table: None,
+            upstream: Vec::new(),
+            parent: None,
+            virtfn: None,
+        };
         if let Some(alloc) = bar_allocator {
-            s.realloc_bar(alloc).unwrap();
+            s.bar_error = s.realloc_bar(alloc, kept).err();
         }
         s
     }
 
+    /// Set when the BARs could not be assigned during enumeration. Memory and
+    /// I/O decoding are then left disabled.
+    pub fn bar_error(&self) -> Option<BarAllocError> {
+        self.bar_error
+    }
+
     pub fn device_type(&self) -> DeviceType {
         let class_info = self.base.revision_and_class();
         DeviceType::from((class_info.base_class, class_info.sub_class))
@@ -49,8 +80,42 @@ impl Endpoint {
         }
     }
 
+    /// Ports decoded by the I/O BAR at `slot`.
+    pub fn bar_io(&self, slot: u8) -> Option<Range<u32>> {
+        let Bar::Io { port } = self._bar(slot)? else {
+            return None;
+        };
+        let size = self.io_bar_size(slot)?;
+        Some(port..port + size)
+    }
+
+    /// Size the I/O BAR at `slot`, with I/O decoding off while it holds all
+    /// ones.
+    fn io_bar_size(&self, slot: u8) -> Option<u32> {
+        let offset = 0x10 + slot as u16 * 4;
+        // the status in the upper half is write 1 to clear, write 0 there
+        let command = self.base.read(0x04) & 0xffff;
+        let io = CommandRegister::IO_ENABLE.bits() as u32;
+        if command & io != 0 {
+            self.base.write(0x04, command & !io);
+        }
+        let old = self.base.read(offset);
+        self.base.write(offset, 0xffff_ffff);
+        // devices may only decode 16 bits, the upper half then reads 0
+        let readback = self.base.read(offset) & !0x3;
+        self.base.write(offset, old);
+        if command & io != 0 {
+            self.base.write(0x04, command);
+        }
+        (readback != 0).then(|| 1 << readback.trailing_zeros())
+    }
+
     fn _bar(&self, slot: u8) -> Option<Bar> {
         assert!(slot < 6, "BAR index out of range");
+        // VF BARs read 0, they are slices of the PF's VF BARs
+        if let Some(virtfn) = &self.virtfn {
+            return virtfn.bars[slot as usize];
+        }
         self.header.bar(slot, self.access())
     }
 
@@ -88,6 +153,23 @@ impl Endpoint {
         self.header.interrupt(self.access()).1
     }
 
+    /// The device on the root bus and the pin, 1 for INTA to 4 for INTD,
+    /// that the endpoint's INTx arrives as at the host bridge, after the
+    /// standard swizzling by every bridge on the way. `None` if the endpoint
+    /// uses no pin.
+    pub fn intx_route(&self) -> Option<(PciAddress, u8)> {
+        let mut pin = self.interrupt_pin();
+        if !(1..=4).contains(&pin) {
+            return None;
+        }
+        let mut address = self.address();
+        for &bridge in self.upstream.iter().rev() {
+            pin = (pin - 1 + address.device()) % 4 + 1;
+            address = bridge;
+        }
+        Some((address, pin))
+    }
+
     pub fn subsystem_id(&self) -> u16 {
         self.header.subsystem(self.access()).0
     }
@@ -110,47 +192,138 @@ impl Endpoint {
         &self.base.root
     }
 
+    /// Reprogram the BARs not in `kept` from `allocator`. On failure the
+    /// ranges taken so far, kept ones included, are given back and the BARs
+    /// restored, decoding stays disabled.
     fn realloc_bar(
         &mut self,
         allocator: &mut SimpleBarAllocator,
-    ) -> Result<(), pci_types::BarWriteError> {
+        kept: u8,
+    ) -> Result<(), BarAllocError> {
         // Disable IO/MEM before reprogramming BARs
         self.base.update_command(|mut cmd| {
             cmd.remove(CommandRegister::IO_ENABLE);
             cmd.remove(CommandRegister::MEMORY_ENABLE);
             cmd
         });
-        for (i, bar) in self.bars().into_iter().enumerate() {
-            if let Some(bar) = bar {
-                match bar {
-                    Bar::Memory32 {
-                        address: _,
-                        size,
-                        prefetchable,
-                    } => {
-                        let addr = allocator.alloc_memory32(size, prefetchable).unwrap();
-                        self.set_bar(i as _, addr as usize).unwrap();
-                    }
-                    Bar::Memory64 {
-                        address: _,
-                        size,
-                        prefetchable,
-                    } => {
-                        let addr = allocator.alloc_memory64(size, prefetchable).unwrap();
-                        self.set_bar(i as _, addr as usize).unwrap();
-                    }
-                    Bar::Io { port: _ } => {}
-                }
+        let saved: [u32; 6] = core::array::from_fn(|i| self.base.read(0x10 + i as u16 * 4));
+        let mut mem = Vec::new();
+        let mut io = Vec::new();
+        for (slot, bar) in self.bars().into_iter().enumerate() {
+            if kept & (1 << slot) == 0 {
+                continue;
+            }
+            match bar {
+                Some(Bar::Memory32 { address, size, .. }) => mem.push((address as _, size as _)),
+                Some(Bar::Memory64 { address, size, .. }) => mem.push((address, size)),
+                Some(Bar::Io { .. }) => io.extend(
+                    self.bar_io(slot as _)
+                        .map(|ports| (ports.start, ports.end - ports.start)),
+                ),
+                None => {}
             }
         }
 
+        let io_unplaced = match self.place_bars(allocator, kept, &mut mem, &mut io) {
+            Ok(io_unplaced) => io_unplaced,
+            Err(e) => {
+                for (address, size) in mem {
+                    allocator.free(address, size);
+                }
+                for (port, size) in io {
+                    allocator.free_io(port, size);
+                }
+                for (i, value) in saved.into_iter().enumerate() {
+                    self.base.write(0x10 + i as u16 * 4, value);
+                }
+                return Err(e);
+            }
+        };
+
+        // Without a port window the I/O BARs keep whatever they hold, do not
+        // let the device decode them.
         self.base.update_command(|mut cmd| {
-            cmd.insert(CommandRegister::MEMORY_ENABLE | CommandRegister::IO_ENABLE);
+            cmd.insert(CommandRegister::MEMORY_ENABLE);
+            if !io_unplaced {
+                cmd.insert(CommandRegister::IO_ENABLE);
+            }
             cmd
         });
 
         Ok(())
     }
+
+    /// Allocate and write each BAR not in `kept`, recording what was taken
+    /// in `mem` and `io`. Returns whether some I/O BAR found no port window.
+    fn place_bars(
+        &mut self,
+        allocator: &mut SimpleBarAllocator,
+        kept: u8,
+        mem: &mut Vec<(u64, u64)>,
+        io: &mut Vec<(u32, u32)>,
+    ) -> Result<bool, BarAllocError> {
+        let mut bars: Vec<(u8, Bar)> = self
+            .bars()
+            .into_iter()
+            .enumerate()
+            .filter(|(i, _)| kept & (1 << i) == 0)
+            .filter_map(|(i, bar)| Some((i as u8, bar?)))
+            .collect();
+        // Largest first: BARs are aligned to their size, so smaller ones then
+        // pack behind them without leaving holes.
+        bars.sort_by_key(|(_, bar)| {
+            Reverse(match *bar {
+                Bar::Memory32 { size, .. } => size as u64,
+                Bar::Memory64 { size, .. } => size,
+                Bar::Io { .. } => 0,
+            })
+        });
+
+        let mut io_unplaced = false;
+        for (slot, bar) in bars {
+            let addr = match bar {
+                Bar::Memory32 {
+                    size, prefetchable, ..
+                } => {
+                    let addr = allocator.alloc_memory32(size, prefetchable).ok_or(
+                        BarAllocError::NoSpace {
+                            slot,
+                            size: size as _,
+                        },
+                    )?;
+                    mem.push((addr as u64, size as u64));
+                    addr as usize
+                }
+                Bar::Memory64 {
+                    size, prefetchable, ..
+                } => {
+                    let addr = allocator
+                        .alloc_memory64(size, prefetchable)
+                        .ok_or(BarAllocError::NoSpace { slot, size })?;
+                    mem.push((addr, size));
+                    addr as usize
+                }
+                Bar::Io { .. } => {
+                    match self
+                        .io_bar_size(slot)
+                        .and_then(|size| Some((allocator.alloc_io(size)?, size)))
+                    {
+                        Some((port, size)) => {
+                            io.push((port, size));
+                            port as usize
+                        }
+                        None => {
+                            io_unplaced = true;
+                            continue;
+                        }
+                    }
+                }
+            };
+            self.set_bar(slot, addr)
+                .map_err(|err| BarAllocError::Write { slot, err })?;
+        }
+        Ok(io_unplaced)
+    }
 }
 
 impl Deref for Endpoint {
@@ -198,3 +371,50 @@ impl Display for Endpoint {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{enumerate_by_controller, mock::*};
+
+    fn with_pin(device_id: u16, pin: u8) -> Function {
+        Function::endpoint(0x8086, device_id).set(0x3c, (pin as u32) << 8)
+    }
+
+    fn routes(space: &ConfigSpace) -> Vec<(u16, Option<(PciAddress, u8)>)> {
+        let mut controller = space.controller();
+        enumerate_by_controller(&mut controller, None)
+            .map(|ep| (ep.device_id(), ep.intx_route()))
+            .collect()
+    }
+
+    #[test]
+    fn test_intx_route_root_bus() {
+        let space = ConfigSpace::default();
+        let address = space.add(0, 3, 0, with_pin(1, 2));
+        space.add(0, 4, 0, with_pin(2, 0));
+        assert_eq!(routes(&space), [(1, Some((address, 2))), (2, None)]);
+    }
+
+    #[test]
+    fn test_intx_route_one_bridge() {
+        let space = ConfigSpace::default();
+        let bridge = space.add(0, 1, 0, Function::bridge());
+        space.add(1, 2, 0, with_pin(1, 1));
+        space.add(1, 3, 0, with_pin(2, 4));
+        assert_eq!(
+            routes(&space),
+            [(1, Some((bridge, 3))), (2, Some((bridge, 3)))]
+        );
+    }
+
+    #[test]
+    fn test_intx_route_two_bridges() {
+        let space = ConfigSpace::default();
+        let outer = space.add(0, 1, 0, Function::bridge());
+        space.add(1, 1, 0, Function::bridge());
+        space.add(2, 1, 0, with_pin(1, 1));
+        // INTA of device 1 is INTB at the inner bridge, 1:1.0, then INTC
+        assert_eq!(routes(&space), [(1, Some((outer, 3)))]);
+    }
+}
@@ -0,0 +1,601 @@
+//! PCI Express extended capabilities, linked from offset 0x100 of the
+//! extended config space.
+//!
+//! The typed capabilities borrow the [`Endpoint`] they were found on, their
+//! accessors read and write the registers live.
+
+use alloc::{collections::btree_set::BTreeSet, vec::Vec};
+
+use bit_field::BitField;
+use bitflags::bitflags;
+
+use super::{Endpoint, PciHeaderBase};
+
+/// First extended capability, right after the legacy config space.
+const EXT_CAP_START: u16 = 0x100;
+const CONFIG_SPACE_END: u16 = 0x1000;
+
+const ID_AER: u16 = 0x0001;
+const ID_DSN: u16 = 0x0003;
+const ID_ACS: u16 = 0x000d;
+const ID_ATS: u16 = 0x000f;
+const ID_SRIOV: u16 = 0x0010;
+const ID_RESIZABLE_BAR: u16 = 0x0015;
+const ID_PASID: u16 = 0x001b;
+const ID_L1PM: u16 = 0x001e;
+
+#[derive(Debug, Clone, Copy)]
+pub enum ExtendedCapability<'a> {
+    Aer(Aer<'a>),
+    SrIov(SrIov<'a>),
+    Acs(Acs<'a>),
+    Ats(Ats<'a>),
+    Pasid(Pasid<'a>),
+    L1PmSubstates(L1PmSubstates<'a>),
+    DeviceSerialNumber(DeviceSerialNumber<'a>),
+    ResizableBar(ResizableBar<'a>),
+    Unknown { id: u16, version: u8, offset: u16 },
+}
+
+impl<'a> ExtendedCapability<'a> {
+    fn new(base: &'a PciHeaderBase, id: u16, version: u8, offset: u16) -> Self {
+        match id {
+            ID_AER => Self::Aer(Aer { base, offset }),
+            ID_DSN => Self::DeviceSerialNumber(DeviceSerialNumber { base, offset }),
+            ID_ACS => Self::Acs(Acs { base, offset }),
+            ID_ATS => Self::Ats(Ats { base, offset }),
+            ID_SRIOV => Self::SrIov(SrIov { base, offset }),
+            ID_RESIZABLE_BAR => Self::ResizableBar(ResizableBar { base, offset }),
+            ID_PASID => Self::Pasid(Pasid { base, offset }),
+            ID_L1PM => Self::L1PmSubstates(L1PmSubstates { base, offset }),
+            _ => Self::Unknown {
+                id,
+                version,
+                offset,
+            },
+        }
+    }
+}
+
+impl PciHeaderBase {
+    /// Walk the extended capability list, stopping at the first offset
+    /// already visited since a broken list may loop.
+    pub(crate) fn extended_capabilities(&self) -> Vec<ExtendedCapability<'_>> {
+        let mut caps = Vec::new();
+        let mut seen = BTreeSet::new();
+        let mut offset = EXT_CAP_START;
+        while offset < CONFIG_SPACE_END && seen.insert(offset) {
+            let header = self.read(offset);
+            if header == 0 || header == 0xffff_ffff {
+                break;
+            }
+            let id = header.get_bits(0..16) as u16;
+            let version = header.get_bits(16..20) as u8;
+            caps.push(ExtendedCapability::new(self, id, version, offset));
+
+            let next = header.get_bits(20..32) as u16 & !0x3;
+            if next < EXT_CAP_START {
+                break;
+            }
+            offset = next;
+        }
+        caps
+    }
+
+    pub(crate) fn sriov(&self) -> Option<SrIov<'_>> {
+        self.find_extended(|cap| match cap {
+            ExtendedCapability::SrIov(sriov) => Some(sriov),
+            _ => None,
+        })
+    }
+
+    fn find_extended<'a, T>(
+        &'a self,
+        f: impl FnMut(ExtendedCapability<'a>) -> Option<T>,
+    ) -> Option<T> {
+        self.extended_capabilities().into_iter().find_map(f)
+    }
+
+    fn read16(&self, offset: u16) -> u16 {
+        let value = self.read(offset & !0x3);
+        if offset & 0x2 == 0 {
+            value as u16
+        } else {
+            (value >> 16) as u16
+        }
+    }
+
+    /// Write the half at `offset`, the other half of the dword is written
+    /// back as read, so it must not hold RW1C bits.
+    fn write16(&self, offset: u16, value: u16) {
+        let aligned = offset & !0x3;
+        let mut data = self.read(aligned);
+        if offset & 0x2 == 0 {
+            data.set_bits(0..16, value as u32);
+        } else {
+            data.set_bits(16..32, value as u32);
+        }
+        self.write(aligned, data);
+    }
+}
+
+impl Endpoint {
+    /// The extended capabilities. Empty for conventional PCI functions and
+    /// when only the legacy config space is accessible.
+    pub fn extended_capabilities(&self) -> Vec<ExtendedCapability<'_>> {
+        PciHeaderBase::extended_capabilities(self)
+    }
+
+    pub fn aer(&self) -> Option<Aer<'_>> {
+        self.find_extended(|cap| match cap {
+            ExtendedCapability::Aer(aer) => Some(aer),
+            _ => None,
+        })
+    }
+
+    pub fn sriov(&self) -> Option<SrIov<'_>> {
+        PciHeaderBase::sriov(self)
+    }
+
+    pub fn acs(&self) -> Option<Acs<'_>> {
+        self.find_extended(|cap| match cap {
+            ExtendedCapability::Acs(acs) => Some(acs),
+            _ => None,
+        })
+    }
+
+    pub fn ats(&self) -> Option<Ats<'_>> {
+        self.find_extended(|cap| match cap {
+            ExtendedCapability::Ats(ats) => Some(ats),
+            _ => None,
+        })
+    }
+
+    pub fn pasid(&self) -> Option<Pasid<'_>> {
+        self.find_extended(|cap| match cap {
+            ExtendedCapability::Pasid(pasid) => Some(pasid),
+            _ => None,
+        })
+    }
+
+    pub fn l1pm_substates(&self) -> Option<L1PmSubstates<'_>> {
+        self.find_extended(|cap| match cap {
+            ExtendedCapability::L1PmSubstates(l1pm) => Some(l1pm),
+            _ => None,
+        })
+    }
+
+    pub fn resizable_bar(&self) -> Option<ResizableBar<'_>> {
+        self.find_extended(|cap| match cap {
+            ExtendedCapability::ResizableBar(rebar) => Some(rebar),
+            _ => None,
+        })
+    }
+
+    /// The 64-bit IEEE EUI-64 from the Device Serial Number capability.
+    pub fn serial_number(&self) -> Option<u64> {
+        self.find_extended(|cap| match cap {
+            ExtendedCapability::DeviceSerialNumber(dsn) => Some(dsn),
+            _ => None,
+        })
+        .map(|dsn| dsn.serial_number())
+    }
+}
+
+/// Advanced Error Reporting.
+#[derive(Debug, Clone, Copy)]
+pub struct Aer<'a> {
+    base: &'a PciHeaderBase,
+    pub offset: u16,
+}
+
+impl Aer<'_> {
+    pub fn uncorrectable_status(&self) -> u32 {
+        self.base.read(self.offset + 0x04)
+    }
+
+    /// Clear the `bits` of the uncorrectable error status, they are RW1C.
+    pub fn clear_uncorrectable_status(&self, bits: u32) {
+        self.base.write(self.offset + 0x04, bits);
+    }
+
+    pub fn uncorrectable_mask(&self) -> u32 {
+        self.base.read(self.offset + 0x08)
+    }
+
+    pub fn set_uncorrectable_mask(&self, mask: u32) {
+        self.base.write(self.offset + 0x08, mask);
+    }
+
+    /// Set bits report the error as fatal, clear ones as non-fatal.
+    pub fn uncorrectable_severity(&self) -> u32 {
+        self.base.read(self.offset + 0x0c)
+    }
+
+    pub fn correctable_status(&self) -> u32 {
+        self.base.read(self.offset + 0x10)
+    }
+
+    /// Clear the `bits` of the correctable error status, they are RW1C.
+    pub fn clear_correctable_status(&self, bits: u32) {
+        self.base.write(self.offset + 0x10, bits);
+    }
+
+    pub fn correctable_mask(&self) -> u32 {
+        self.base.read(self.offset + 0x14)
+    }
+
+    pub fn set_correctable_mask(&self, mask: u32) {
+        self.base.write(self.offset + 0x14, mask);
+    }
+
+    /// Bit of the uncorrectable status that was reported first.
+    pub fn first_error_pointer(&self) -> u8 {
+        self.base.read(self.offset + 0x18).get_bits(0..5) as u8
+    }
+
+    /// Header of the TLP that caused the first reported error.
+    pub fn header_log(&self) -> [u32; 4] {
+        core::array::from_fn(|i| self.base.read(self.offset + 0x1c + i as u16 * 4))
+    }
+}
+
+/// Single Root I/O Virtualization.
+#[derive(Debug, Clone, Copy)]
+pub struct SrIov<'a> {
+    base: &'a PciHeaderBase,
+    pub offset: u16,
+}
+
+impl SrIov<'_> {
+    pub fn capabilities(&self) -> u32 {
+        self.base.read(self.offset + 0x04)
+    }
+
+    pub fn control(&self) -> u16 {
+        self.base.read16(self.offset + 0x08)
+    }
+
+    /// Whether the virtual functions are enabled.
+    pub fn vf_enabled(&self) -> bool {
+        self.control().get_bit(0)
+    }
+
+    pub fn initial_vfs(&self) -> u16 {
+        self.base.read16(self.offset + 0x0c)
+    }
+
+    pub fn total_vfs(&self) -> u16 {
+        self.base.read16(self.offset + 0x0e)
+    }
+
+    pub fn num_vfs(&self) -> u16 {
+        self.base.read16(self.offset + 0x10)
+    }
+
+    /// Offset of the routing ID of the first VF from the one of the PF.
+    pub fn first_vf_offset(&self) -> u16 {
+        self.base.read16(self.offset + 0x14)
+    }
+
+    /// Distance between the routing IDs of consecutive VFs.
+    pub fn vf_stride(&self) -> u16 {
+        self.base.read16(self.offset + 0x16)
+    }
+
+    pub fn vf_device_id(&self) -> u16 {
+        self.base.read16(self.offset + 0x1a)
+    }
+
+    /// Page sizes the PF supports, bit `n` standing for `4 KiB << n`.
+    pub fn supported_page_sizes(&self) -> u32 {
+        self.base.read(self.offset + 0x1c)
+    }
+
+    /// Page size the VF BARs are aligned to, in the same encoding as
+    /// [`supported_page_sizes`](Self::supported_page_sizes).
+    pub fn system_page_size(&self) -> u32 {
+        self.base.read(self.offset + 0x20)
+    }
+}
+
+bitflags! {
+    /// Access Control Services, the same bits are used by the capability and
+    /// the control register.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct AcsFlags: u16 {
+        const SOURCE_VALIDATION = 1 << 0;
+        const TRANSLATION_BLOCKING = 1 << 1;
+        const P2P_REQUEST_REDIRECT = 1 << 2;
+        const P2P_COMPLETION_REDIRECT = 1 << 3;
+        const UPSTREAM_FORWARDING = 1 << 4;
+        const P2P_EGRESS_CONTROL = 1 << 5;
+        const DIRECT_TRANSLATED_P2P = 1 << 6;
+    }
+}
+
+/// Access Control Services.
+#[derive(Debug, Clone, Copy)]
+pub struct Acs<'a> {
+    base: &'a PciHeaderBase,
+    pub offset: u16,
+}
+
+impl Acs<'_> {
+    pub fn capabilities(&self) -> AcsFlags {
+        AcsFlags::from_bits_truncate(self.base.read16(self.offset + 0x04))
+    }
+
+    pub fn control(&self) -> AcsFlags {
+        AcsFlags::from_bits_truncate(self.base.read16(self.offset + 0x06))
+    }
+
+    /// Enable `flags`, limited to the supported ones; the others are disabled.
+    pub fn set_control(&self, flags: AcsFlags) {
+        let flags = flags & self.capabilities();
+        self.base.write16(self.offset + 0x06, flags.bits());
+    }
+}
+
+/// Address Translation Services.
+#[derive(Debug, Clone, Copy)]
+pub struct Ats<'a> {
+    base: &'a PciHeaderBase,
+    pub offset: u16,
+}
+
+impl Ats<'_> {
+    /// Invalidate requests the function can queue, 0 meaning 32.
+    pub fn invalidate_queue_depth(&self) -> u8 {
+        self.base.read16(self.offset + 0x04).get_bits(0..5) as u8
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.base.read16(self.offset + 0x06).get_bit(15)
+    }
+
+    /// Enable ATS with a smallest translation unit of `4 KiB << stu`.
+    pub fn enable(&self, stu: u8) {
+        let mut control = 0u16;
+        control.set_bits(0..5, stu as u16 & 0x1f);
+        control.set_bit(15, true);
+        self.base.write16(self.offset + 0x06, control);
+    }
+
+    pub fn disable(&self) {
+        self.base.write16(self.offset + 0x06, 0);
+    }
+}
+
+/// Process Address Space ID.
+#[derive(Debug, Clone, Copy)]
+pub struct Pasid<'a> {
+    base: &'a PciHeaderBase,
+    pub offset: u16,
+}
+
+impl Pasid<'_> {
+    pub fn execute_permission_supported(&self) -> bool {
+        self.base.read16(self.offset + 0x04).get_bit(1)
+    }
+
+    pub fn privileged_mode_supported(&self) -> bool {
+        self.base.read16(self.offset + 0x04).get_bit(2)
+    }
+
+    /// Width of the PASIDs the function supports, in bits.
+    pub fn max_pasid_width(&self) -> u8 {
+        self.base.read16(self.offset + 0x04).get_bits(8..13) as u8
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.base.read16(self.offset + 0x06).get_bit(0)
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        let mut control = self.base.read16(self.offset + 0x06);
+        control.set_bit(0, enabled);
+        self.base.write16(self.offset + 0x06, control);
+    }
+}
+
+bitflags! {
+    /// L1 PM substates, the same bits are used by the capability and the
+    /// enables of control 1.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct L1PmFlags: u32 {
+        const PCI_PM_L1_2 = 1 << 0;
+        const PCI_PM_L1_1 = 1 << 1;
+        const ASPM_L1_2 = 1 << 2;
+        const ASPM_L1_1 = 1 << 3;
+    }
+}
+
+/// L1 PM Substates.
+#[derive(Debug, Clone, Copy)]
+pub struct L1PmSubstates<'a> {
+    base: &'a PciHeaderBase,
+    pub offset: u16,
+}
+
+impl L1PmSubstates<'_> {
+    pub fn capabilities(&self) -> L1PmFlags {
+        L1PmFlags::from_bits_truncate(self.base.read(self.offset + 0x04))
+    }
+
+    pub fn enabled(&self) -> L1PmFlags {
+        L1PmFlags::from_bits_truncate(self.base.read(self.offset + 0x08))
+    }
+
+    /// Enable `flags`, limited to the supported ones; the others are disabled.
+    pub fn set_enabled(&self, flags: L1PmFlags) {
+        let flags = flags & self.capabilities();
+        let mut control = self.base.read(self.offset + 0x08);
+        control.set_bits(0..4, flags.bits());
+        self.base.write(self.offset + 0x08, control);
+    }
+}
+
+/// Device Serial Number.
+#[derive(Debug, Clone, Copy)]
+pub struct DeviceSerialNumber<'a> {
+    base: &'a PciHeaderBase,
+    pub offset: u16,
+}
+
+impl DeviceSerialNumber<'_> {
+    pub fn serial_number(&self) -> u64 {
+        let low = self.base.read(self.offset + 0x04) as u64;
+        let high = self.base.read(self.offset + 0x08) as u64;
+        high << 32 | low
+    }
+}
+
+/// Resizable BAR.
+#[derive(Debug, Clone, Copy)]
+pub struct ResizableBar<'a> {
+    base: &'a PciHeaderBase,
+    pub offset: u16,
+}
+
+/// A BAR covered by the Resizable BAR capability.
+#[derive(Debug, Clone, Copy)]
+pub struct ResizableBarEntry {
+    /// BAR slot.
+    pub bar: u8,
+    /// Supported sizes, bit `n` standing for `1 MiB << n`.
+    pub supported: u64,
+    /// Current size in bytes.
+    pub size: u64,
+}
+
+impl ResizableBar<'_> {
+    pub fn entries(&self) -> Vec<ResizableBarEntry> {
+        // the count is only valid in the first control register
+        let count = self.base.read(self.offset + 0x08).get_bits(5..8) as u16;
+        (0..count)
+            .map(|i| {
+                let cap = self.base.read(self.offset + 0x04 + i * 8);
+                let control = self.base.read(self.offset + 0x08 + i * 8);
+                // bits 4..32 of the capability hold sizes 1 MiB..128 TiB,
+                // bits 16..32 of the control the larger ones
+                let supported =
+                    cap.get_bits(4..32) as u64 | (control.get_bits(16..32) as u64) << 28;
+                ResizableBarEntry {
+                    bar: control.get_bits(0..3) as u8,
+                    supported,
+                    size: 1u64.checked_shl(20 + control.get_bits(8..14)).unwrap_or(0),
+                }
+            })
+            .collect()
+    }
+
+    /// Resize `bar` to `1 MiB << size_log2`. The BAR must be reassigned
+    /// afterwards, with memory decoding disabled meanwhile.
+    ///
+    /// Returns `false` if the BAR is not resizable or the size unsupported.
+    pub fn set_size(&self, bar: u8, size_log2: u8) -> bool {
+        let Some(i) = self
+            .entries()
+            .iter()
+            .position(|e| e.bar == bar && size_log2 < 64 && e.supported.get_bit(size_log2 as _))
+        else {
+            return false;
+        };
+        let offset = self.offset + 0x08 + i as u16 * 8;
+        let mut control = self.base.read(offset);
+        control.set_bits(8..14, size_log2 as u32);
+        self.base.write(offset, control);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::*;
+
+    fn endpoint(f: Function) -> Endpoint {
+        let space = ConfigSpace::default();
+        let address = space.add(0, 0, 0, f);
+        Endpoint::new(
+            PciHeaderBase::new(&mut space.controller(), address).unwrap(),
+            None,
+            0,
+        )
+    }
+
+    #[test]
+    fn test_extended_capabilities() {
+        let ep = endpoint(
+            Function::endpoint(0x8086, 1)
+                .ext_capability(0x100, ID_AER)
+                .ext_capability(0x140, ID_SRIOV)
+                .ext_capability(0x200, 0x23),
+        );
+        let caps = ep.extended_capabilities();
+        assert_eq!(caps.len(), 3);
+        assert!(matches!(
+            caps[2],
+            ExtendedCapability::Unknown {
+                id: 0x23,
+                version: 1,
+                offset: 0x200
+            }
+        ));
+        assert_eq!(ep.aer().unwrap().offset, 0x100);
+        assert_eq!(ep.sriov().unwrap().offset, 0x140);
+        assert!(ep.acs().is_none());
+    }
+
+    #[test]
+    fn test_no_extended_capabilities() {
+        assert!(endpoint(Function::endpoint(0x8086, 1))
+            .extended_capabilities()
+            .is_empty());
+        // conventional PCI behind a bridge without extended config space
+        let ep = endpoint(Function::endpoint(0x8086, 1).set(0x100, 0xffff_ffff));
+        assert!(ep.extended_capabilities().is_empty());
+    }
+
+    #[test]
+    fn test_extended_loop_stops() {
+        // points back to itself
+        let ep = endpoint(Function::endpoint(0x8086, 1).set(0x100, 0x1001_0001));
+        assert_eq!(ep.extended_capabilities().len(), 1);
+        // 0x140 points back to 0x100
+        let ep = endpoint(
+            Function::endpoint(0x8086, 1)
+                .set(0x100, 0x1401_0001)
+                .set(0x140, 0x1001_0010),
+        );
+        assert_eq!(ep.extended_capabilities().len(), 2);
+    }
+
+    #[test]
+    fn test_extended_next_below_start() {
+        let ep = endpoint(
+            Function::endpoint(0x8086, 1)
+                .set(0x100, 0x0401_0001)
+                .set(0x140, 0x0001_0010),
+        );
+        assert_eq!(ep.extended_capabilities().len(), 1);
+        assert!(ep.sriov().is_none());
+    }
+
+    #[test]
+    fn test_acs_control_limited_to_supported() {
+        let ep = endpoint(
+            Function::endpoint(0x8086, 1)
+                .ext_capability(0x100, ID_ACS)
+                .set(0x104, 0x0000_0005),
+        );
+        let acs = ep.acs().unwrap();
+        acs.set_control(AcsFlags::all());
+        assert_eq!(
+            acs.control(),
+            AcsFlags::SOURCE_VALIDATION | AcsFlags::P2P_REQUEST_REDIRECT
+        );
+        assert_eq!(acs.capabilities().bits(), 0x5);
+    }
+}
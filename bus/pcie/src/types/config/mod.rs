@@ -2,13 +2,18 @@ use core::fmt::Debug;
 
 mod card_bridge;
 mod endpoint;
+mod ext_cap;
+mod msi;
 mod pci_bridge;
+mod reset;
 mod unknown;
 
 pub use card_bridge::*;
 pub use endpoint::Endpoint;
+pub use ext_cap::*;
 pub use pci_bridge::*;
 use rdif_pcie::ConfigAccess;
+pub use reset::PowerState;
 pub use unknown::*;
 
 use pci_types::{
@@ -49,6 +54,23 @@ impl PciHeaderBase {
         })
     }
 
+    /// A virtual function, whose vendor and device IDs read all ones: `vid`
+    /// is the one of its physical function, `did` the VF Device ID of the
+    /// SR-IOV capability.
+    pub(crate) fn virtual_function(
+        root: &mut PcieController,
+        address: PciAddress,
+        vid: u16,
+        did: u16,
+    ) -> Self {
+        Self {
+            vid,
+            did,
+            root: root.config_access(address),
+            header: PciHeader::new(address),
+        }
+    }
+
     pub fn header(&self) -> PciHeader {
         PciHeader::new(self.address())
     }
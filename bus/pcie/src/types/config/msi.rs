@@ -0,0 +1,558 @@
+//! Message signaled interrupts of an endpoint, MSI or MSI-X, with vectors
+//! allocated from an MSI controller.
+
+use core::ptr::NonNull;
+
+use alloc::vec::Vec;
+use bit_field::BitField;
+use pci_types::{Bar, CommandRegister};
+use rdif_intc::{Interface as Intc, IrqConfig, IrqId, MsiVector};
+
+use super::{Endpoint, PciHeaderBase};
+use crate::err::MsiError;
+
+const CAP_ID_MSI: u8 = 0x05;
+const CAP_ID_MSIX: u8 = 0x11;
+/// Bytes per MSI-X table entry.
+const MSIX_ENTRY_SIZE: usize = 16;
+
+/// Vectors enabled on an endpoint.
+pub(super) struct MsiState {
+    mode: MsiMode,
+    device_id: u32,
+    irqs: Vec<IrqId>,
+}
+
+/// MSI registers, or MSI-X table entries, to restore after a reset.
+pub(super) struct MsiSaved {
+    control: u32,
+    regs: Vec<(u16, u32)>,
+    entries: Vec<[u32; 4]>,
+}
+
+enum MsiMode {
+    Msi {
+        offset: u16,
+    },
+    MsiX {
+        offset: u16,
+        /// Virtual address of the vector table.
+        table: usize,
+    },
+}
+
+impl PciHeaderBase {
+    /// Offset of the first legacy capability with `id`.
+    pub(crate) fn find_capability(&self, id: u8) -> Option<u16> {
+        if !self.status().has_capability_list() {
+            return None;
+        }
+        let mut offset = (self.read(0x34) & 0xfc) as u16;
+        // bound the walk, a broken list may loop
+        for _ in 0..48 {
+            if offset == 0 {
+                break;
+            }
+            let header = self.read(offset);
+            if header.get_bits(0..8) as u8 == id {
+                return Some(offset);
+            }
+            offset = header.get_bits(8..16) as u16 & 0xfc;
+        }
+        None
+    }
+}
+
+impl Endpoint {
+    /// Allocate `count` vectors for the endpoint's requester `device_id` from
+    /// the MSI controller `intc` and enable MSI with them. INTx is disabled.
+    ///
+    /// MSI gives a function a power-of-two block of vectors, so up to `count`
+    /// rounded up are taken from `intc`.
+    pub fn enable_msi(
+        &mut self,
+        intc: &mut dyn Intc,
+        device_id: u32,
+        count: usize,
+    ) -> Result<Vec<IrqConfig>, MsiError> {
+        if self.msi.is_some() {
+            return Err(MsiError::AlreadyEnabled);
+        }
+        let offset = self
+            .find_capability(CAP_ID_MSI)
+            .ok_or(MsiError::NoCapability("MSI"))?;
+        let control = self.read(offset);
+        let supported = 1 << control.get_bits(17..20);
+        let block = count.next_power_of_two();
+        if count == 0 || block > supported {
+            return Err(MsiError::TooMany {
+                requested: count,
+                supported,
+            });
+        }
+
+        let vectors = intc
+            .alloc_msi(device_id, block, true)
+            .map_err(MsiError::Alloc)?;
+        let irqs: Vec<IrqId> = vectors.iter().map(|v| v.config.irq).collect();
+        let Some(first) = vectors.first() else {
+            return Err(MsiError::TooMany {
+                requested: count,
+                supported: 0,
+            });
+        };
+
+        let is_64bit = control.get_bit(23);
+        let message = first.message;
+        if !is_64bit && message.address > u32::MAX as u64 {
+            for irq in irqs {
+                intc.free_msi(device_id, irq);
+            }
+            return Err(MsiError::AddressTooHigh(message.address));
+        }
+
+        self.write(offset + 0x04, message.address as u32);
+        let data_offset = if is_64bit {
+            self.write(offset + 0x08, (message.address >> 32) as u32);
+            0x0c
+        } else {
+            0x08
+        };
+        self.write(offset + data_offset, message.data);
+        if control.get_bit(24) {
+            // per-vector mask follows the data
+            self.write(offset + data_offset + 0x04, 0);
+        }
+
+        let mut control = control;
+        control.set_bits(20..23, block.trailing_zeros());
+        control.set_bit(16, true);
+        self.write(offset, control);
+        self.set_intx_disabled(true);
+
+        self.msi = Some(MsiState {
+            mode: MsiMode::Msi { offset },
+            device_id,
+            irqs,
+        });
+        Ok(Self::configs(vectors, count))
+    }
+
+    /// Allocate `count` vectors for the endpoint's requester `device_id` from
+    /// the MSI controller `intc` and enable MSI-X with them. INTx is disabled.
+    ///
+    /// `map` maps the vector table, `(physical address, size)`, into the
+    /// address space of the caller. The mapping is kept for the life of the
+    /// endpoint, later calls reuse it.
+    pub fn enable_msix(
+        &mut self,
+        intc: &mut dyn Intc,
+        device_id: u32,
+        count: usize,
+        map: impl FnOnce(u64, usize) -> Option<NonNull<u8>>,
+    ) -> Result<Vec<IrqConfig>, MsiError> {
+        if self.msi.is_some() {
+            return Err(MsiError::AlreadyEnabled);
+        }
+        let offset = self
+            .find_capability(CAP_ID_MSIX)
+            .ok_or(MsiError::NoCapability("MSI-X"))?;
+        let control = self.read(offset);
+        let supported = control.get_bits(16..27) as usize + 1;
+        if count == 0 || count > supported {
+            return Err(MsiError::TooMany {
+                requested: count,
+                supported,
+            });
+        }
+
+        let table = self.read(offset + 0x04);
+        let bir = table.get_bits(0..3) as u8;
+        let phys =
+            self.bar_address(bir).ok_or(MsiError::TableUnmapped(bir))? + (table & !0x7) as u64;
+        let table = match self.msix_table {
+            Some((mapped, table)) if mapped == phys => table,
+            _ => {
+                let table = map(phys, supported * MSIX_ENTRY_SIZE)
+                    .ok_or(MsiError::TableUnmapped(bir))?
+                    .as_ptr() as usize;
+                self.msix_table = Some((phys, table));
+                table
+            }
+        };
+
+        let vectors = intc
+            .alloc_msi(device_id, count, false)
+            .map_err(MsiError::Alloc)?;
+        let irqs = vectors.iter().map(|v| v.config.irq).collect();
+
+        // Keep the function masked while the table is written.
+        let mut control = control;
+        control.set_bit(31, true);
+        control.set_bit(30, true);
+        self.write(offset, control);
+        self.set_intx_disabled(true);
+
+        for (i, vector) in vectors.iter().enumerate() {
+            let message = vector.message;
+            Self::write_entry(table, i, 0, message.address as u32);
+            Self::write_entry(table, i, 4, (message.address >> 32) as u32);
+            Self::write_entry(table, i, 8, message.data);
+            Self::write_entry(table, i, 12, 0);
+        }
+
+        control.set_bit(30, false);
+        self.write(offset, control);
+
+        self.msi = Some(MsiState {
+            mode: MsiMode::MsiX { offset, table },
+            device_id,
+            irqs,
+        });
+        Ok(Self::configs(vectors, count))
+    }
+
+    /// Disable MSI or MSI-X and give the vectors back to `intc`, which must
+    /// be the controller they were allocated from. INTx is enabled again.
+    pub fn disable_msi(&mut self, intc: &mut dyn Intc) -> Result<(), MsiError> {
+        let state = self.msi.take().ok_or(MsiError::NotEnabled)?;
+        let offset = match state.mode {
+            MsiMode::Msi { offset } => {
+                let mut control = self.read(offset);
+                control.set_bit(16, false);
+                self.write(offset, control);
+                offset
+            }
+            MsiMode::MsiX { offset, .. } => {
+                let mut control = self.read(offset);
+                control.set_bit(31, false);
+                self.write(offset, control);
+                offset
+            }
+        };
+        log::debug!("{} MSI at {offset:#x} disabled", self.address());
+        for irq in state.irqs {
+            intc.free_msi(state.device_id, irq);
+        }
+        self.set_intx_disabled(false);
+        Ok(())
+    }
+
+    /// Mask or unmask vector `index`. MSI needs per-vector masking support.
+    pub fn set_msi_masked(&mut self, index: usize, masked: bool) -> Result<(), MsiError> {
+        let state = self.msi.as_ref().ok_or(MsiError::NotEnabled)?;
+        if index >= state.irqs.len() {
+            return Err(MsiError::MaskUnsupported(index));
+        }
+        match state.mode {
+            MsiMode::Msi { offset } => {
+                let control = self.read(offset);
+                if !control.get_bit(24) {
+                    return Err(MsiError::MaskUnsupported(index));
+                }
+                let mask_offset = offset + if control.get_bit(23) { 0x10 } else { 0x0c };
+                let mut mask = self.read(mask_offset);
+                mask.set_bit(index, masked);
+                self.write(mask_offset, mask);
+            }
+            MsiMode::MsiX { table, .. } => {
+                Self::write_entry(table, index, 12, masked as u32);
+            }
+        }
+        Ok(())
+    }
+
+    /// Save what a reset clears of the enabled MSI or MSI-X.
+    pub(super) fn save_msi(&self) -> Option<MsiSaved> {
+        let state = self.msi.as_ref()?;
+        let saved = match state.mode {
+            MsiMode::Msi { offset } => {
+                let control = self.read(offset);
+                let mut len = if control.get_bit(23) { 0x0c } else { 0x08 };
+                if control.get_bit(24) {
+                    len += 0x04;
+                }
+                MsiSaved {
+                    control,
+                    regs: (0x04..=len)
+                        .step_by(4)
+                        .map(|reg| (offset + reg, self.read(offset + reg)))
+                        .collect(),
+                    entries: Vec::new(),
+                }
+            }
+            MsiMode::MsiX { offset, table } => MsiSaved {
+                control: self.read(offset),
+                regs: Vec::new(),
+                entries: (0..state.irqs.len())
+                    .map(|i| core::array::from_fn(|dw| Self::read_entry(table, i, dw * 4)))
+                    .collect(),
+            },
+        };
+        Some(saved)
+    }
+
+    pub(super) fn restore_msi(&mut self, saved: MsiSaved) {
+        let Some(state) = self.msi.as_ref() else {
+            return;
+        };
+        match state.mode {
+            MsiMode::Msi { offset } => {
+                for &(reg, value) in &saved.regs {
+                    self.write(reg, value);
+                }
+                self.write(offset, saved.control);
+            }
+            MsiMode::MsiX { offset, table } => {
+                let mut control = saved.control;
+                control.set_bit(30, true);
+                self.write(offset, control);
+                for (i, entry) in saved.entries.iter().enumerate() {
+                    for (dw, &value) in entry.iter().enumerate() {
+                        Self::write_entry(table, i, dw * 4, value);
+                    }
+                }
+                self.write(offset, saved.control);
+            }
+        }
+    }
+
+    /// Address of the memory BAR at `slot` as assigned, read without sizing
+    /// it: the device may be decoding it. `None` if it is not a memory BAR or
+    /// unassigned.
+    fn bar_address(&self, slot: u8) -> Option<u64> {
+        if slot >= 6 {
+            return None;
+        }
+        if let Some(virtfn) = &self.virtfn {
+            return match virtfn.bars[slot as usize]? {
+                Bar::Memory32 { address, .. } => Some(address as u64),
+                Bar::Memory64 { address, .. } => Some(address),
+                Bar::Io { .. } => None,
+            };
+        }
+        let offset = 0x10 + slot as u16 * 4;
+        let low = self.read(offset);
+        if low.get_bit(0) {
+            return None;
+        }
+        let mut address = (low & !0xf) as u64;
+        if low.get_bits(1..3) == 0b10 && slot < 5 {
+            address |= (self.read(offset + 4) as u64) << 32;
+        }
+        (address != 0).then_some(address)
+    }
+
+    fn set_intx_disabled(&mut self, disabled: bool) {
+        self.update_command(|mut cmd| {
+            cmd.set(CommandRegister::INTERRUPT_DISABLE, disabled);
+            cmd
+        });
+    }
+
+    fn write_entry(table: usize, index: usize, offset: usize, value: u32) {
+        let reg = (table + index * MSIX_ENTRY_SIZE + offset) as *mut u32;
+        unsafe { reg.write_volatile(value) };
+    }
+
+    fn read_entry(table: usize, index: usize, offset: usize) -> u32 {
+        let reg = (table + index * MSIX_ENTRY_SIZE + offset) as *const u32;
+        unsafe { reg.read_volatile() }
+    }
+
+    fn configs(vectors: Vec<MsiVector>, count: usize) -> Vec<IrqConfig> {
+        vectors.into_iter().take(count).map(|v| v.config).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rdif_intc::{DriverGeneric, IrqConfig, KError, MsiMessage, Trigger};
+
+    use super::*;
+    use crate::mock::*;
+
+    /// Hands out vectors from 32 with data from 0x20, all writing to
+    /// `address`.
+    struct MsiController {
+        address: u64,
+        freed: Vec<IrqId>,
+    }
+
+    impl MsiController {
+        fn new(address: u64) -> Self {
+            Self {
+                address,
+                freed: Vec::new(),
+            }
+        }
+    }
+
+    impl DriverGeneric for MsiController {
+        fn open(&mut self) -> Result<(), KError> {
+            Ok(())
+        }
+
+        fn close(&mut self) -> Result<(), KError> {
+            Ok(())
+        }
+    }
+
+    impl Intc for MsiController {
+        fn alloc_msi(
+            &mut self,
+            _device_id: u32,
+            count: usize,
+            _contiguous: bool,
+        ) -> Result<Vec<MsiVector>, KError> {
+            Ok((0..count)
+                .map(|i| MsiVector {
+                    config: IrqConfig {
+                        irq: (32 + i).into(),
+                        trigger: Trigger::EdgeRising,
+                        is_private: false,
+                    },
+                    message: MsiMessage {
+                        address: self.address,
+                        data: 0x20 + i as u32,
+                    },
+                })
+                .collect())
+        }
+
+        fn free_msi(&mut self, _device_id: u32, irq: IrqId) {
+            self.freed.push(irq);
+        }
+    }
+
+    /// An endpoint with MSI at 0x50, after PM, and `control` as Message
+    /// Control.
+    fn endpoint(control: u16) -> (ConfigSpace, Endpoint) {
+        let space = ConfigSpace::default();
+        let address = space.add(
+            0,
+            0,
+            0,
+            Function::endpoint(0x8086, 1)
+                .capability(0x40, 0x01)
+                .capability(0x50, CAP_ID_MSI)
+                .set(0x50, (control as u32) << 16 | CAP_ID_MSI as u32),
+        );
+        let base = PciHeaderBase::new(&mut space.controller(), address).unwrap();
+        (space, Endpoint::new(base, None, 0))
+    }
+
+    #[test]
+    fn test_msi_64bit_mask_offset() {
+        // 4 vectors, 64-bit, per-vector masking
+        let (space, mut ep) = endpoint(0x0184);
+        let address = ep.address();
+        let mut intc = MsiController::new(0x1_0804_0040);
+        let configs = ep.enable_msi(&mut intc, 0x10, 3).unwrap();
+        assert_eq!(configs.len(), 3);
+        assert_eq!(space.read(address, 0x54), 0x0804_0040);
+        assert_eq!(space.read(address, 0x58), 0x1);
+        assert_eq!(space.read(address, 0x5c), 0x20);
+        // MSI Enable with 4 vectors, INTx disabled
+        assert_eq!(space.read(address, 0x50) >> 16, 0x01a5);
+        assert_ne!(space.read(address, 0x04) & 1 << 10, 0);
+
+        ep.set_msi_masked(2, true).unwrap();
+        assert_eq!(space.read(address, 0x60), 0b100);
+        assert_eq!(space.read(address, 0x5c), 0x20);
+        ep.set_msi_masked(2, false).unwrap();
+        assert_eq!(space.read(address, 0x60), 0);
+    }
+
+    #[test]
+    fn test_msi_32bit_mask_offset() {
+        // 2 vectors, 32-bit, per-vector masking
+        let (space, mut ep) = endpoint(0x0102);
+        let address = ep.address();
+        let mut intc = MsiController::new(0x0804_0040);
+        ep.enable_msi(&mut intc, 0x10, 2).unwrap();
+        assert_eq!(space.read(address, 0x54), 0x0804_0040);
+        assert_eq!(space.read(address, 0x58), 0x20);
+
+        ep.set_msi_masked(1, true).unwrap();
+        assert_eq!(space.read(address, 0x5c), 0b10);
+        assert_eq!(space.read(address, 0x58), 0x20);
+        assert!(matches!(
+            ep.set_msi_masked(2, true),
+            Err(MsiError::MaskUnsupported(2))
+        ));
+
+        ep.disable_msi(&mut intc).unwrap();
+        assert_eq!(space.read(address, 0x50) >> 16 & 1, 0);
+        assert_eq!(intc.freed, [IrqId::from(32), IrqId::from(33)]);
+    }
+
+    #[test]
+    fn test_msi_without_mask() {
+        let (_space, mut ep) = endpoint(0x0082);
+        let mut intc = MsiController::new(0x0804_0040);
+        ep.enable_msi(&mut intc, 0x10, 1).unwrap();
+        assert!(matches!(
+            ep.set_msi_masked(0, true),
+            Err(MsiError::MaskUnsupported(0))
+        ));
+    }
+
+    #[test]
+    fn test_msi_enable_errors() {
+        let (_space, mut ep) = endpoint(0x0102);
+        let mut intc = MsiController::new(0x1_0804_0040);
+        assert!(matches!(
+            ep.enable_msi(&mut intc, 0x10, 2),
+            Err(MsiError::AddressTooHigh(0x1_0804_0040))
+        ));
+        assert_eq!(intc.freed, [IrqId::from(32), IrqId::from(33)]);
+        assert!(matches!(
+            ep.enable_msi(&mut intc, 0x10, 4),
+            Err(MsiError::TooMany {
+                requested: 4,
+                supported: 2
+            })
+        ));
+    }
+
+    #[test]
+    fn test_msix_table_mapped_once() {
+        let space = ConfigSpace::default();
+        let address = space.add(
+            0,
+            0,
+            0,
+            Function::endpoint(0x8086, 1)
+                .capability(0x50, CAP_ID_MSIX)
+                // 4 entries, table at 0x2000 of BAR2
+                .set(0x50, 0x0003 << 16 | CAP_ID_MSIX as u32)
+                .set(0x54, 0x2000 | 2)
+                .bar64(2, 0x4000, true)
+                .set(0x18, 0x0000_000c)
+                .set(0x1c, 0x80),
+        );
+        let base = PciHeaderBase::new(&mut space.controller(), address).unwrap();
+        let mut ep = Endpoint::new(base, None, 0);
+        let mut intc = MsiController::new(0x0804_0040);
+
+        let mut table = [0u32; 4 * 4];
+        let virt = NonNull::new(table.as_mut_ptr() as *mut u8).unwrap();
+        let mut mapped = Vec::new();
+        for _ in 0..2 {
+            ep.enable_msix(&mut intc, 0x10, 2, |phys, size| {
+                mapped.push((phys, size));
+                Some(virt)
+            })
+            .unwrap();
+            ep.disable_msi(&mut intc).unwrap();
+        }
+        assert_eq!(mapped, [(0x80_0000_2000, 4 * MSIX_ENTRY_SIZE)]);
+        assert_eq!(table[4..8], [0x0804_0040, 0, 0x21, 0]);
+        // the BAR was read as assigned, not sized
+        assert!(space
+            .written(address)
+            .iter()
+            .all(|&(offset, _)| !(0x18..0x20).contains(&offset)));
+    }
+}
@@ -1,7 +1,10 @@
-use core::{fmt::Debug, ops::Deref};
+use core::{
+    fmt::Debug,
+    ops::{Deref, Range},
+};
 
 use bit_field::BitField;
-use pci_types::{ConfigRegionAccess, PciPciBridgeHeader};
+use pci_types::{CommandRegister, ConfigRegionAccess, PciPciBridgeHeader};
 use rdif_pcie::ConfigAccess;
 
 use super::PciHeaderBase;
@@ -10,14 +13,21 @@ pub struct PciPciBridge {
     base: Option<PciHeaderBase>,
     header: Option<PciPciBridgeHeader>,
     is_root: bool,
+    /// Bus numbers of the root, which has no config space to hold them.
+    root_bus: BusNumber,
 }
 
 impl PciPciBridge {
-    pub(crate) fn root() -> Self {
+    pub(crate) fn root(bus: u8) -> Self {
         Self {
             base: None,
             header: None,
             is_root: true,
+            root_bus: BusNumber {
+                primary: bus,
+                secondary: bus,
+                subordinate: bus,
+            },
         }
     }
 
@@ -29,6 +39,11 @@ impl PciPciBridge {
             base: Some(base),
             header: Some(header),
             is_root: false,
+            root_bus: BusNumber {
+                primary: 0,
+                secondary: 0,
+                subordinate: 0,
+            },
         }
     }
 
@@ -36,27 +51,27 @@ impl PciPciBridge {
         self.header.as_ref().expect("Not a root bridge")
     }
 
-    fn access(&self) -> &ConfigAccess {
+    pub(super) fn access(&self) -> &ConfigAccess {
         &self.base.as_ref().expect("Not a root bridge").root
     }
 
     pub fn primary_bus_number(&self) -> u8 {
         if self.is_root {
-            return 0;
+            return self.root_bus.primary;
         }
         self.header().primary_bus_number(self.access())
     }
 
     pub fn secondary_bus_number(&self) -> u8 {
         if self.is_root {
-            return 0;
+            return self.root_bus.secondary;
         }
         self.header().secondary_bus_number(self.access())
     }
 
     pub fn subordinate_bus_number(&self) -> u8 {
         if self.is_root {
-            return 0;
+            return self.root_bus.subordinate;
         }
         self.header().subordinate_bus_number(self.access())
     }
@@ -66,6 +81,7 @@ impl PciPciBridge {
         F: FnOnce(BusNumber) -> BusNumber,
     {
         if self.is_root {
+            self.root_bus = f(self.root_bus);
             return;
         }
         let address = self.base.as_ref().unwrap().address();
@@ -82,8 +98,100 @@ impl PciPciBridge {
             self.access().write(address, 0x18, data);
         }
     }
+
+    /// Forward the non-prefetchable memory `window` to the secondary bus,
+    /// `None` closes it. Bounds must be aligned to [`BRIDGE_MEM_ALIGN`] and
+    /// below 4 GiB.
+    pub fn set_memory_window(&mut self, window: Option<Range<u64>>) {
+        let value = match window {
+            Some(w) => ((w.start >> 16) as u32 & 0xfff0) | (((w.end - 1) as u32) & 0xfff0_0000),
+            None => 0x0000_fff0,
+        };
+        self.write(0x20, value);
+    }
+
+    /// Forward the prefetchable memory `window` to the secondary bus, `None`
+    /// closes it. Bounds must be aligned to [`BRIDGE_MEM_ALIGN`].
+    pub fn set_prefetchable_window(&mut self, window: Option<Range<u64>>) {
+        let (base, limit) = match window {
+            Some(w) => (w.start, w.end - 1),
+            None => (u64::MAX, 0),
+        };
+        self.write(
+            0x24,
+            ((base >> 16) as u32 & 0xfff0) | (limit as u32 & 0xfff0_0000),
+        );
+        self.write(0x28, (base >> 32) as u32);
+        self.write(0x2c, (limit >> 32) as u32);
+    }
+
+    /// Forward the I/O port `window` to the secondary bus, `None` closes it.
+    /// Bounds must be aligned to [`BRIDGE_IO_ALIGN`].
+    pub fn set_io_window(&mut self, window: Option<Range<u32>>) {
+        let (base, limit) = match window {
+            Some(w) => (w.start, w.end - 1),
+            None => (0xf000, 0),
+        };
+        // the upper half is the RW1C secondary status, written as 0 to keep it
+        self.write(0x1c, ((base >> 8) & 0xf0) | (limit & 0xf000));
+        self.write(0x30, (base >> 16) | (limit & 0xffff_0000));
+    }
+
+    /// The non-prefetchable memory window as programmed, `None` if closed.
+    pub fn memory_window(&self) -> Option<Range<u64>> {
+        let value = self.read(0x20);
+        let base = ((value & 0xfff0) as u64) << 16;
+        let limit = (value & 0xfff0_0000) as u64 | 0xf_ffff;
+        (base < limit).then(|| base..limit.saturating_add(1))
+    }
+
+    /// The prefetchable memory window as programmed, `None` if closed.
+    pub fn prefetchable_window(&self) -> Option<Range<u64>> {
+        let value = self.read(0x24);
+        let mut base = ((value & 0xfff0) as u64) << 16;
+        let mut limit = (value & 0xfff0_0000) as u64 | 0xf_ffff;
+        // 64-bit decode, the upper halves follow
+        if value & 0xf == 1 {
+            base |= (self.read(0x28) as u64) << 32;
+            limit |= (self.read(0x2c) as u64) << 32;
+        }
+        (base < limit).then(|| base..limit.saturating_add(1))
+    }
+
+    /// The I/O port window as programmed, `None` if closed.
+    pub fn io_window(&self) -> Option<Range<u32>> {
+        let value = self.read(0x1c);
+        let mut base = (value & 0xf0) << 8;
+        let mut limit = (value & 0xf000) | 0xfff;
+        // 32-bit decode, the upper halves follow
+        if value & 0xf == 1 {
+            let upper = self.read(0x30);
+            base |= upper << 16;
+            limit |= upper & 0xffff_0000;
+        }
+        (base < limit).then(|| base..limit.saturating_add(1))
+    }
+
+    /// Enable forwarding of memory and I/O transactions and bus mastering.
+    pub fn enable(&mut self) {
+        let base = self.base.as_mut().expect("Not a root bridge");
+        base.update_command(|mut cmd| {
+            cmd.insert(
+                CommandRegister::MEMORY_ENABLE
+                    | CommandRegister::IO_ENABLE
+                    | CommandRegister::BUS_MASTER_ENABLE,
+            );
+            cmd
+        });
+    }
 }
 
+/// Granularity of bridge memory windows.
+pub const BRIDGE_MEM_ALIGN: u64 = 1 << 20;
+/// Granularity of bridge I/O windows.
+pub const BRIDGE_IO_ALIGN: u64 = 1 << 12;
+
+#[derive(Debug, Clone, Copy)]
 pub struct BusNumber {
     pub primary: u8,
     pub secondary: u8,
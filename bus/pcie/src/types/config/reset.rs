@@ -0,0 +1,421 @@
+//! Resets and power management of a function.
+//!
+//! A reset returns the function's config space to its defaults. What the
+//! enumeration and the driver programmed there, BARs, command, MSI, is saved
+//! before and written back once the function answers again.
+//!
+//! The waits go through a `sleep` closure that returns `false` when it
+//! cannot wait. It is first asked for no wait at all, so that without one
+//! the request fails before the function is touched.
+
+use core::time::Duration;
+
+use bit_field::BitField;
+use pci_types::{ConfigRegionAccess, PciAddress};
+use rdif_pcie::ConfigAccess;
+
+use super::{msi::MsiSaved, Endpoint, PciPciBridge};
+use crate::err::{PowerStateError, ResetError};
+
+const CAP_ID_PM: u8 = 0x01;
+const CAP_ID_EXP: u8 = 0x10;
+const CAP_ID_AF: u8 = 0x13;
+
+/// Before config requests to a function coming out of reset.
+const RESET_RECOVERY: Duration = Duration::from_millis(100);
+/// Secondary bus reset held, at least Trst of 1ms.
+const RESET_HOLD: Duration = Duration::from_millis(2);
+/// How long a function may then keep answering not ready.
+const READY_TIMEOUT: Duration = Duration::from_secs(1);
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Bridge control, upper half of the dword at 0x3c.
+const BRIDGE_CONTROL: u16 = 0x3c;
+const SECONDARY_BUS_RESET: usize = 16 + 6;
+/// Discard Timer Status, write 1 to clear.
+const DISCARD_TIMER_STATUS: usize = 16 + 10;
+/// Write 1 to clear bits of the status register, upper half of the command.
+const STATUS_RW1C: u32 = 0xf900 << 16;
+
+/// Device power state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PowerState {
+    D0,
+    D1,
+    D2,
+    D3Hot,
+}
+
+impl PowerState {
+    fn from_bits(bits: u32) -> Self {
+        match bits & 0x3 {
+            0 => Self::D0,
+            1 => Self::D1,
+            2 => Self::D2,
+            _ => Self::D3Hot,
+        }
+    }
+
+    /// Time the function needs after a transition between `self` and `other`.
+    fn delay(self, other: Self) -> Duration {
+        match self.max(other) {
+            Self::D3Hot => Duration::from_millis(10),
+            Self::D2 => Duration::from_micros(200),
+            _ => Duration::ZERO,
+        }
+    }
+}
+
+enum Flr {
+    /// Through the PCI Express capability at the offset.
+    Exp(u16),
+    /// Through the Advanced Features capability at the offset.
+    Af(u16),
+}
+
+/// Config space a reset clears.
+struct SavedState {
+    /// Header dwords 0x04..0x40.
+    header: [u32; 15],
+    /// PCI Express device, link and device 2 controls.
+    exp: Option<(u16, [u32; 3])>,
+    msi: Option<MsiSaved>,
+}
+
+impl Endpoint {
+    /// Whether [`function_level_reset`](Self::function_level_reset) is
+    /// available.
+    pub fn has_flr(&self) -> bool {
+        self.flr().is_some()
+    }
+
+    /// Reset the function alone with a Function Level Reset, through the PCI
+    /// Express capability or else the Advanced Features one. `sleep` waits
+    /// for the given time.
+    pub fn function_level_reset(
+        &mut self,
+        sleep: impl Fn(Duration) -> bool,
+    ) -> Result<(), ResetError> {
+        let flr = self.flr().ok_or(ResetError::Unsupported("FLR"))?;
+        if !sleep(Duration::ZERO) {
+            return Err(ResetError::NoSleep);
+        }
+        let saved = self.save_state();
+        match flr {
+            Flr::Exp(offset) => {
+                // Transactions Pending in the device status
+                self.wait_idle(offset + 0x08, 16 + 5, &sleep);
+                let control = self.read(offset + 0x08) & 0xffff;
+                self.write(offset + 0x08, control | 1 << 15);
+            }
+            Flr::Af(offset) => {
+                self.wait_idle(offset + 0x04, 8, &sleep);
+                self.write(offset + 0x04, 1);
+            }
+        }
+        sleep(RESET_RECOVERY);
+        self.wait_ready(&sleep)?;
+        self.restore_state(saved);
+        Ok(())
+    }
+
+    /// Reset the function with a secondary bus reset of the bridge above it.
+    /// Every function on that bus is reset, only this one is restored.
+    pub fn secondary_bus_reset(
+        &mut self,
+        sleep: impl Fn(Duration) -> bool,
+    ) -> Result<(), ResetError> {
+        let parent = *self.upstream.last().ok_or(ResetError::NoParentBridge)?;
+        let access = self.parent.as_ref().ok_or(ResetError::NoParentBridge)?;
+        if !sleep(Duration::ZERO) {
+            return Err(ResetError::NoSleep);
+        }
+        let saved = self.save_state();
+        pulse_secondary_reset(access, parent, &sleep);
+        sleep(RESET_RECOVERY);
+        self.wait_ready(&sleep)?;
+        self.restore_state(saved);
+        Ok(())
+    }
+
+    /// The current power state, `None` without power management.
+    pub fn power_state(&self) -> Option<PowerState> {
+        let offset = self.find_capability(CAP_ID_PM)?;
+        Some(PowerState::from_bits(self.read(offset + 0x04)))
+    }
+
+    /// Move the function to `state`, waiting the time the transition needs
+    /// with `sleep`. A function reset by leaving D3hot is restored.
+    pub fn set_power_state(
+        &mut self,
+        state: PowerState,
+        sleep: impl Fn(Duration) -> bool,
+    ) -> Result<(), PowerStateError> {
+        let offset = self
+            .find_capability(CAP_ID_PM)
+            .ok_or(PowerStateError::NoCapability)?;
+        let pmc = self.read(offset);
+        let supported = match state {
+            PowerState::D1 => pmc.get_bit(16 + 9),
+            PowerState::D2 => pmc.get_bit(16 + 10),
+            _ => true,
+        };
+        if !supported {
+            return Err(PowerStateError::Unsupported(state));
+        }
+
+        let mut pmcsr = self.read(offset + 0x04);
+        let current = PowerState::from_bits(pmcsr);
+        if current == state {
+            return Ok(());
+        }
+        if current != PowerState::D0 && state != PowerState::D0 && state < current {
+            return Err(PowerStateError::InvalidTransition {
+                from: current,
+                to: state,
+            });
+        }
+        if !sleep(Duration::ZERO) {
+            return Err(PowerStateError::NoSleep);
+        }
+
+        // Without No_Soft_Reset, D3hot to D0 resets the function.
+        let saved = (current == PowerState::D3Hot && !pmcsr.get_bit(3)).then(|| self.save_state());
+        pmcsr.set_bits(0..2, state as u32);
+        // PME_Status is write 1 to clear
+        pmcsr.set_bit(15, false);
+        self.write(offset + 0x04, pmcsr);
+        sleep(current.delay(state));
+
+        if let Some(saved) = saved {
+            self.restore_state(saved);
+        }
+        Ok(())
+    }
+
+    fn flr(&self) -> Option<Flr> {
+        if let Some(offset) = self.find_capability(CAP_ID_EXP) {
+            if self.read(offset + 0x04).get_bit(28) {
+                return Some(Flr::Exp(offset));
+            }
+        }
+        let offset = self.find_capability(CAP_ID_AF)?;
+        // FLR and Transactions Pending capable
+        let cap = self.read(offset).get_bits(24..26);
+        (cap == 0b11).then_some(Flr::Af(offset))
+    }
+
+    /// Wait up to the reset recovery time for the pending bit `bit` of
+    /// `reg` to clear. A reset goes on regardless, as Linux does.
+    fn wait_idle(&self, reg: u16, bit: usize, sleep: &impl Fn(Duration) -> bool) {
+        let mut waited = Duration::ZERO;
+        while self.read(reg).get_bit(bit) {
+            if waited >= RESET_RECOVERY {
+                log::warn!("{} reset with transactions pending", self.address());
+                return;
+            }
+            sleep(POLL_INTERVAL);
+            waited += POLL_INTERVAL;
+        }
+    }
+
+    /// Poll the vendor ID until the function answers, it reads all ones, or
+    /// 0x0001 with Configuration Request Retry Status, while it is not ready.
+    /// The vendor ID of a VF always reads all ones, its command register is
+    /// polled instead, as Linux does.
+    fn wait_ready(&self, sleep: &impl Fn(Duration) -> bool) -> Result<(), ResetError> {
+        let mut waited = Duration::ZERO;
+        loop {
+            let ready = if self.is_virtfn() {
+                self.read(0x04) != 0xffff_ffff
+            } else {
+                let vendor = self.read(0x00) as u16;
+                vendor != 0xffff && vendor != 0x0001
+            };
+            if ready {
+                return Ok(());
+            }
+            if waited >= READY_TIMEOUT {
+                return Err(ResetError::NotReady);
+            }
+            sleep(POLL_INTERVAL);
+            waited += POLL_INTERVAL;
+        }
+    }
+
+    fn save_state(&self) -> SavedState {
+        let exp = self.find_capability(CAP_ID_EXP).map(|offset| {
+            let regs = [0x08, 0x10, 0x28].map(|reg| self.read(offset + reg) & 0xffff);
+            (offset, regs)
+        });
+        SavedState {
+            header: core::array::from_fn(|i| self.read(0x04 + i as u16 * 4)),
+            exp,
+            msi: self.save_msi(),
+        }
+    }
+
+    fn restore_state(&mut self, saved: SavedState) {
+        // BARs up to the interrupt line, then the command, leaving the
+        // status alone.
+        for (i, &value) in saved.header.iter().enumerate().skip(2) {
+            self.write(0x04 + i as u16 * 4, value);
+        }
+        if let Some((offset, regs)) = saved.exp {
+            for (reg, value) in [0x08, 0x10, 0x28].into_iter().zip(regs) {
+                self.write(offset + reg, value);
+            }
+        }
+        self.write(0x04, saved.header[0] & !STATUS_RW1C);
+        if let Some(msi) = saved.msi {
+            self.restore_msi(msi);
+        }
+    }
+}
+
+impl PciPciBridge {
+    /// Reset every function below the bridge with a secondary bus reset,
+    /// waiting with `sleep` until they may be accessed again. Their config
+    /// space is not restored.
+    pub fn reset_secondary_bus(
+        &mut self,
+        sleep: impl Fn(Duration) -> bool,
+    ) -> Result<(), ResetError> {
+        if !sleep(Duration::ZERO) {
+            return Err(ResetError::NoSleep);
+        }
+        pulse_secondary_reset(self.access(), self.address(), &sleep);
+        sleep(RESET_RECOVERY);
+        Ok(())
+    }
+}
+
+fn pulse_secondary_reset(
+    access: &ConfigAccess,
+    bridge: PciAddress,
+    sleep: &impl Fn(Duration) -> bool,
+) {
+    let mut control = unsafe { access.read(bridge, BRIDGE_CONTROL) };
+    control.set_bit(DISCARD_TIMER_STATUS, false);
+    control.set_bit(SECONDARY_BUS_RESET, true);
+    unsafe { access.write(bridge, BRIDGE_CONTROL, control) };
+    sleep(RESET_HOLD);
+    control.set_bit(SECONDARY_BUS_RESET, false);
+    unsafe { access.write(bridge, BRIDGE_CONTROL, control) };
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+    use core::cell::RefCell;
+
+    use super::*;
+    use crate::{mock::*, PciHeaderBase};
+
+    /// An endpoint in `state` whose power management supports D1 and D2 as
+    /// `pmc` says.
+    fn endpoint(pmc: u16, state: PowerState) -> (ConfigSpace, Endpoint) {
+        let space = ConfigSpace::default();
+        let address = space.add(
+            0,
+            0,
+            0,
+            Function::endpoint(0x8086, 1)
+                .capability(0x40, CAP_ID_PM)
+                .set(0x40, (pmc as u32) << 16 | CAP_ID_PM as u32)
+                .set(0x44, state as u32),
+        );
+        let base = PciHeaderBase::new(&mut space.controller(), address).unwrap();
+        (space, Endpoint::new(base, None, 0))
+    }
+
+    #[test]
+    fn test_power_state_delays() {
+        let (space, mut ep) = endpoint(0x0603, PowerState::D0);
+        let slept = RefCell::new(Vec::new());
+        // leaving out the zero wait that checks `sleep` works
+        let sleep = |d: Duration| {
+            if !d.is_zero() {
+                slept.borrow_mut().push(d);
+            }
+            true
+        };
+
+        ep.set_power_state(PowerState::D2, sleep).unwrap();
+        assert_eq!(ep.power_state(), Some(PowerState::D2));
+        ep.set_power_state(PowerState::D3Hot, sleep).unwrap();
+        assert_eq!(space.read(ep.address(), 0x44) & 0x3, 3);
+        ep.set_power_state(PowerState::D0, sleep).unwrap();
+        assert_eq!(ep.power_state(), Some(PowerState::D0));
+        // already there
+        ep.set_power_state(PowerState::D0, sleep).unwrap();
+        assert_eq!(
+            *slept.borrow(),
+            [
+                Duration::from_micros(200),
+                Duration::from_millis(10),
+                Duration::from_millis(10),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_power_state_transitions() {
+        let (_space, mut ep) = endpoint(0x0603, PowerState::D1);
+        ep.set_power_state(PowerState::D2, |_| true).unwrap();
+        assert!(matches!(
+            ep.set_power_state(PowerState::D1, |_| true),
+            Err(PowerStateError::InvalidTransition {
+                from: PowerState::D2,
+                to: PowerState::D1
+            })
+        ));
+        ep.set_power_state(PowerState::D3Hot, |_| true).unwrap();
+        assert!(matches!(
+            ep.set_power_state(PowerState::D2, |_| true),
+            Err(PowerStateError::InvalidTransition {
+                from: PowerState::D3Hot,
+                to: PowerState::D2
+            })
+        ));
+        ep.set_power_state(PowerState::D0, |_| true).unwrap();
+        ep.set_power_state(PowerState::D1, |_| true).unwrap();
+    }
+
+    #[test]
+    fn test_power_state_without_sleep() {
+        let (space, mut ep) = endpoint(0x0603, PowerState::D0);
+        assert!(matches!(
+            ep.set_power_state(PowerState::D3Hot, |_| false),
+            Err(PowerStateError::NoSleep)
+        ));
+        assert_eq!(space.read(ep.address(), 0x44) & 0x3, 0);
+        // nothing to wait for
+        ep.set_power_state(PowerState::D0, |_| false).unwrap();
+    }
+
+    #[test]
+    fn test_power_state_unsupported() {
+        // neither D1 nor D2
+        let (_space, mut ep) = endpoint(0x0003, PowerState::D0);
+        assert!(matches!(
+            ep.set_power_state(PowerState::D1, |_| true),
+            Err(PowerStateError::Unsupported(PowerState::D1))
+        ));
+        assert!(matches!(
+            ep.set_power_state(PowerState::D2, |_| true),
+            Err(PowerStateError::Unsupported(PowerState::D2))
+        ));
+        ep.set_power_state(PowerState::D3Hot, |_| true).unwrap();
+
+        let space = ConfigSpace::default();
+        let address = space.add(0, 0, 0, Function::endpoint(0x8086, 1));
+        let base = PciHeaderBase::new(&mut space.controller(), address).unwrap();
+        let mut ep = Endpoint::new(base, None, 0);
+        assert_eq!(ep.power_state(), None);
+        assert!(matches!(
+            ep.set_power_state(PowerState::D3Hot, |_| true),
+            Err(PowerStateError::NoCapability)
+        ));
+    }
+}
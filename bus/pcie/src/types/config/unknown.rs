@@ -8,6 +8,10 @@ pub struct Unknown {
 }
 
 impl Unknown {
+    pub(crate) fn new(base: PciHeaderBase) -> Self {
+        Self { base }
+    }
+
     fn header(&self) -> &PciHeaderBase {
         &self.base
     }
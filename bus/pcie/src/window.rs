@@ -0,0 +1,540 @@
+//! Sizing and assignment of PCI-PCI bridge memory and I/O windows.
+//!
+//! Endpoints get their BARs while they are enumerated, from the allocator of
+//! the bus they sit on. Before that, a first walk sums up what the new
+//! endpoints behind each bridge need, and every bridge seen for the first
+//! time gets its windows allocated from its parent bus, programmed, and an
+//! allocator over them in [`PcieController::bridge_allocators`].
+//!
+//! Windows are powers of two aligned to their size, like BARs, and are
+//! allocated largest first among siblings to limit fragmentation of the
+//! parent window.
+//!
+//! Under [`BarPolicy::Preserve`] the windows and BARs the firmware left are
+//! reserved where they are, bus by bus from the root, before anything new is
+//! allocated on that bus. Only what is unassigned or conflicts is moved.
+
+use alloc::{collections::btree_map::BTreeMap, vec::Vec};
+use core::{cmp::Reverse, ops::Range};
+
+use log::{debug, warn};
+use pci_types::Bar;
+use rdif_pcie::{BarPolicy, PciIo, PciMem32, PciMem64, SimpleBarAllocator};
+
+use crate::{
+    chip::PcieController, root::PciIterator, Endpoint, PciAddress, PciHeaderBase, PciPciBridge,
+    BRIDGE_IO_ALIGN, BRIDGE_MEM_ALIGN,
+};
+
+/// Memory needed on a bus, in bytes.
+#[derive(Debug, Default, Clone, Copy)]
+struct Need {
+    /// Non-prefetchable window: 32-bit BARs and 64-bit non-prefetchable ones.
+    mem: u64,
+    /// Prefetchable window: 64-bit prefetchable BARs.
+    pref: u64,
+    /// I/O window, in ports.
+    io: u64,
+}
+
+/// A BAR the firmware assigned, to be kept under [`BarPolicy::Preserve`].
+#[derive(Debug, Clone, Copy)]
+enum Assigned {
+    Mem {
+        address: u64,
+        size: u64,
+        prefetchable: bool,
+    },
+    Io {
+        port: u32,
+        size: u32,
+    },
+}
+
+impl Assigned {
+    /// The assignment of BAR `slot`, if the firmware made one. A BAR at 0
+    /// counts as unassigned.
+    fn of(ep: &Endpoint, slot: u8, bar: Bar) -> Option<Self> {
+        let assigned = match bar {
+            Bar::Memory32 {
+                address,
+                size,
+                prefetchable,
+            } => Self::Mem {
+                address: address as _,
+                size: size as _,
+                prefetchable,
+            },
+            Bar::Memory64 {
+                address,
+                size,
+                prefetchable,
+            } => Self::Mem {
+                address,
+                size,
+                prefetchable,
+            },
+            Bar::Io { .. } => {
+                let ports = ep.bar_io(slot)?;
+                Self::Io {
+                    port: ports.start,
+                    size: ports.end - ports.start,
+                }
+            }
+        };
+        match assigned {
+            Self::Mem { address: 0, .. } | Self::Io { port: 0, .. } => None,
+            assigned => Some(assigned),
+        }
+    }
+}
+
+/// Windows of a bridge seen for the first time.
+#[derive(Debug, Default)]
+struct Windows {
+    mem: Option<Range<u64>>,
+    pref: Option<Range<u64>>,
+    io: Option<Range<u32>>,
+}
+
+fn window_size(need: u64, granularity: u64) -> u64 {
+    if need == 0 {
+        0
+    } else {
+        need.next_power_of_two().max(granularity)
+    }
+}
+
+fn allocator(
+    controller: &mut PcieController,
+    bus_start: u8,
+    bus: u8,
+) -> Option<&mut SimpleBarAllocator> {
+    if bus == bus_start {
+        controller.bar_allocator.as_mut()
+    } else {
+        controller.bridge_allocators.get_mut(&bus)
+    }
+}
+
+/// Set up the bridge windows for the endpoints `realloc` selects. Returns,
+/// by endpoint, the BARs reserved where the firmware placed them as a mask
+/// of slots.
+pub(crate) fn assign(
+    controller: &mut PcieController,
+    range: Range<usize>,
+    mut realloc: impl FnMut(PciAddress) -> bool,
+) -> BTreeMap<PciAddress, u8> {
+    let bus_start = range.start as u8;
+
+    // Learn the topology and the needs of new endpoints, without moving BARs.
+    let mut needs = BTreeMap::<u8, Need>::new();
+    let mut firmware = BTreeMap::<u8, Vec<(PciAddress, u8, Assigned)>>::new();
+    let mut iter = PciIterator::new(controller, range, |_| false);
+    iter.log_skipped = false;
+    while let Some(ep) = iter.next() {
+        let address = ep.address();
+        if !realloc(address) {
+            continue;
+        }
+        let preserve = iter.controller().bar_policy_of(address) == BarPolicy::Preserve;
+        let need = needs.entry(address.bus()).or_default();
+        for (slot, bar) in ep.bars().into_iter().enumerate() {
+            let Some(bar) = bar else {
+                continue;
+            };
+            match bar {
+                Bar::Memory64 {
+                    size,
+                    prefetchable: true,
+                    ..
+                } => need.pref += size,
+                Bar::Memory64 { size, .. } => need.mem += size,
+                Bar::Memory32 { size, .. } => need.mem += size as u64,
+                Bar::Io { .. } => {
+                    if let Some(ports) = ep.bar_io(slot as _) {
+                        need.io += (ports.end - ports.start) as u64;
+                    }
+                }
+            }
+            if !preserve {
+                continue;
+            }
+            if let Some(assigned) = Assigned::of(&ep, slot as _, bar) {
+                firmware
+                    .entry(address.bus())
+                    .or_default()
+                    .push((address, slot as _, assigned));
+            }
+        }
+        // Room for the VF BARs, should the PF driver enable its VFs.
+        for region in crate::sriov::max_regions(&ep) {
+            if region.is_64bit && region.prefetchable {
+                need.pref += region.size;
+            } else {
+                need.mem += region.size;
+            }
+        }
+    }
+    let bridges = core::mem::take(&mut iter.bridges);
+
+    let mut fresh: Vec<(PciAddress, u8)> = bridges
+        .into_iter()
+        .filter(|(_, secondary)| !controller.bridge_allocators.contains_key(secondary))
+        .collect();
+
+    // Bridges are found parents first, size them children first.
+    let mut windows = BTreeMap::<u8, Need>::new();
+    for &(address, secondary) in fresh.iter().rev() {
+        let need = needs.get(&secondary).copied().unwrap_or_default();
+        let window = Need {
+            mem: window_size(need.mem, BRIDGE_MEM_ALIGN),
+            pref: window_size(need.pref, BRIDGE_MEM_ALIGN),
+            io: window_size(need.io, BRIDGE_IO_ALIGN),
+        };
+        windows.insert(secondary, window);
+
+        let parent = needs.entry(address.bus()).or_default();
+        parent.mem += window.mem;
+        parent.pref += window.pref;
+        parent.io += window.io;
+    }
+
+    // A bridge's primary bus is above its parent's, so ordering by it keeps
+    // parents first; siblings go largest first.
+    fresh.sort_by_key(|(address, secondary)| {
+        let window = windows[secondary];
+        (address.bus(), Reverse(window.mem + window.pref))
+    });
+
+    let mut kept = BTreeMap::new();
+    for siblings in fresh.chunk_by(|a, b| a.0.bus() == b.0.bus()) {
+        let parent_bus = siblings[0].0.bus();
+        reserve_bus(controller, bus_start, parent_bus, &mut firmware, &mut kept);
+
+        let mut opened = Vec::new();
+        for &(address, secondary) in siblings {
+            let Some(base) = PciHeaderBase::new(controller, address) else {
+                continue;
+            };
+            let bridge = PciPciBridge::new(base);
+            let mut found = Windows::default();
+            if controller.bar_policy_of(address) == BarPolicy::Preserve {
+                if let Some(parent) = allocator(controller, bus_start, parent_bus) {
+                    found = keep_windows(&bridge, parent);
+                }
+            }
+            opened.push((address, bridge, secondary, found));
+        }
+
+        for (address, mut bridge, secondary, mut found) in opened {
+            let window = windows[&secondary];
+            let Some(parent) = allocator(controller, bus_start, parent_bus) else {
+                continue;
+            };
+            alloc_windows(address, window, &mut found, parent);
+
+            let mut child = SimpleBarAllocator::default();
+            if let Some(mem) = &found.mem {
+                let _ = child.set_mem32(
+                    PciMem32 {
+                        address: mem.start as _,
+                        size: (mem.end - mem.start) as _,
+                    },
+                    false,
+                );
+            }
+            if let Some(pref) = &found.pref {
+                let _ = child.set_mem64(
+                    PciMem64 {
+                        address: pref.start,
+                        size: pref.end - pref.start,
+                    },
+                    true,
+                );
+            }
+            if let Some(io) = &found.io {
+                let _ = child.set_io(PciIo {
+                    address: io.start,
+                    size: io.end - io.start,
+                    cpu_address: controller.io_cpu_address(io.start).unwrap_or(io.start as _),
+                });
+            }
+            controller.bridge_allocators.insert(secondary, child);
+
+            bridge.set_memory_window(found.mem);
+            bridge.set_prefetchable_window(found.pref);
+            bridge.set_io_window(found.io);
+            bridge.enable();
+        }
+    }
+
+    // Buses without new bridges behind them.
+    let buses: Vec<u8> = firmware.keys().copied().collect();
+    for bus in buses {
+        reserve_bus(controller, bus_start, bus, &mut firmware, &mut kept);
+    }
+    kept
+}
+
+/// Reserve the windows `bridge` was left with by the firmware in `parent`.
+/// Windows that do not fit are left for [`alloc_windows`].
+fn keep_windows(bridge: &PciPciBridge, parent: &mut SimpleBarAllocator) -> Windows {
+    let address = bridge.address();
+    // A window at 0 is one the firmware did not program.
+    let found = Windows {
+        mem: bridge
+            .memory_window()
+            .filter(|w| w.start > 0 && parent.reserve(w.start, w.end - w.start, false)),
+        pref: bridge
+            .prefetchable_window()
+            .filter(|w| w.start > 0 && parent.reserve(w.start, w.end - w.start, true)),
+        io: bridge
+            .io_window()
+            .filter(|w| w.start > 0 && parent.reserve_io(w.start, w.end - w.start)),
+    };
+    debug!("bridge {address} keeps {found:x?}");
+    found
+}
+
+/// Allocate from `parent` the windows of `window` not found yet.
+fn alloc_windows(
+    address: PciAddress,
+    window: Need,
+    found: &mut Windows,
+    parent: &mut SimpleBarAllocator,
+) {
+    if found.mem.is_none() && window.mem > 0 {
+        found.mem = u32::try_from(window.mem)
+            .ok()
+            .and_then(|size| parent.alloc_memory32(size, false))
+            .map(|start| start as u64..start as u64 + window.mem);
+        if found.mem.is_none() {
+            warn!(
+                "no room for {:#x} bytes window of bridge {address}",
+                window.mem
+            );
+        }
+    }
+    if found.pref.is_none() && window.pref > 0 {
+        found.pref = parent
+            .alloc_memory64(window.pref, true)
+            .map(|start| start..start + window.pref);
+        if found.pref.is_none() {
+            warn!(
+                "no room for {:#x} bytes prefetchable window of bridge {address}",
+                window.pref
+            );
+        }
+    }
+    if found.io.is_none() && window.io > 0 {
+        found.io = parent
+            .alloc_io(window.io as _)
+            .map(|start| start..start + window.io as u32);
+        if found.io.is_none() {
+            warn!(
+                "no room for {:#x} ports window of bridge {address}",
+                window.io
+            );
+        }
+    }
+}
+
+/// Reserve the firmware BARs of the endpoints on `bus`, recording the ones
+/// kept in `kept`. The others conflict and get reassigned.
+fn reserve_bus(
+    controller: &mut PcieController,
+    bus_start: u8,
+    bus: u8,
+    firmware: &mut BTreeMap<u8, Vec<(PciAddress, u8, Assigned)>>,
+    kept: &mut BTreeMap<PciAddress, u8>,
+) {
+    let Some(bars) = firmware.remove(&bus) else {
+        return;
+    };
+    let Some(allocator) = allocator(controller, bus_start, bus) else {
+        return;
+    };
+    for (address, slot, assigned) in bars {
+        let reserved = match assigned {
+            Assigned::Mem {
+                address,
+                size,
+                prefetchable,
+            } => allocator.reserve(address, size, prefetchable),
+            Assigned::Io { port, size } => allocator.reserve_io(port, size),
+        };
+        if reserved {
+            *kept.entry(address).or_default() |= 1 << slot;
+        } else {
+            debug!("BAR{slot} of {address} {assigned:x?} conflicts, reassigning");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{enumerate_by_controller, mock::*};
+
+    #[test]
+    fn test_window_size() {
+        assert_eq!(window_size(0, BRIDGE_MEM_ALIGN), 0);
+        assert_eq!(window_size(0x1000, BRIDGE_MEM_ALIGN), BRIDGE_MEM_ALIGN);
+        assert_eq!(window_size(0x20_1000, BRIDGE_MEM_ALIGN), 0x40_0000);
+        assert_eq!(window_size(0x100, BRIDGE_IO_ALIGN), BRIDGE_IO_ALIGN);
+    }
+
+    #[test]
+    fn test_bridge_windows_aligned() {
+        let space = ConfigSpace::default();
+        space.add(
+            0,
+            0,
+            0,
+            Function::endpoint(0x8086, 1).bar32(0, 0x1000, false),
+        );
+        let bridge = space.add(0, 1, 0, Function::bridge());
+        space.add(
+            1,
+            0,
+            0,
+            Function::endpoint(0x8086, 2).bar32(0, 0x1000, false),
+        );
+        space.add(
+            1,
+            1,
+            0,
+            Function::endpoint(0x8086, 3)
+                .bar32(0, 0x20_0000, false)
+                .bar64(2, 0x10_0000, true),
+        );
+        let mut controller = space.controller();
+        let eps: Vec<Endpoint> = enumerate_by_controller(&mut controller, None).collect();
+        assert_eq!(eps.len(), 3);
+
+        let bridge = PciPciBridge::new(PciHeaderBase::new(&mut controller, bridge).unwrap());
+        assert_eq!(bridge.secondary_bus_number(), 1);
+        let mem = bridge.memory_window().unwrap();
+        assert_eq!(mem.end - mem.start, 0x40_0000);
+        assert_eq!(mem.start % 0x40_0000, 0);
+        let pref = bridge.prefetchable_window().unwrap();
+        assert_eq!(pref.end - pref.start, BRIDGE_MEM_ALIGN);
+        assert_eq!(pref.start % BRIDGE_MEM_ALIGN, 0);
+        assert!(pref.start >= 0x80_0000_0000);
+        assert!(bridge.io_window().is_none());
+
+        for ep in eps.iter().filter(|ep| ep.address().bus() == 1) {
+            for bar in ep.bars().into_iter().flatten() {
+                let (address, size, window) = match bar {
+                    Bar::Memory32 { address, size, .. } => (address as u64, size as u64, &mem),
+                    Bar::Memory64 { address, size, .. } => (address, size, &pref),
+                    Bar::Io { .. } => unreachable!(),
+                };
+                assert_eq!(address % size, 0);
+                assert!(window.start <= address && address + size <= window.end);
+            }
+        }
+    }
+
+    #[test]
+    fn test_empty_bus_range() {
+        let space = ConfigSpace::default();
+        space.add(
+            0,
+            0,
+            0,
+            Function::endpoint(0x8086, 1).bar32(0, 0x1000, false),
+        );
+        let mut controller = space.controller();
+        assert!(controller.set_bus_range(1..1).is_err());
+        assert!(controller.set_bus_range(0..0x101).is_err());
+        assert_eq!(
+            enumerate_by_controller(&mut controller, Some(1..1)).count(),
+            0
+        );
+        assert_eq!(enumerate_by_controller(&mut controller, None).count(), 1);
+    }
+
+    fn bar_address(ep: &Endpoint, slot: usize) -> u64 {
+        match ep.bars()[slot] {
+            Some(Bar::Memory32 { address, .. }) => address as u64,
+            Some(Bar::Memory64 { address, .. }) => address,
+            _ => panic!("no memory BAR{slot}"),
+        }
+    }
+
+    #[test]
+    fn test_realloc_failure_restores_bars() {
+        let space = ConfigSpace::default();
+        // each BAR fills the 32-bit window alone
+        let address = space.add(
+            0,
+            0,
+            0,
+            Function::endpoint(0x8086, 1)
+                .bar32(0, 0x1000_0000, false)
+                .bar32(1, 0x1000_0000, false)
+                .set(0x04, 0x3)
+                .set(0x10, 0x2000_0000)
+                .set(0x14, 0x3000_0000),
+        );
+        let mut controller = space.controller();
+        let eps: Vec<Endpoint> = enumerate_by_controller(&mut controller, None).collect();
+        assert!(eps[0].bar_error().is_some());
+        assert_eq!(space.read(address, 0x10), 0x2000_0000);
+        assert_eq!(space.read(address, 0x14), 0x3000_0000);
+        assert_eq!(space.read(address, 0x04) & 0x3, 0);
+
+        // the BAR placed before the failure was given back
+        let allocator = controller.bar_allocator.as_mut().unwrap();
+        assert_eq!(
+            allocator.alloc_memory32(0x1000_0000, false),
+            Some(0x1000_0000)
+        );
+    }
+
+    #[test]
+    fn test_preserve_keeps_firmware() {
+        let space = ConfigSpace::default();
+        space.add(
+            0,
+            0,
+            0,
+            Function::endpoint(0x8086, 1)
+                .bar32(0, 0x1000, false)
+                .set(0x10, 0x1800_0000),
+        );
+        let bridge = space.add(0, 1, 0, Function::bridge().set(0x20, 0x19f0_1900));
+        space.add(
+            1,
+            0,
+            0,
+            Function::endpoint(0x8086, 2)
+                .bar32(0, 0x1000, false)
+                .set(0x10, 0x1900_0000),
+        );
+        // conflicts with the first endpoint
+        space.add(
+            0,
+            2,
+            0,
+            Function::endpoint(0x8086, 3)
+                .bar32(0, 0x1000, false)
+                .set(0x10, 0x1800_0000),
+        );
+        let mut controller = space.controller();
+        controller.set_bar_policy(BarPolicy::Preserve);
+        let eps: Vec<Endpoint> = enumerate_by_controller(&mut controller, None).collect();
+        assert_eq!(eps.len(), 3);
+        let ep = |device_id| eps.iter().find(|ep| ep.device_id() == device_id).unwrap();
+        assert_eq!(bar_address(ep(1), 0), 0x1800_0000);
+        assert_eq!(bar_address(ep(2), 0), 0x1900_0000);
+        let moved = bar_address(ep(3), 0);
+        assert_ne!(moved, 0x1800_0000);
+        assert!(!(0x1900_0000..0x1a00_0000).contains(&moved));
+
+        let bridge = PciPciBridge::new(PciHeaderBase::new(&mut controller, bridge).unwrap());
+        assert_eq!(bridge.memory_window(), Some(0x1900_0000..0x1a00_0000));
+    }
+}
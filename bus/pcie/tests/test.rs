@@ -1,10 +1,17 @@
-#![no_std]
-#![no_main]
-#![feature(used_with_arg)]
+//! Enumerates the PCIe bus of QEMU `virt`, run with
+//! `cargo test --test test --target aarch64-unknown-none-softfloat`.
 
+#![cfg_attr(target_os = "none", no_std, no_main, feature(used_with_arg))]
+
+#[cfg(target_os = "none")]
 extern crate alloc;
+#[cfg(target_os = "none")]
 extern crate bare_test;
 
+#[cfg(not(target_os = "none"))]
+fn main() {}
+
+#[cfg(target_os = "none")]
 #[bare_test::tests]
 mod tests {
     use bare_test::{
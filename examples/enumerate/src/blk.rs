@@ -16,6 +16,7 @@ pub fn register() -> DriverRegister {
         }],
         level: ProbeLevel::PostKernel,
         priority: ProbePriority::DEFAULT,
+        params: &[],
     }
 }
 
@@ -25,21 +26,13 @@ fn probe(info: FdtInfo<'_>, _dev: PlatformDevice) -> Result<(), OnProbeError> {
         info.node.name()
     )))?;
 
-    if let Some(irq) = _dev.descriptor.irq_parent {
-        let intc = rdrive::get::<Intc>(irq).unwrap();
-
-        if let Some(irqs) = info.node.interrupts() {
-            for irq_prop in irqs {
-                let irq_prop: Vec<u32> = irq_prop.collect();
-                let irq_id = intc.lock().unwrap().setup_irq_by_fdt(&irq_prop);
-                debug!(
-                    "virtio mmio device [{}] setup irq: {:?}",
-                    info.node.name(),
-                    irq_id
-                );
-            }
-        }
-
+    for irq in info.irqs()? {
+        let intc = rdrive::get::<Intc>(irq.irq_parent).unwrap();
+        debug!(
+            "virtio mmio device [{}] irq: {:?}",
+            info.node.name(),
+            irq.config
+        );
         println!("parent intc: {:?}", intc.descriptor().name);
     }
 
@@ -19,6 +19,7 @@ pub fn register() -> DriverRegister {
         }],
         level: ProbeLevel::PreKernel,
         priority: ProbePriority::CLK,
+        params: &[],
     }
 }
 
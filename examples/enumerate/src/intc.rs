@@ -17,6 +17,7 @@ pub fn register() -> DriverRegister {
         }],
         level: ProbeLevel::PreKernel,
         priority: ProbePriority::INTC,
+        params: &[],
     }
 }
 
@@ -18,6 +18,7 @@ pub fn register() -> DriverRegister {
         }],
         level: ProbeLevel::PreKernel,
         priority: ProbePriority::DEFAULT,
+        params: &[],
     }
 }
 
@@ -2,13 +2,83 @@
 
 extern crate alloc;
 
+use alloc::vec::Vec;
+
 pub use rdif_base::_rdif_prelude::*;
 use rdif_base::def_driver;
 
+/// The write a device performs to raise a message-signaled interrupt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MsiMessage {
+    pub address: u64,
+    pub data: u32,
+}
+
+/// An interrupt allocated by an MSI controller.
+#[derive(Debug, Clone)]
+pub struct MsiVector {
+    pub config: IrqConfig,
+    pub message: MsiMessage,
+}
+
 pub trait Interface: DriverGeneric {
     fn setup_irq_by_fdt(&mut self, _irq_prop: &[u32]) -> IrqId {
         unimplemented!();
     }
+
+    /// Map an FDT interrupt specifier to an interrupt id, failing on
+    /// specifiers the controller does not decode.
+    ///
+    /// The default defers to [`setup_irq_by_fdt`](Interface::setup_irq_by_fdt).
+    /// Controllers that reject some specifiers override this one instead.
+    fn try_setup_irq_by_fdt(&mut self, irq_prop: &[u32]) -> Result<IrqId, KError> {
+        Ok(self.setup_irq_by_fdt(irq_prop))
+    }
+
+    /// Decode an FDT interrupt specifier into a full [`IrqConfig`].
+    ///
+    /// The default takes the id from [`try_setup_irq_by_fdt`](Interface::try_setup_irq_by_fdt)
+    /// and the trigger from the `IRQ_TYPE_*` flags in the last cell, as most
+    /// two- and three-cell bindings do. Controllers with private interrupts
+    /// should override it.
+    fn irq_config_by_fdt(&mut self, irq_prop: &[u32]) -> Result<IrqConfig, KError> {
+        let irq = self.try_setup_irq_by_fdt(irq_prop)?;
+        let flags = match irq_prop {
+            [_, .., last] => *last,
+            _ => 0,
+        };
+        let trigger = match flags & 0xf {
+            1 => Trigger::EdgeRising,
+            2 => Trigger::EdgeFailling,
+            3 => Trigger::EdgeBoth,
+            8 => Trigger::LevelLow,
+            _ => Trigger::LevelHigh,
+        };
+        Ok(IrqConfig {
+            irq,
+            trigger,
+            is_private: false,
+        })
+    }
+
+    /// Allocate `count` message-signaled interrupts for the device whose
+    /// requests carry `device_id`, e.g. a PCI requester ID translated by
+    /// `msi-map`. With `contiguous` the data values must be consecutive and
+    /// the block aligned to `count`, as multiple-message MSI requires.
+    ///
+    /// Controllers that are not MSI controllers keep the default, which
+    /// fails.
+    fn alloc_msi(
+        &mut self,
+        _device_id: u32,
+        _count: usize,
+        _contiguous: bool,
+    ) -> Result<Vec<MsiVector>, KError> {
+        Err(KError::Unknown("not an MSI controller"))
+    }
+
+    /// Return an interrupt handed out by [`alloc_msi`](Interface::alloc_msi).
+    fn free_msi(&mut self, _device_id: u32, _irq: IrqId) {}
 }
 
 def_driver!(Intc, Interface);
@@ -207,6 +207,28 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_allocate_best_fit() {
+        let mut pool = AddressAllocator::new(0x0, 0x10000).unwrap();
+        pool.allocate(0x1000, 0x1000, AllocPolicy::ExactMatch(0x3000))
+            .unwrap();
+        pool.allocate(0x1000, 0x1000, AllocPolicy::ExactMatch(0x5000))
+            .unwrap();
+        // Free are [0x0, 0x2FFF], [0x4000, 0x4FFF] and [0x6000, 0xFFFF].
+        assert_eq!(
+            pool.allocate(0x1000, 0x1000, AllocPolicy::BestFit).unwrap(),
+            RangeInclusive::new(0x4000, 0x4FFF).unwrap()
+        );
+        assert_eq!(
+            pool.allocate(0x2000, 0x2000, AllocPolicy::BestFit).unwrap(),
+            RangeInclusive::new(0x0, 0x1FFF).unwrap()
+        );
+        assert_eq!(
+            pool.allocate(0x2000, 0x2000, AllocPolicy::BestFit).unwrap(),
+            RangeInclusive::new(0x6000, 0x7FFF).unwrap()
+        );
+    }
+
     #[test]
     fn test_allocate_address_not_enough_space() {
         let mut pool = AddressAllocator::new(0x1000, 0x1000).unwrap();
@@ -347,6 +347,9 @@ impl InnerNode {
             // satisfying the specified constraints or `ResourceNotAvailable`
             // if the request can not be satisfied.
             AllocPolicy::LastMatch => self.last_match(constraint),
+            // Returns the smallest node satisfying the specified constraints
+            // or `ResourceNotAvailable` if the request can not be satisfied.
+            AllocPolicy::BestFit => self.best_match(constraint),
             // Returns the node containing the address specified or the
             // `ResourceNotAvailable` error if any of the sanity checks is not
             // passing.
@@ -445,6 +448,33 @@ impl InnerNode {
         res
     }
 
+    /// Returns the smallest free node from the managed address space that is
+    /// satisfying the specified constraints and the aligned address of the
+    /// desired memory slot. Among nodes of the same size the lowest one wins.
+    /// Or if the request can not be satisfied `ResourceNotAvailable`.
+    fn best_match(&self, constraint: &Constraint) -> Result<(&Self, RangeInclusive)> {
+        // Visit the nodes in address order so ties resolve to the lowest.
+        let candidates = [
+            self.left
+                .as_ref()
+                .and_then(|n| n.best_match(constraint).ok()),
+            self.check_constraint(constraint)
+                .ok()
+                .map(|range| (self, range)),
+            self.right
+                .as_ref()
+                .and_then(|n| n.best_match(constraint).ok()),
+        ];
+
+        let mut best: Option<(&Self, RangeInclusive)> = None;
+        for (node, range) in candidates.into_iter().flatten() {
+            if best.is_none_or(|(b, _)| node.key.len() < b.key.len()) {
+                best = Some((node, range));
+            }
+        }
+        best.ok_or(Error::ResourceNotAvailable)
+    }
+
     /// Check that the candidate node is satisfying all the constraints for
     /// the requested memory slot.
     fn check_constraint(&self, constraint: &Constraint) -> Result<RangeInclusive> {
@@ -455,7 +485,9 @@ impl InnerNode {
         let node_key = self.key;
         // Get the starting address for the memory slot.
         let range_start = match constraint.policy {
-            AllocPolicy::FirstMatch => align_up(node_key.start(), constraint.align)?,
+            AllocPolicy::FirstMatch | AllocPolicy::BestFit => {
+                align_up(node_key.start(), constraint.align)?
+            }
             AllocPolicy::LastMatch => {
                 // This operation can not underflow as we check at the beginning
                 // of this method that the requested node fits in the selected
@@ -295,6 +295,9 @@ pub enum AllocPolicy {
     FirstMatch,
     /// Allocate first matched entry from the end of the range.
     LastMatch,
+    /// Allocate from the smallest free entry that can hold the request,
+    /// at its lowest aligned address.
+    BestFit,
     /// Allocate a memory slot starting with the specified address
     /// if it is available.
     ExactMatch(u64),
@@ -1,6 +1,6 @@
 use crate::{
-    PciMem32, PciMem64,
-    addr_alloc::{self, AddressAllocator, AllocPolicy},
+    PciIo, PciMem32, PciMem64,
+    addr_alloc::{self, AddressAllocator, AllocPolicy, RangeInclusive},
 };
 
 #[derive(Default)]
@@ -11,6 +11,8 @@ pub struct SimpleBarAllocator {
     // Prefetchable windows
     mem32_pref: Option<AddressAllocator>,
     mem64_pref: Option<AddressAllocator>,
+    // I/O port window
+    io: Option<AddressAllocator>,
 }
 
 impl SimpleBarAllocator {
@@ -44,35 +46,106 @@ impl SimpleBarAllocator {
         Ok(())
     }
 
+    /// Convenience: add the I/O port window.
+    pub fn set_io(&mut self, space: PciIo) -> Result<(), addr_alloc::Error> {
+        self.io = Some(AddressAllocator::new(space.address as _, space.size as _)?);
+        Ok(())
+    }
+
+    /// Allocate from the 32-bit windows. Prefetchable BARs prefer the
+    /// prefetchable window and fall back to the non-prefetchable one.
     pub fn alloc_memory32(&mut self, size: u32, prefetchable: bool) -> Option<u32> {
-        if prefetchable
-            && let Some(set) = self.mem32_pref.as_mut()
-            && let Ok(addr) = set.allocate(size as _, size as _, AllocPolicy::FirstMatch)
-        {
-            return Some(addr.start() as _);
+        if prefetchable && let Some(addr) = Self::alloc(&mut self.mem32_pref, size as _) {
+            return Some(addr as _);
         }
-
-        let res = self
-            .mem32
-            .as_mut()?
-            .allocate(size as _, size as _, AllocPolicy::FirstMatch)
-            .ok()?;
-        Some(res.start() as _)
+        Self::alloc(&mut self.mem32, size as _).map(|addr| addr as _)
     }
 
+    /// Allocate from the 64-bit windows. When they are missing or full, e.g.
+    /// on platforms with only a 32-bit window, the BAR is placed below 4 GiB.
     pub fn alloc_memory64(&mut self, size: u64, prefetchable: bool) -> Option<u64> {
+        if prefetchable && let Some(addr) = Self::alloc(&mut self.mem64_pref, size) {
+            return Some(addr);
+        }
+        if let Some(addr) = Self::alloc(&mut self.mem64, size) {
+            return Some(addr);
+        }
+        let addr = self.alloc_memory32(u32::try_from(size).ok()?, prefetchable)?;
+        Some(addr as _)
+    }
+
+    pub fn alloc_io(&mut self, size: u32) -> Option<u32> {
+        Self::alloc(&mut self.io, size as _).map(|port| port as _)
+    }
+
+    /// Claim `address..address + size` where the firmware already placed a
+    /// BAR or bridge window. Non-prefetchable ranges are only accepted in
+    /// non-prefetchable windows.
+    ///
+    /// Returns `false` if no suitable window holds the range free.
+    pub fn reserve(&mut self, address: u64, size: u64, prefetchable: bool) -> bool {
         if prefetchable
-            && let Some(set) = self.mem64_pref.as_mut()
-            && let Ok(addr) = set.allocate(size as _, size as _, AllocPolicy::FirstMatch)
+            && (Self::reserve_in(&mut self.mem32_pref, address, size)
+                || Self::reserve_in(&mut self.mem64_pref, address, size))
         {
-            return Some(addr.start() as _);
+            return true;
         }
+        Self::reserve_in(&mut self.mem32, address, size)
+            || Self::reserve_in(&mut self.mem64, address, size)
+    }
+
+    /// Claim ports where the firmware already placed an I/O BAR or window.
+    pub fn reserve_io(&mut self, port: u32, size: u32) -> bool {
+        Self::reserve_in(&mut self.io, port as _, size as _)
+    }
+
+    fn reserve_in(set: &mut Option<AddressAllocator>, address: u64, size: u64) -> bool {
+        set.as_mut().is_some_and(|set| {
+            set.allocate(size, 1, AllocPolicy::ExactMatch(address))
+                .is_ok()
+        })
+    }
 
-        let res = self
-            .mem64
+    /// Take `size` bytes aligned to `size` from the smallest free range of
+    /// `set` that holds them, keeping large ranges for large BARs.
+    fn alloc(set: &mut Option<AddressAllocator>, size: u64) -> Option<u64> {
+        let range = set
             .as_mut()?
-            .allocate(size as _, size as _, AllocPolicy::FirstMatch)
+            .allocate(size, size, AllocPolicy::BestFit)
             .ok()?;
-        Some(res.start() as _)
+        Some(range.start())
+    }
+
+    /// Return ports handed out by [`alloc_io`](Self::alloc_io).
+    pub fn free_io(&mut self, port: u32, size: u32) -> bool {
+        let Some(io) = self.io.as_mut() else {
+            return false;
+        };
+        match RangeInclusive::new(port as _, port as u64 + size as u64 - 1) {
+            Ok(range) if size > 0 => io.free(&range).is_ok(),
+            _ => false,
+        }
+    }
+
+    /// Return a BAR range handed out by [`alloc_memory32`](Self::alloc_memory32)
+    /// or [`alloc_memory64`](Self::alloc_memory64) to the window it came from.
+    ///
+    /// Returns `false` if no window holds an allocation at `address..address + size`.
+    pub fn free(&mut self, address: u64, size: u64) -> bool {
+        if size == 0 {
+            return false;
+        }
+        let Ok(range) = RangeInclusive::new(address, address + (size - 1)) else {
+            return false;
+        };
+        [
+            &mut self.mem32_pref,
+            &mut self.mem32,
+            &mut self.mem64_pref,
+            &mut self.mem64,
+        ]
+        .into_iter()
+        .flatten()
+        .any(|set| set.free(&range).is_ok())
     }
 }
@@ -2,11 +2,11 @@
 
 extern crate alloc;
 
-use core::cell::UnsafeCell;
+use core::{cell::UnsafeCell, ops::Range};
 
-use alloc::{boxed::Box, sync::Arc};
-use pci_types::ConfigRegionAccess;
+use alloc::{boxed::Box, collections::btree_map::BTreeMap, sync::Arc};
 pub use pci_types::PciAddress;
+use pci_types::{Bar, ConfigRegionAccess};
 pub use rdif_base::{DriverGeneric, KError};
 
 mod addr_alloc;
@@ -26,6 +26,28 @@ pub struct PciMem64 {
     pub size: u64,
 }
 
+/// I/O port window, `address` is the port number seen by devices.
+#[derive(Debug, Clone, Copy)]
+pub struct PciIo {
+    pub address: u32,
+    pub size: u32,
+    /// CPU physical address port `address` is accessed at: the port itself
+    /// on x86, a memory-mapped window on architectures without port I/O.
+    pub cpu_address: u64,
+}
+
+/// What enumeration does with BARs and bridge windows the firmware already
+/// programmed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BarPolicy {
+    /// Assign everything again from the controller windows.
+    #[default]
+    Reassign,
+    /// Keep assignments that lie free inside the windows of the bus, only
+    /// allocate the ones that are unassigned or conflict.
+    Preserve,
+}
+
 impl rdif_base::DriverGeneric for PcieController {
     fn open(&mut self) -> Result<(), rdif_base::KError> {
         self.as_mut().open()
@@ -57,9 +79,45 @@ pub trait Interface: DriverGeneric {
     fn write(&mut self, address: PciAddress, offset: u16, value: u32);
 }
 
+/// The allocator of a [`PcieController`] a BAR was taken from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BarOwner {
+    /// The controller windows, [`PcieController::bar_allocator`].
+    Root,
+    /// The window of the bridge with this secondary bus, in
+    /// [`PcieController::bridge_allocators`].
+    Bridge(u8),
+}
+
+/// The VF BARs of an SR-IOV physical function, each followed by the same BAR
+/// of every other VF.
+#[derive(Debug, Clone, Copy)]
+pub struct VfBars {
+    pub num_vfs: u16,
+    /// VF BAR0-5, with the address of the first VF and the size of one.
+    pub bars: [Option<Bar>; 6],
+    /// Where the VF BARs were allocated, `None` if the firmware assigned them.
+    pub owner: Option<BarOwner>,
+}
+
 pub struct PcieController {
     chip: Arc<ChipRaw>,
     pub bar_allocator: Option<SimpleBarAllocator>,
+    /// Allocators over the memory windows of PCI-PCI bridges, by the bridge's
+    /// secondary bus. Endpoints on that bus get their BARs from them.
+    pub bridge_allocators: BTreeMap<u8, SimpleBarAllocator>,
+    /// Buses decoded by the controller, all of them if `None`.
+    pub bus_range: Option<Range<usize>>,
+    /// PCI segment (domain) of the controller, from `linux,pci-domain` or the
+    /// ACPI MCFG entry.
+    pub segment: u16,
+    /// Policy for firmware assignments of functions without an override in
+    /// `device_bar_policies`.
+    pub bar_policy: BarPolicy,
+    pub device_bar_policies: BTreeMap<PciAddress, BarPolicy>,
+    /// VF BARs assigned to SR-IOV physical functions, by physical function.
+    pub vf_bars: BTreeMap<PciAddress, VfBars>,
+    io: Option<PciIo>,
 }
 
 impl PcieController {
@@ -67,6 +125,13 @@ impl PcieController {
         Self {
             chip: Arc::new(ChipRaw::new(chip)),
             bar_allocator: None,
+            bridge_allocators: BTreeMap::new(),
+            bus_range: None,
+            segment: 0,
+            bar_policy: BarPolicy::default(),
+            device_bar_policies: BTreeMap::new(),
+            vf_bars: BTreeMap::new(),
+            io: None,
         }
     }
     pub fn typed_ref<T: Interface>(&self) -> Option<&T> {
@@ -96,6 +161,84 @@ impl PcieController {
         let al = self.bar_allocator.get_or_insert_default();
         al.set_mem64(space, perfetchable).unwrap();
     }
+
+    pub fn set_io(&mut self, space: PciIo) {
+        let al = self.bar_allocator.get_or_insert_default();
+        al.set_io(space).unwrap();
+        self.io = Some(space);
+    }
+
+    /// CPU physical address of `port`, `None` if it is outside the I/O
+    /// window. Bridge windows are carved from that window, so this holds for
+    /// ports behind bridges too.
+    pub fn io_cpu_address(&self, port: u32) -> Option<u64> {
+        let io = self.io?;
+        let offset = port.checked_sub(io.address).filter(|&off| off < io.size)?;
+        Some(io.cpu_address + offset as u64)
+    }
+
+    /// The allocator BARs of functions on `bus` are taken from: the
+    /// controller windows for the first bus, the window of the bridge above
+    /// for the others.
+    pub fn bar_owner(&self, bus: u8) -> BarOwner {
+        let bus_start = self.bus_range.as_ref().map_or(0, |range| range.start as u8);
+        if bus == bus_start {
+            BarOwner::Root
+        } else {
+            BarOwner::Bridge(bus)
+        }
+    }
+
+    pub fn allocator(&mut self, owner: BarOwner) -> Option<&mut SimpleBarAllocator> {
+        match owner {
+            BarOwner::Root => self.bar_allocator.as_mut(),
+            BarOwner::Bridge(bus) => self.bridge_allocators.get_mut(&bus),
+        }
+    }
+
+    /// Return a BAR range to the allocator `owner` it was taken from.
+    pub fn free_bar(&mut self, owner: BarOwner, address: u64, size: u64) -> bool {
+        self.allocator(owner)
+            .is_some_and(|al| al.free(address, size))
+    }
+
+    /// Return I/O ports to the allocator `owner` they were taken from.
+    pub fn free_io_bar(&mut self, owner: BarOwner, port: u32, size: u32) -> bool {
+        self.allocator(owner)
+            .is_some_and(|al| al.free_io(port, size))
+    }
+
+    pub fn set_segment(&mut self, segment: u16) {
+        self.segment = segment;
+    }
+
+    pub fn set_bar_policy(&mut self, policy: BarPolicy) {
+        self.bar_policy = policy;
+    }
+
+    /// Override the controller's [`BarPolicy`] for the function at `address`.
+    pub fn set_device_bar_policy(&mut self, address: PciAddress, policy: BarPolicy) {
+        self.device_bar_policies.insert(address, policy);
+    }
+
+    /// The [`BarPolicy`] that applies to the function at `address`.
+    pub fn bar_policy_of(&self, address: PciAddress) -> BarPolicy {
+        self.device_bar_policies
+            .get(&address)
+            .copied()
+            .unwrap_or(self.bar_policy)
+    }
+
+    /// Limit enumeration to `range`, e.g. from the `bus-range` of the host bridge.
+    ///
+    /// Fails with [`KError::InvalidArg`] if `range` holds no bus or goes past bus 255.
+    pub fn set_bus_range(&mut self, range: Range<usize>) -> Result<(), KError> {
+        if range.is_empty() || range.end > 0x100 {
+            return Err(KError::InvalidArg { name: "range" });
+        }
+        self.bus_range = Some(range);
+        Ok(())
+    }
 }
 
 impl ConfigRegionAccess for PcieController {
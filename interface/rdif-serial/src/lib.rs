@@ -6,6 +6,7 @@ use core::{
     any::Any,
     fmt::{Debug, Display},
     num::NonZeroU32,
+    str::FromStr,
 };
 
 use alloc::boxed::Box;
@@ -160,6 +161,48 @@ impl Config {
     }
 }
 
+/// 解析 Linux 风格的串口选项 `<baud>{<parity>{<bits>{<flow>}}}`，如 `115200n8`
+///
+/// 未给出的字段保持 `None`，流控字符 `r` 被忽略。
+impl FromStr for Config {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let digits = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        let (baud, rest) = s.split_at(digits);
+        let mut config = Config::new();
+        if !baud.is_empty() {
+            let baud = baud.parse().map_err(|_| ConfigError::InvalidBaudrate)?;
+            if baud == 0 {
+                return Err(ConfigError::InvalidBaudrate);
+            }
+            config = config.baudrate(baud);
+        }
+
+        let mut rest = rest.chars();
+        if let Some(c) = rest.next() {
+            config = config.parity(match c {
+                'n' => Parity::None,
+                'o' => Parity::Odd,
+                'e' => Parity::Even,
+                'm' => Parity::Mark,
+                's' => Parity::Space,
+                _ => return Err(ConfigError::UnsupportedParity),
+            });
+        }
+        if let Some(c) = rest.next() {
+            config = config.data_bits(match c {
+                '5' => DataBits::Five,
+                '6' => DataBits::Six,
+                '7' => DataBits::Seven,
+                '8' => DataBits::Eight,
+                _ => return Err(ConfigError::UnsupportedDataBits),
+            });
+        }
+        Ok(config)
+    }
+}
+
 pub trait InterfaceRaw: Send + Any + 'static {
     type IrqHandler: TIrqHandler;
     type Sender: TSender;
@@ -306,3 +349,38 @@ pub trait TReciever: Send + 'static {
         Ok(read_count)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_from_str() {
+        let config: Config = "115200n8".parse().unwrap();
+        assert_eq!(config.baudrate, Some(115200));
+        assert_eq!(config.parity, Some(Parity::None));
+        assert_eq!(config.data_bits, Some(DataBits::Eight));
+        assert_eq!(config.stop_bits, None);
+
+        let config: Config = "9600e7r".parse().unwrap();
+        assert_eq!(config.baudrate, Some(9600));
+        assert_eq!(config.parity, Some(Parity::Even));
+        assert_eq!(config.data_bits, Some(DataBits::Seven));
+
+        let config: Config = "".parse().unwrap();
+        assert_eq!(config.baudrate, None);
+
+        assert_eq!(
+            "0".parse::<Config>().unwrap_err(),
+            ConfigError::InvalidBaudrate
+        );
+        assert_eq!(
+            "115200x".parse::<Config>().unwrap_err(),
+            ConfigError::UnsupportedParity
+        );
+        assert_eq!(
+            "115200n9".parse::<Config>().unwrap_err(),
+            ConfigError::UnsupportedDataBits
+        );
+    }
+}
@@ -0,0 +1,21 @@
+use alloc::string::String;
+
+/// Split a command line on whitespace, keeping double-quoted spans together.
+pub(crate) fn split_cmdline(cmdline: &str) -> impl Iterator<Item = String> + '_ {
+    let mut chars = cmdline.chars().peekable();
+    core::iter::from_fn(move || {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        chars.peek()?;
+
+        let mut token = String::new();
+        let mut quoted = false;
+        while let Some(c) = chars.next_if(|c| quoted || !c.is_whitespace()) {
+            if c == '"' {
+                quoted = !quoted;
+            } else {
+                token.push(c);
+            }
+        }
+        Some(token)
+    })
+}
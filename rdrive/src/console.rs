@@ -0,0 +1,94 @@
+//! Early console bring-up from `/chosen/stdout-path`.
+
+use alloc::{string::String, vec::Vec};
+
+use rdif_serial::{BSender, BSerial, Config, ConfigError};
+
+use crate::{
+    GetDeviceError, ProbeError,
+    policy::ProbeTarget,
+    probe::{OnProbeError, fdt::ChosenDevice},
+    register::ProbeKind,
+};
+
+#[derive(thiserror::Error, Debug)]
+pub enum EarlyConsoleError {
+    #[error("no `stdout-path` in /chosen")]
+    NoStdout,
+    #[error("no registered driver matches console `{0}`")]
+    NoDriver(String),
+    #[error("probe console: {0}")]
+    Probe(#[from] ProbeError),
+    #[error("console device: {0}")]
+    Device(#[from] GetDeviceError),
+    #[error("invalid console options `{0}`: {1:?}")]
+    Options(String, ConfigError),
+    #[error("set console config: {0:?}")]
+    Config(ConfigError),
+    #[error("console sender already taken")]
+    SenderTaken,
+}
+
+/// Bring up the console named by `/chosen/stdout-path` and take its sender.
+///
+/// Only the serial driver matching the console node is probed, so this may be
+/// called right after [`init`](crate::init) and driver registration, before
+/// [`probe_pre_kernel`](crate::probe_pre_kernel). The `:options` suffix of
+/// `stdout-path`, e.g. `115200n8`, is applied with [`Config`].
+///
+/// The driver must register its device as [`BSerial`].
+pub fn early_console() -> Result<BSender, EarlyConsoleError> {
+    let console = crate::fdt_stdout().ok_or(EarlyConsoleError::NoStdout)?;
+
+    if crate::get::<BSerial>(console.device_id).is_err() {
+        probe_console(&console)?;
+    }
+
+    let dev = crate::get::<BSerial>(console.device_id)?;
+    let mut serial = dev.lock()?;
+    if let Some(options) = &console.options {
+        let config: Config = options
+            .parse()
+            .map_err(|e| EarlyConsoleError::Options(options.clone(), e))?;
+        serial
+            .set_config(&config)
+            .map_err(EarlyConsoleError::Config)?;
+    }
+    serial.take_tx().ok_or(EarlyConsoleError::SenderTaken)
+}
+
+/// Probe the first driver whose FDT compatibles match the console node.
+///
+/// An async probe is run to completion through [`Osal::run_tasks`](crate::Osal::run_tasks).
+fn probe_console(console: &ChosenDevice) -> Result<(), EarlyConsoleError> {
+    let compatibles = crate::probe::fdt::compatibles_of(&console.path).unwrap_or_default();
+    let target = ProbeTarget::Fdt {
+        path: &console.path,
+        compatibles: &compatibles,
+    };
+
+    let candidates = crate::edit(|manager| manager.unregistered())?
+        .into_iter()
+        .filter(|register| {
+            register.probe_kinds.iter().any(|kind| match kind {
+                ProbeKind::Fdt { compatibles: c, .. }
+                | ProbeKind::FdtAsync { compatibles: c, .. } => {
+                    c.iter().any(|c| compatibles.contains(c))
+                }
+                _ => false,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    for register in candidates {
+        if !crate::is_bind_allowed(register.name, &target) {
+            continue;
+        }
+        match crate::probe::fdt::bind(&register, &console.path) {
+            Ok(()) => return Ok(()),
+            Err(ProbeError::OnProbe(OnProbeError::NotMatch)) => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Err(EarlyConsoleError::NoDriver(console.path.clone()))
+}
@@ -1,7 +1,7 @@
 use pcie::PcieController;
 pub use rdif_base::DriverGeneric;
 
-use crate::Descriptor;
+use crate::{Descriptor, param::Params};
 
 pub struct Empty;
 
@@ -17,11 +17,17 @@ impl DriverGeneric for Empty {
 
 pub struct PlatformDevice {
     pub descriptor: Descriptor,
+    params: Params,
 }
 
 impl PlatformDevice {
-    pub(crate) fn new(descriptor: Descriptor) -> Self {
-        Self { descriptor }
+    pub(crate) fn new(descriptor: Descriptor, params: Params) -> Self {
+        Self { descriptor, params }
+    }
+
+    /// Parameters of the probing driver, with kernel overrides applied.
+    pub fn params(&self) -> &Params {
+        &self.params
     }
 
     /// Register a device to the driver manager.
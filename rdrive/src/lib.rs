@@ -6,12 +6,15 @@ extern crate alloc;
 #[macro_use]
 extern crate log;
 
+use alloc::string::String;
 use core::ptr::NonNull;
 
 pub use fdt_parser::Phandle;
 use register::{DriverRegister, ProbeLevel};
 use spin::{Mutex, Once};
 
+mod cmdline;
+mod console;
 mod descriptor;
 pub mod driver;
 pub mod error;
@@ -20,9 +23,12 @@ mod lock;
 mod manager;
 mod osal;
 
+pub mod param;
+pub mod policy;
 pub mod probe;
 pub mod register;
 
+pub use console::{EarlyConsoleError, early_console};
 pub use descriptor::*;
 pub use driver::PlatformDevice;
 pub use lock::*;
@@ -32,7 +38,15 @@ pub use probe::ProbeError;
 pub use rdif_base::{DriverGeneric, KError, irq::IrqId};
 pub use rdrive_macros::*;
 
-use crate::{error::DriverError, probe::OnProbeError};
+use crate::{
+    error::DriverError,
+    param::{DriverParam, ParamRegistry, Params},
+    policy::{BindPolicy, ProbeTarget},
+    probe::{
+        BindTarget, OnProbeError,
+        fdt::{ChosenDevice, OverlayError},
+    },
+};
 
 static CONTAINER: Once<Mutex<Manager>> = Once::new();
 
@@ -59,6 +73,12 @@ pub fn init(platform: Platform) -> Result<(), DriverError> {
     Ok(())
 }
 
+/// An empty manager for unit tests which run probes.
+#[cfg(test)]
+pub(crate) fn init_test_manager() {
+    CONTAINER.call_once(|| Mutex::new(Manager::new().unwrap()));
+}
+
 pub(crate) fn edit<F, T>(f: F) -> T
 where
     F: FnOnce(&mut Manager) -> T,
@@ -83,6 +103,43 @@ pub fn register_append(registers: &[DriverRegister]) {
     edit(|manager| manager.registers.append(registers))
 }
 
+/// Replace the policy consulted before each probe.
+///
+/// See [`policy`] for the rule syntax accepted by [`BindPolicy::parse`].
+pub fn set_bind_policy(policy: BindPolicy) {
+    edit(|manager| manager.policy = policy);
+}
+
+/// Replace the driver parameters handed to probe functions.
+///
+/// See [`param`] for the `driver.param=value` syntax accepted by [`ParamRegistry::parse`].
+pub fn set_driver_params(params: ParamRegistry) {
+    edit(|manager| manager.params = params);
+}
+
+pub(crate) fn driver_params(driver: &str, decls: &'static [DriverParam]) -> Params {
+    read(|manager| manager.params.params_for(driver, decls))
+}
+
+pub(crate) fn is_bind_allowed(driver: &str, target: &ProbeTarget<'_>) -> bool {
+    let allowed = read(|manager| manager.policy.is_allowed(driver, target));
+    if !allowed {
+        info!("Bind [{driver}] -> {target:?} denied by policy");
+    }
+    allowed
+}
+
+/// FDT paths the bind policy forces `driver` onto.
+pub(crate) fn forced_paths(driver: &str) -> Vec<String> {
+    read(|manager| {
+        manager
+            .policy
+            .forced_paths(driver)
+            .map(Into::into)
+            .collect()
+    })
+}
+
 pub fn probe_pre_kernel() -> Result<(), ProbeError> {
     let unregistered = edit(|manager| manager.unregistered())?;
 
@@ -99,25 +156,41 @@ fn probe_system<'a>(
     registers: impl Iterator<Item = &'a DriverRegister>,
     stop_if_fail: bool,
 ) -> Result<(), ProbeError> {
-    for one in registers {
-        // let system = edit(|manager| manager.enum_system.clone());
+    let mut registers = registers.peekable();
+    while let Some(first) = registers.next() {
+        // drivers of one priority do not depend on each other, so their async
+        // probes run together once the sync ones are done
+        let mut pending = Vec::new();
+        let mut wave = Some(first);
+        while let Some(one) = wave {
+            let (res, futures) = probe::fdt::probe_register_split(one)?;
+            check_probe_results(res, stop_if_fail)?;
+            pending.extend(futures);
+
+            wave = registers.next_if(|next| next.priority == first.priority);
+        }
 
-        // let res = system.probe_register(one)?;
+        check_probe_results(probe::fdt::run_async(pending), stop_if_fail)?;
+    }
 
-        let res = probe::fdt::probe_register(one)?;
+    Ok(())
+}
 
-        for r in res {
-            match r {
-                Ok(_) => {}
-                Err(OnProbeError::NotMatch) => {
-                    // Not a match, skip to the next probe
-                }
-                Err(e) => {
-                    if stop_if_fail {
-                        return Err(e.into());
-                    } else {
-                        warn!("Probe failed for [{}]: {}", one.name, e);
-                    }
+fn check_probe_results(
+    res: Vec<(&'static str, Result<(), OnProbeError>)>,
+    stop_if_fail: bool,
+) -> Result<(), ProbeError> {
+    for (name, r) in res {
+        match r {
+            Ok(_) => {}
+            Err(OnProbeError::NotMatch) => {
+                // Not a match, skip to the next probe
+            }
+            Err(e) => {
+                if stop_if_fail {
+                    return Err(e.into());
+                } else {
+                    warn!("Probe failed for [{}]: {}", name, e);
                 }
             }
         }
@@ -136,6 +209,34 @@ pub fn probe_all(stop_if_fail: bool) -> Result<(), ProbeError> {
     Ok(())
 }
 
+/// Re-walk every PCIe controller after a hotplug event.
+///
+/// Functions whose config space now reads `0xffff` are removed: the device
+/// their driver registered is dropped and their BARs are returned to the
+/// controller's allocator. Functions that appeared since the last walk get
+/// BARs and are probed against all registered drivers. Functions already
+/// present are left untouched.
+pub fn pci_rescan(stop_if_fail: bool) -> Result<(), ProbeError> {
+    let registers = edit(|manager| manager.unregistered())?;
+    probe::pci::rescan(&registers, stop_if_fail)
+}
+
+/// Bind the driver registered as `driver_name` to `target`, like Linux's `bind`.
+///
+/// The register's probe function runs against the given FDT node or PCI
+/// function even if it is not listed in the driver's compatibles. The bind
+/// policy is not consulted. A target a driver is already bound to is refused
+/// with [`ProbeError::TargetBound`]; once bound, later probes skip it.
+pub fn bind<'a>(driver_name: &str, target: impl Into<BindTarget<'a>>) -> Result<(), ProbeError> {
+    let register = read(|manager| manager.registers.find(driver_name).cloned())
+        .ok_or_else(|| ProbeError::DriverNotFound(driver_name.into()))?;
+
+    match target.into() {
+        BindTarget::FdtPath(path) => probe::fdt::bind(&register, path),
+        BindTarget::Pci(address) => probe::pci::bind(&register, address),
+    }
+}
+
 pub fn get_list<T: DriverGeneric>() -> Vec<Device<T>> {
     read(|manager| manager.dev_container.devices())
 }
@@ -152,6 +253,42 @@ pub fn fdt_phandle_to_device_id(phandle: Phandle) -> Option<DeviceId> {
     probe::fdt::system().phandle_to_device_id(phandle)
 }
 
+/// Id of the FDT node at `path`, e.g. `/pl011@9000000`, `serial0` or
+/// `serial0:115200n8`. Paths not starting with `/` begin with an alias.
+pub fn fdt_path_to_device_id(path: &str) -> Option<DeviceId> {
+    probe::fdt::system().path_to_device_id(path)
+}
+
+/// The device named by the path-valued `/chosen` property `property`.
+pub fn fdt_chosen_device(property: &str) -> Option<ChosenDevice> {
+    probe::fdt::system().chosen(property)
+}
+
+/// The console from `/chosen/stdout-path`, with its `:options` suffix.
+pub fn fdt_stdout() -> Option<ChosenDevice> {
+    fdt_chosen_device("stdout-path").or_else(|| fdt_chosen_device("linux,stdout-path"))
+}
+
+/// Get the device probed from the FDT node at `path`, see [`fdt_path_to_device_id`].
+pub fn get_by_fdt_path<T: DriverGeneric>(path: &str) -> Result<Device<T>, GetDeviceError> {
+    let id = fdt_path_to_device_id(path).ok_or(GetDeviceError::NotFound)?;
+    get(id)
+}
+
+/// Merge a device tree overlay (`.dtbo`) into the tree being probed.
+///
+/// Nodes added or changed by the overlay are probed by the next
+/// [`probe_all`], even by drivers that already bound to another node.
+pub fn fdt_apply_overlay(dtbo: &[u8]) -> Result<(), OverlayError> {
+    probe::fdt::apply_overlay(dtbo)
+}
+
+/// Mark the FDT node at `path` as reserved, e.g. for secure firmware or a
+/// hypervisor. Neither the node nor its children will be probed.
+pub fn fdt_reserve_node(path: &str) {
+    probe::fdt::system().reserve(path);
+}
+
 /// Macro for generating a driver module.
 ///
 /// This macro automatically generates a driver registration module that creates a static
@@ -161,9 +298,11 @@ pub fn fdt_phandle_to_device_id(phandle: Phandle) -> Option<DeviceId> {
 /// at runtime.
 ///
 /// # Parameters
-/// - `$i:ident`: Field identifier (e.g., `name`, `level`, `priority`, `probe_kinds`)
+/// - `$i:ident`: Field identifier (e.g., `name`, `level`, `priority`, `probe_kinds`, `params`)
 /// - `$t:expr`: Expression for the corresponding field value
 ///
+/// Fields that are not given take their value from [`DriverRegister::EMPTY`](register::DriverRegister::EMPTY).
+///
 /// # Generated Code
 ///
 /// The macro generates a module containing a static `DriverRegister` that:
@@ -175,7 +314,7 @@ pub fn fdt_phandle_to_device_id(phandle: Phandle) -> Option<DeviceId> {
 ///
 /// ```rust
 /// #![feature(used_with_arg)]
-/// 
+///
 /// use rdrive::{
 ///     module_driver,
 ///     driver::*,
@@ -245,8 +384,10 @@ macro_rules! module_driver {
                 #[unsafe(link_section = ".driver.register")]
                 #[unsafe(no_mangle)]
                 #[used(linker)]
+                #[allow(clippy::needless_update)]
                 pub static DRIVER: DriverRegister = DriverRegister {
-                    $($i : $t),+
+                    $($i : $t,)+
+                    ..DriverRegister::EMPTY
                 };
             }
         }
@@ -5,6 +5,8 @@ use rdif_base::DriverGeneric;
 use crate::{
     Descriptor, Device, DeviceId, DeviceOwner, GetDeviceError,
     error::DriverError,
+    param::ParamRegistry,
+    policy::BindPolicy,
     probe::ProbeError,
     register::{DriverRegister, RegisterContainer},
 };
@@ -12,6 +14,8 @@ use crate::{
 pub struct Manager {
     pub registers: RegisterContainer,
     pub(crate) dev_container: DeviceContainer,
+    pub(crate) policy: BindPolicy,
+    pub(crate) params: ParamRegistry,
     // pub(crate) enum_system: EnumSystem,
 }
 
@@ -21,6 +25,8 @@ impl Manager {
             // enum_system: EnumSystem::new(platform)?,
             registers: RegisterContainer::default(),
             dev_container: DeviceContainer::default(),
+            policy: BindPolicy::new(),
+            params: ParamRegistry::new(),
         })
     }
 
@@ -42,6 +48,12 @@ impl DeviceContainer {
             .insert(descriptor.device_id, DeviceOwner::new(descriptor, device));
     }
 
+    /// Unregister the device, locking an outstanding [`Device`] handle then
+    /// fails with [`GetDeviceError::DeviceReleased`].
+    pub fn remove(&mut self, id: DeviceId) -> Option<DeviceOwner> {
+        self.devices.remove(&id)
+    }
+
     pub fn get_typed<T: DriverGeneric>(&self, id: DeviceId) -> Result<Device<T>, GetDeviceError> {
         let dev = self.devices.get(&id).ok_or(GetDeviceError::NotFound)?;
 
@@ -1,6 +1,11 @@
-use rdif_base::custom_type;
+use alloc::vec::Vec;
+use core::{ptr::NonNull, time::Duration};
+
+use rdif_base::{KError, custom_type};
 use spin::RwLock;
 
+use crate::probe::ProbeTask;
+
 custom_type!(#[doc="Process ID"],Pid, usize, "{:?}");
 
 impl Pid {
@@ -19,6 +24,34 @@ impl Pid {
 pub trait Osal: Sync + Send + 'static {
     /// Get the current process ID.
     fn get_pid(&self) -> Pid;
+
+    /// Run async probe tasks to completion, e.g. on an executor or spread
+    /// over CPUs. Must return only once every task has completed.
+    ///
+    /// The default polls them one after another on the calling CPU.
+    fn run_tasks(&self, tasks: Vec<ProbeTask>) {
+        ProbeTask::block_on_all(tasks)
+    }
+
+    /// Map `size` bytes of device memory at physical address `phys`, for
+    /// drivers built into rdrive. The mapping is never released.
+    ///
+    /// The default maps nothing and returns `None`: the built-in ECAM and
+    /// GICv2m drivers then fail to probe and MSI-X cannot be enabled.
+    fn ioremap(&self, phys: u64, size: usize) -> Option<NonNull<u8>> {
+        let _ = (phys, size);
+        None
+    }
+
+    /// Wait at least `duration`, for the delays hardware needs, e.g. after a
+    /// PCI function reset. Kernels sleep or spin on their timer.
+    ///
+    /// The default cannot wait and fails, PCI resets, power state changes
+    /// and SR-IOV enablement then fail too without touching the device.
+    fn sleep(&self, duration: Duration) -> Result<(), KError> {
+        let _ = duration;
+        Err(KError::Unknown("sleep not supported"))
+    }
 }
 
 struct OsalImplEmplty;
@@ -39,3 +72,19 @@ pub fn set_osal(osal: &'static dyn Osal) {
 pub(crate) fn get_pid() -> Pid {
     OSAL.read().get_pid()
 }
+
+pub(crate) fn run_tasks(tasks: Vec<ProbeTask>) {
+    let osal = *OSAL.read();
+    osal.run_tasks(tasks)
+}
+
+pub(crate) fn ioremap(phys: u64, size: usize) -> Option<NonNull<u8>> {
+    let osal = *OSAL.read();
+    osal.ioremap(phys, size)
+}
+
+/// Whether `duration` was waited.
+pub(crate) fn sleep(duration: Duration) -> bool {
+    let osal = *OSAL.read();
+    osal.sleep(duration).is_ok()
+}
@@ -0,0 +1,208 @@
+//! Driver-specific parameters.
+//!
+//! Drivers declare their tunables with defaults in
+//! [`DriverRegister::params`](crate::register::DriverRegister::params), and
+//! the kernel overrides them with `driver.param=value` tokens, usually taken
+//! from the command line:
+//!
+//! ```text
+//! virtio-blk.queue_depth=64 "PL011 UART.baudrate=9600" nvme.debug=true
+//! ```
+//!
+//! The values are handed to `on_probe` through [`PlatformDevice::params`](crate::PlatformDevice::params).
+
+use alloc::{
+    collections::btree_map::BTreeMap,
+    string::{String, ToString},
+};
+
+use crate::cmdline::split_cmdline;
+
+/// A parameter declared by a driver, with its default value.
+#[derive(Debug, Clone, Copy)]
+pub struct DriverParam {
+    pub name: &'static str,
+    pub default: &'static str,
+}
+
+impl DriverParam {
+    pub const fn new(name: &'static str, default: &'static str) -> Self {
+        Self { name, default }
+    }
+}
+
+/// Parameter values set by the kernel, keyed by driver name.
+#[derive(Debug, Clone, Default)]
+pub struct ParamRegistry {
+    drivers: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+impl ParamRegistry {
+    pub const fn new() -> Self {
+        Self {
+            drivers: BTreeMap::new(),
+        }
+    }
+
+    /// Collect every `driver.param=value` token of a command line.
+    ///
+    /// Tokens without a `.` in the key, such as `console=ttyAMA0`, and the
+    /// `rdrive.` options read by [`BindPolicy`](crate::policy::BindPolicy) are ignored.
+    pub fn parse(cmdline: &str) -> Self {
+        let mut registry = Self::new();
+        for token in split_cmdline(cmdline) {
+            if token.starts_with("rdrive.") {
+                continue;
+            }
+            let Some((key, value)) = token.split_once('=') else {
+                continue;
+            };
+            let Some((driver, param)) = key.rsplit_once('.') else {
+                continue;
+            };
+            if driver.is_empty() || param.is_empty() {
+                continue;
+            }
+            registry.set(driver, param, value);
+        }
+        registry
+    }
+
+    pub fn set(&mut self, driver: &str, param: &str, value: &str) {
+        self.drivers
+            .entry(driver.to_string())
+            .or_default()
+            .insert(param.to_string(), value.to_string());
+    }
+
+    pub fn get(&self, driver: &str, param: &str) -> Option<&str> {
+        self.drivers.get(driver)?.get(param).map(String::as_str)
+    }
+
+    pub(crate) fn params_for(&self, driver: &str, decls: &'static [DriverParam]) -> Params {
+        Params {
+            driver: driver.to_string(),
+            decls,
+            values: self.drivers.get(driver).cloned().unwrap_or_default(),
+        }
+    }
+}
+
+/// The parameters of one driver, as seen from its probe function.
+#[derive(Debug, Clone, Default)]
+pub struct Params {
+    driver: String,
+    decls: &'static [DriverParam],
+    values: BTreeMap<String, String>,
+}
+
+impl Params {
+    /// Raw value of `name`: the value set by the kernel, or the declared default.
+    pub fn get_str(&self, name: &str) -> Option<&str> {
+        if let Some(value) = self.values.get(name) {
+            return Some(value);
+        }
+        self.default(name)
+    }
+
+    pub fn get_bool(&self, name: &str) -> Option<bool> {
+        self.get_parsed(name, |s| match s {
+            "1" | "y" | "Y" | "yes" | "on" | "true" => Some(true),
+            "0" | "n" | "N" | "no" | "off" | "false" => Some(false),
+            _ => None,
+        })
+    }
+
+    /// Integer value of `name`, accepting decimal and `0x` prefixed hex.
+    pub fn get_u64(&self, name: &str) -> Option<u64> {
+        self.get_parsed(name, |s| match s.strip_prefix("0x") {
+            Some(hex) => u64::from_str_radix(hex, 16).ok(),
+            None => s.parse().ok(),
+        })
+    }
+
+    pub fn get_u32(&self, name: &str) -> Option<u32> {
+        self.get_u64(name).and_then(|v| v.try_into().ok())
+    }
+
+    pub fn get_usize(&self, name: &str) -> Option<usize> {
+        self.get_u64(name).and_then(|v| v.try_into().ok())
+    }
+
+    pub fn get_i64(&self, name: &str) -> Option<i64> {
+        self.get_parsed(name, |s| s.parse().ok())
+    }
+
+    fn default(&self, name: &str) -> Option<&'static str> {
+        self.decls
+            .iter()
+            .find(|p| p.name == name)
+            .map(|p| p.default)
+    }
+
+    /// Parse the kernel value, falling back to the default if it is malformed.
+    fn get_parsed<T>(&self, name: &str, parse: impl Fn(&str) -> Option<T>) -> Option<T> {
+        if let Some(value) = self.values.get(name) {
+            match parse(value) {
+                Some(v) => return Some(v),
+                None => warn!(
+                    "[{}] invalid value `{value}` for param `{name}`, use default",
+                    self.driver
+                ),
+            }
+        }
+        self.default(name).and_then(parse)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DECLS: &[DriverParam] = &[
+        DriverParam::new("queue_depth", "32"),
+        DriverParam::new("debug", "false"),
+        DriverParam::new("mode", "fast"),
+    ];
+
+    #[test]
+    fn test_defaults() {
+        let params = ParamRegistry::new().params_for("virtio-blk", DECLS);
+        assert_eq!(params.get_u32("queue_depth"), Some(32));
+        assert_eq!(params.get_bool("debug"), Some(false));
+        assert_eq!(params.get_str("mode"), Some("fast"));
+        assert_eq!(params.get_str("unknown"), None);
+    }
+
+    #[test]
+    fn test_parse_overrides() {
+        let registry = ParamRegistry::parse(
+            r#"console=ttyAMA0 virtio-blk.queue_depth=0x40 virtio-blk.debug=on "PL011 UART.baudrate=9600""#,
+        );
+        let params = registry.params_for("virtio-blk", DECLS);
+        assert_eq!(params.get_u64("queue_depth"), Some(0x40));
+        assert_eq!(params.get_bool("debug"), Some(true));
+        assert_eq!(params.get_str("mode"), Some("fast"));
+
+        assert_eq!(registry.get("PL011 UART", "baudrate"), Some("9600"));
+        assert_eq!(registry.get("console", ""), None);
+    }
+
+    #[test]
+    fn test_invalid_falls_back() {
+        let registry = ParamRegistry::parse("virtio-blk.queue_depth=deep");
+        let params = registry.params_for("virtio-blk", DECLS);
+        assert_eq!(params.get_u32("queue_depth"), Some(32));
+        assert_eq!(params.get_str("queue_depth"), Some("deep"));
+    }
+
+    #[test]
+    fn test_skips_rdrive_options() {
+        let registry = ParamRegistry::parse(
+            r#"rdrive.deny=driver:GICv3 "rdrive.allow=driver:PL011 UART" virtio-blk.debug=1"#,
+        );
+        assert_eq!(registry.get("rdrive", "deny"), None);
+        assert_eq!(registry.get("rdrive", "allow"), None);
+        assert_eq!(registry.get("virtio-blk", "debug"), Some("1"));
+    }
+}
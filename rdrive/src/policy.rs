@@ -0,0 +1,398 @@
+//! Driver binding policy.
+//!
+//! A [`BindPolicy`] is consulted before every probe and decides whether a
+//! [`DriverRegister`](crate::register::DriverRegister) may bind to a given
+//! device. It is usually built from the kernel command line:
+//!
+//! ```text
+//! rdrive.deny=driver:GICv3
+//! rdrive.deny=compatible:arm,pl031
+//! rdrive.deny=pci-id:8086:10d3
+//! rdrive.allow="driver:PL011 UART+path:/pl011@9000000"
+//! ```
+//!
+//! Each `rdrive.deny=`/`rdrive.allow=` token holds one rule made of
+//! `kind:value` selectors joined with `+`; a rule matches when all of its
+//! selectors match. Supported kinds are `driver`, `path`, `compatible`,
+//! `pci` (`[segment:]bus:device.function`) and `pci-id` (`vendor:device`).
+//! Values containing spaces may be wrapped in double quotes.
+//!
+//! Deny rules always win. Allow rules restrict which drivers may bind to the
+//! targets they select: once any allow rule selects a target, only drivers
+//! named by one of the selecting rules may bind to it. An allow rule without
+//! target selectors selects every target.
+//!
+//! An allow rule made of a `driver` and a single `path` selector forces the
+//! driver onto that FDT node: the driver is probed against it whatever its
+//! compatibles, as [`bind`](crate::bind) does, when its turn comes in
+//! [`probe_all`](crate::probe_all). PCI drivers are tried against every
+//! function, so `driver:...+pci:...` already makes the named driver the only
+//! one probed against the function.
+
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
+
+use rdif_pcie::PciAddress;
+
+use crate::cmdline::split_cmdline;
+
+/// The device a driver is about to be probed against.
+#[derive(Debug, Clone, Copy)]
+pub enum ProbeTarget<'a> {
+    Fdt {
+        path: &'a str,
+        compatibles: &'a [&'a str],
+    },
+    Pci {
+        address: PciAddress,
+        vendor: u16,
+        device: u16,
+    },
+}
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum PolicyError {
+    #[error("empty rule")]
+    EmptyRule,
+    #[error("unknown selector kind `{0}`")]
+    UnknownKind(String),
+    #[error("invalid `{kind}` selector value `{value}`")]
+    InvalidValue { kind: &'static str, value: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Selector {
+    Driver(String),
+    Path(String),
+    Compatible(String),
+    PciAddress {
+        segment: Option<u16>,
+        bus: u8,
+        device: u8,
+        function: u8,
+    },
+    PciId {
+        vendor: u16,
+        device: u16,
+    },
+}
+
+impl Selector {
+    fn parse(s: &str) -> Result<Self, PolicyError> {
+        let (kind, value) = s
+            .split_once(':')
+            .ok_or_else(|| PolicyError::UnknownKind(s.to_string()))?;
+        let value = value.trim_matches('"');
+
+        match kind {
+            "driver" => Ok(Self::Driver(value.to_string())),
+            "path" => Ok(Self::Path(value.to_string())),
+            "compatible" => Ok(Self::Compatible(value.to_string())),
+            "pci" => parse_pci_address(value).ok_or_else(|| PolicyError::InvalidValue {
+                kind: "pci",
+                value: value.to_string(),
+            }),
+            "pci-id" => parse_pci_id(value).ok_or_else(|| PolicyError::InvalidValue {
+                kind: "pci-id",
+                value: value.to_string(),
+            }),
+            _ => Err(PolicyError::UnknownKind(kind.to_string())),
+        }
+    }
+
+    fn is_driver(&self) -> bool {
+        matches!(self, Self::Driver(_))
+    }
+
+    /// Match a target selector against `target`.
+    fn matches_target(&self, target: &ProbeTarget<'_>) -> bool {
+        match (self, target) {
+            (Self::Path(want), ProbeTarget::Fdt { path, .. }) => want == path,
+            (Self::Compatible(want), ProbeTarget::Fdt { compatibles, .. }) => {
+                compatibles.iter().any(|c| c == want)
+            }
+            (
+                Self::PciAddress {
+                    segment,
+                    bus,
+                    device,
+                    function,
+                },
+                ProbeTarget::Pci { address, .. },
+            ) => {
+                segment.is_none_or(|s| s == address.segment())
+                    && *bus == address.bus()
+                    && *device == address.device()
+                    && *function == address.function()
+            }
+            (
+                Self::PciId { vendor, device },
+                ProbeTarget::Pci {
+                    vendor: v,
+                    device: d,
+                    ..
+                },
+            ) => vendor == v && device == d,
+            _ => false,
+        }
+    }
+}
+
+fn parse_pci_address(s: &str) -> Option<Selector> {
+    let (rest, function) = s.rsplit_once('.')?;
+    let mut parts = rest.rsplitn(3, ':');
+    let device = u8::from_str_radix(parts.next()?, 16).ok()?;
+    let bus = u8::from_str_radix(parts.next()?, 16).ok()?;
+    let segment = match parts.next() {
+        Some(seg) => Some(u16::from_str_radix(seg, 16).ok()?),
+        None => None,
+    };
+    let function = u8::from_str_radix(function, 16).ok()?;
+    if device > 31 || function > 7 {
+        return None;
+    }
+    Some(Selector::PciAddress {
+        segment,
+        bus,
+        device,
+        function,
+    })
+}
+
+fn parse_pci_id(s: &str) -> Option<Selector> {
+    let (vendor, device) = s.split_once(':')?;
+    Some(Selector::PciId {
+        vendor: u16::from_str_radix(vendor, 16).ok()?,
+        device: u16::from_str_radix(device, 16).ok()?,
+    })
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Rule {
+    selectors: Vec<Selector>,
+}
+
+impl Rule {
+    fn parse(s: &str) -> Result<Self, PolicyError> {
+        let selectors = s
+            .split('+')
+            .map(str::trim)
+            .filter(|one| !one.is_empty())
+            .map(Selector::parse)
+            .collect::<Result<Vec<_>, _>>()?;
+        if selectors.is_empty() {
+            return Err(PolicyError::EmptyRule);
+        }
+        Ok(Self { selectors })
+    }
+
+    fn matches_driver(&self, driver: &str) -> bool {
+        self.selectors
+            .iter()
+            .all(|s| !matches!(s, Selector::Driver(want) if want != driver))
+    }
+
+    fn matches_target(&self, target: &ProbeTarget<'_>) -> bool {
+        self.selectors
+            .iter()
+            .filter(|s| !s.is_driver())
+            .all(|s| s.matches_target(target))
+    }
+
+    fn matches(&self, driver: &str, target: &ProbeTarget<'_>) -> bool {
+        self.matches_driver(driver) && self.matches_target(target)
+    }
+
+    /// The path of a rule naming `driver` and one `path`, nothing else.
+    fn forced_path(&self, driver: &str) -> Option<&str> {
+        let mut named = false;
+        let mut path = None;
+        for selector in &self.selectors {
+            match selector {
+                Selector::Driver(want) if want == driver => named = true,
+                Selector::Path(want) if path.is_none() => path = Some(want.as_str()),
+                _ => return None,
+            }
+        }
+        path.filter(|_| named)
+    }
+}
+
+/// Deny/allow rules deciding which driver may bind to which device.
+#[derive(Debug, Clone, Default)]
+pub struct BindPolicy {
+    deny: Vec<Rule>,
+    allow: Vec<Rule>,
+}
+
+impl BindPolicy {
+    pub const fn new() -> Self {
+        Self {
+            deny: Vec::new(),
+            allow: Vec::new(),
+        }
+    }
+
+    /// Build a policy from a kernel command line.
+    ///
+    /// Tokens other than `rdrive.deny=` and `rdrive.allow=` are ignored.
+    pub fn parse(cmdline: &str) -> Result<Self, PolicyError> {
+        let mut policy = Self::new();
+        for token in split_cmdline(cmdline) {
+            if let Some(rule) = token.strip_prefix("rdrive.deny=") {
+                policy.deny(rule)?;
+            } else if let Some(rule) = token.strip_prefix("rdrive.allow=") {
+                policy.allow(rule)?;
+            }
+        }
+        Ok(policy)
+    }
+
+    /// Add a deny rule, e.g. `driver:GICv3` or `compatible:arm,pl031+path:/pl031@9010000`.
+    pub fn deny(&mut self, rule: &str) -> Result<(), PolicyError> {
+        self.deny.push(Rule::parse(rule)?);
+        Ok(())
+    }
+
+    /// Add an allow rule, e.g. `driver:PL011 UART+path:/pl011@9000000`.
+    ///
+    /// The rule restricts which drivers may bind to the targets it selects.
+    /// Naming a driver and a path only, it also forces the driver onto the
+    /// node, see [`forced_paths`](Self::forced_paths).
+    pub fn allow(&mut self, rule: &str) -> Result<(), PolicyError> {
+        self.allow.push(Rule::parse(rule)?);
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deny.is_empty() && self.allow.is_empty()
+    }
+
+    /// FDT paths allow rules force `driver` onto, from rules naming the
+    /// driver and a `path` and nothing else.
+    pub fn forced_paths<'a>(&'a self, driver: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.allow
+            .iter()
+            .filter_map(move |rule| rule.forced_path(driver))
+    }
+
+    /// Whether the driver named `driver` may be probed against `target`.
+    pub fn is_allowed(&self, driver: &str, target: &ProbeTarget<'_>) -> bool {
+        if self.deny.iter().any(|rule| rule.matches(driver, target)) {
+            return false;
+        }
+
+        let mut selected = self
+            .allow
+            .iter()
+            .filter(|rule| rule.matches_target(target))
+            .peekable();
+
+        if selected.peek().is_none() {
+            return true;
+        }
+
+        selected.any(|rule| rule.matches_driver(driver))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FDT_UART: ProbeTarget<'static> = ProbeTarget::Fdt {
+        path: "/pl011@9000000",
+        compatibles: &["arm,pl011", "arm,primecell"],
+    };
+
+    const FDT_RTC: ProbeTarget<'static> = ProbeTarget::Fdt {
+        path: "/pl031@9010000",
+        compatibles: &["arm,pl031", "arm,primecell"],
+    };
+
+    fn pci_nic() -> ProbeTarget<'static> {
+        ProbeTarget::Pci {
+            address: PciAddress::new(0, 0, 2, 0),
+            vendor: 0x8086,
+            device: 0x10d3,
+        }
+    }
+
+    #[test]
+    fn test_empty_allows_all() {
+        let policy = BindPolicy::parse("console=ttyAMA0 root=/dev/vda").unwrap();
+        assert!(policy.is_empty());
+        assert!(policy.is_allowed("PL011", &FDT_UART));
+        assert!(policy.is_allowed("e1000e", &pci_nic()));
+    }
+
+    #[test]
+    fn test_deny() {
+        let policy = BindPolicy::parse(
+            "rdrive.deny=driver:PL031 rdrive.deny=compatible:arm,pl011 rdrive.deny=pci-id:8086:10d3",
+        )
+        .unwrap();
+        assert!(!policy.is_allowed("PL031", &FDT_RTC));
+        assert!(!policy.is_allowed("Other", &FDT_UART));
+        assert!(!policy.is_allowed("e1000e", &pci_nic()));
+    }
+
+    #[test]
+    fn test_deny_driver_on_target() {
+        let policy = BindPolicy::parse("rdrive.deny=driver:Generic+pci:00:02.0").unwrap();
+        assert!(!policy.is_allowed("Generic", &pci_nic()));
+        assert!(policy.is_allowed("e1000e", &pci_nic()));
+        assert!(policy.is_allowed("Generic", &FDT_UART));
+    }
+
+    #[test]
+    fn test_allow_restricts_target() {
+        let policy =
+            BindPolicy::parse(r#"rdrive.allow="driver:PL011 UART+path:/pl011@9000000""#).unwrap();
+        assert!(policy.is_allowed("PL011 UART", &FDT_UART));
+        assert!(!policy.is_allowed("Primecell", &FDT_UART));
+        assert!(policy.is_allowed("Primecell", &FDT_RTC));
+    }
+
+    #[test]
+    fn test_forced_paths() {
+        let policy = BindPolicy::parse(concat!(
+            r#"rdrive.allow="driver:PL011 UART+path:/pl011@9000000" "#,
+            "rdrive.allow=driver:PL011+path:/pl011@9000000+compatible:arm,pl011 ",
+            "rdrive.allow=driver:PL031 ",
+            "rdrive.allow=driver:e1000e+pci:00:02.0",
+        ))
+        .unwrap();
+        assert_eq!(
+            policy.forced_paths("PL011 UART").collect::<Vec<_>>(),
+            ["/pl011@9000000"]
+        );
+        assert_eq!(policy.forced_paths("PL011").count(), 0);
+        assert_eq!(policy.forced_paths("PL031").count(), 0);
+        assert_eq!(policy.forced_paths("e1000e").count(), 0);
+    }
+
+    #[test]
+    fn test_deny_wins() {
+        let mut policy = BindPolicy::new();
+        policy.allow("driver:PL011").unwrap();
+        policy.deny("path:/pl011@9000000").unwrap();
+        assert!(!policy.is_allowed("PL011", &FDT_UART));
+        assert!(!policy.is_allowed("PL031", &FDT_RTC));
+    }
+
+    #[test]
+    fn test_parse_error() {
+        assert_eq!(
+            BindPolicy::parse("rdrive.deny=foo:bar").unwrap_err(),
+            PolicyError::UnknownKind("foo".into())
+        );
+        assert!(BindPolicy::parse("rdrive.deny=pci:00:20.0").is_err());
+        assert_eq!(
+            BindPolicy::parse("rdrive.allow=").unwrap_err(),
+            PolicyError::EmptyRule
+        );
+    }
+}
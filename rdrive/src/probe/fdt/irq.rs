@@ -0,0 +1,456 @@
+//! Interrupt specifier resolution through `interrupt-parent`,
+//! `interrupts-extended` and `interrupt-map`.
+
+use alloc::{format, string::String, vec::Vec};
+
+use fdt_parser::{Fdt, Node, Phandle};
+use rdif_base::irq::IrqConfig;
+use rdif_pcie::PciAddress;
+
+use crate::DeviceId;
+
+/// Nexus nodes followed before giving up, guards against `interrupt-map` loops.
+const MAX_NEXUS_DEPTH: usize = 16;
+
+/// An interrupt of a device, ready to be set up on its controller.
+#[derive(Debug, Clone)]
+pub struct FdtIrq {
+    /// The interrupt controller owning [`config`](Self::config).
+    pub irq_parent: DeviceId,
+    pub config: IrqConfig,
+}
+
+/// An interrupt specifier in the format of the controller that owns it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct IrqSpec {
+    pub controller: Phandle,
+    pub cells: Vec<u32>,
+}
+
+fn read_u32(node: &Node<'_>, name: &str) -> Option<u32> {
+    node.find_property(name)?.u32_list().next()
+}
+
+fn interrupt_cells(node: &Node<'_>) -> Result<usize, String> {
+    read_u32(node, "#interrupt-cells")
+        .map(|v| v as usize)
+        .ok_or_else(|| format!("[{}] has no `#interrupt-cells`", node.name()))
+}
+
+fn address_cells(node: &Node<'_>) -> usize {
+    read_u32(node, "#address-cells").unwrap_or(0) as usize
+}
+
+/// `#address-cells` of the first nexus an interrupt is looked up in: its
+/// own, else the nearest ancestor's, else 2, as Linux's `of_irq_parse_raw`
+/// does for trees that rely on it.
+fn nexus_address_cells(fdt: &Fdt<'_>, nexus: &Node<'_>) -> usize {
+    // nodes are told apart by where their name sits in the blob
+    let mut chain = Vec::new();
+    for node in fdt.all_nodes() {
+        chain.truncate(node.level.saturating_sub(1));
+        let found = core::ptr::eq(node.name().as_ptr(), nexus.name().as_ptr());
+        chain.push(node);
+        if found {
+            break;
+        }
+    }
+    chain
+        .iter()
+        .rev()
+        .find_map(|node| read_u32(node, "#address-cells"))
+        .unwrap_or(2) as usize
+}
+
+fn is_controller(node: &Node<'_>) -> bool {
+    node.find_property("interrupt-controller").is_some()
+}
+
+fn node_by_phandle<'a>(fdt: &Fdt<'a>, phandle: Phandle) -> Result<Node<'a>, String> {
+    fdt.get_node_by_phandle(phandle)
+        .ok_or_else(|| format!("interrupt parent {phandle:?} not found"))
+}
+
+/// Raw specifiers of `node` paired with the interrupt parent they refer to.
+///
+/// `interrupts-extended` takes precedence over `interrupts`.
+fn raw_specs<'a>(fdt: &Fdt<'a>, node: &Node<'a>) -> Result<Vec<(Node<'a>, Vec<u32>)>, String> {
+    let mut out = Vec::new();
+
+    if let Some(prop) = node.find_property("interrupts-extended") {
+        let mut cells = prop.u32_list();
+        while let Some(phandle) = cells.next() {
+            let parent = node_by_phandle(fdt, phandle.into())?;
+            let n = interrupt_cells(&parent)?;
+            let spec = cells.by_ref().take(n).collect::<Vec<_>>();
+            if spec.len() != n {
+                return Err(format!("[{}] truncated `interrupts-extended`", node.name()));
+            }
+            out.push((parent, spec));
+        }
+        return Ok(out);
+    }
+
+    let Some(prop) = node.find_property("interrupts") else {
+        return Ok(out);
+    };
+    let parent = node
+        .interrupt_parent()
+        .ok_or_else(|| format!("[{}] has no interrupt parent", node.name()))?
+        .node;
+    let n = interrupt_cells(&parent)?;
+    if n == 0 {
+        return Ok(out);
+    }
+    let raw = prop.u32_list().collect::<Vec<_>>();
+    for spec in raw.chunks_exact(n) {
+        out.push((parent.clone(), spec.to_vec()));
+    }
+    Ok(out)
+}
+
+/// An interrupt as seen by `parent`: its unit address and specifier.
+pub(crate) struct ParentIrq<'a> {
+    pub parent: Node<'a>,
+    pub unit: Vec<u32>,
+    pub spec: Vec<u32>,
+}
+
+/// Look `unit` + `spec` up in the `interrupt-map` of `nexus`, whose child
+/// unit addresses are `addr_len` cells.
+///
+/// Returns `None` if no entry matches.
+pub(crate) fn map_through<'a>(
+    fdt: &Fdt<'a>,
+    nexus: &Node<'a>,
+    addr_len: usize,
+    unit: &[u32],
+    spec: &[u32],
+) -> Result<Option<ParentIrq<'a>>, String> {
+    let map = nexus
+        .find_property("interrupt-map")
+        .ok_or_else(|| format!("[{}] has no `interrupt-map`", nexus.name()))?
+        .u32_list()
+        .collect::<Vec<_>>();
+    let spec_len = interrupt_cells(nexus)?;
+
+    let mut child = Vec::with_capacity(addr_len + spec_len);
+    child.extend((0..addr_len).map(|i| unit.get(i).copied().unwrap_or(0)));
+    child.extend((0..spec_len).map(|i| spec.get(i).copied().unwrap_or(0)));
+
+    let mask = match nexus.find_property("interrupt-map-mask") {
+        Some(prop) => prop.u32_list().collect::<Vec<_>>(),
+        None => vec![u32::MAX; child.len()],
+    };
+    let masked = child
+        .iter()
+        .enumerate()
+        .map(|(i, v)| v & mask.get(i).copied().unwrap_or(u32::MAX))
+        .collect::<Vec<_>>();
+
+    let mut rest = map.as_slice();
+    while rest.len() > child.len() {
+        let (entry_child, tail) = rest.split_at(child.len());
+        let parent = node_by_phandle(fdt, tail[0].into())?;
+        let parent_addr_len = address_cells(&parent);
+        let parent_spec_len = interrupt_cells(&parent)?;
+        let parent_len = parent_addr_len + parent_spec_len;
+        if tail.len() < 1 + parent_len {
+            return Err(format!("[{}] truncated `interrupt-map`", nexus.name()));
+        }
+        let parent_cells = &tail[1..1 + parent_len];
+        rest = &tail[1 + parent_len..];
+
+        if entry_child == masked.as_slice() {
+            let (unit, spec) = parent_cells.split_at(parent_addr_len);
+            return Ok(Some(ParentIrq {
+                parent,
+                unit: unit.to_vec(),
+                spec: spec.to_vec(),
+            }));
+        }
+    }
+    Ok(None)
+}
+
+/// Follow `interrupt-map` nexus nodes from `parent` until an
+/// `interrupt-controller` is reached.
+pub(crate) fn resolve_spec<'a>(fdt: &Fdt<'a>, irq: ParentIrq<'a>) -> Result<IrqSpec, String> {
+    let mut irq = irq;
+    // further nexus nodes get the unit address of the map entry, sized by
+    // their own `#address-cells`
+    let mut addr_len = nexus_address_cells(fdt, &irq.parent);
+    for _ in 0..MAX_NEXUS_DEPTH {
+        if is_controller(&irq.parent) {
+            let controller = irq.parent.phandle().ok_or_else(|| {
+                format!(
+                    "interrupt controller [{}] has no phandle",
+                    irq.parent.name()
+                )
+            })?;
+            return Ok(IrqSpec {
+                controller,
+                cells: irq.spec,
+            });
+        }
+        irq = map_through(fdt, &irq.parent, addr_len, &irq.unit, &irq.spec)?.ok_or_else(|| {
+            format!(
+                "{:?} not found in `interrupt-map` of [{}]",
+                irq.spec,
+                irq.parent.name()
+            )
+        })?;
+        addr_len = irq.unit.len();
+    }
+    Err(format!(
+        "`interrupt-map` nested deeper than {MAX_NEXUS_DEPTH}"
+    ))
+}
+
+/// The INTx `pin` of the PCI function at `address` behind the host bridge
+/// `host`, in its owning controller's format.
+///
+/// The unit address is the `phys.hi` cell of the PCI binding, with the
+/// other address cells 0.
+pub(crate) fn resolve_pci<'a>(
+    fdt: &Fdt<'a>,
+    host: &Node<'a>,
+    address: PciAddress,
+    pin: u8,
+) -> Result<IrqSpec, String> {
+    let hi = (address.bus() as u32) << 16
+        | (address.device() as u32) << 11
+        | (address.function() as u32) << 8;
+    resolve_spec(
+        fdt,
+        ParentIrq {
+            parent: host.clone(),
+            unit: vec![hi, 0, 0],
+            spec: vec![pin as u32],
+        },
+    )
+}
+
+/// All interrupts of `node`, each in its owning controller's format.
+pub(crate) fn resolve<'a>(fdt: &Fdt<'a>, node: &Node<'a>) -> Result<Vec<IrqSpec>, String> {
+    let unit = node
+        .find_property("reg")
+        .map(|prop| prop.u32_list().collect::<Vec<_>>())
+        .unwrap_or_default();
+
+    raw_specs(fdt, node)?
+        .into_iter()
+        .map(|(parent, spec)| {
+            resolve_spec(
+                fdt,
+                ParentIrq {
+                    parent,
+                    unit: unit.clone(),
+                    spec,
+                },
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{super::overlay::*, *};
+    use crate::probe::fdt::nodes_with_path;
+
+    fn cells(v: &[u32]) -> Vec<u8> {
+        v.iter().flat_map(|c| c.to_be_bytes()).collect()
+    }
+
+    fn node(name: &str, props: &[(&str, Vec<u8>)], children: Vec<TreeNode>) -> TreeNode {
+        TreeNode {
+            name: name.into(),
+            props: props
+                .iter()
+                .map(|(n, v)| (String::from(*n), v.clone()))
+                .collect(),
+            children,
+        }
+    }
+
+    const GIC: u32 = 1;
+    const PIC: u32 = 2;
+    const NEXUS: u32 = 3;
+    const NEXUS2: u32 = 4;
+
+    /// A GIC and a one-cell controller, reached directly or through two
+    /// cascaded nexus nodes. The first nexus has no `#address-cells` of its
+    /// own, `/soc` gives it 1. A PCI host bridge maps the INTx of devices 0
+    /// and 1.
+    fn blob() -> Vec<u8> {
+        let controller = |name, phandle, interrupt_cells| {
+            node(
+                name,
+                &[
+                    ("phandle", cells(&[phandle])),
+                    ("interrupt-controller", vec![]),
+                    ("#interrupt-cells", cells(&[interrupt_cells])),
+                    ("#address-cells", cells(&[0])),
+                ],
+                vec![],
+            )
+        };
+        let device = |name, props: &[(&str, Vec<u8>)]| node(name, props, vec![]);
+        Tree {
+            root: node(
+                "",
+                &[("#address-cells", cells(&[2]))],
+                vec![
+                    controller("gic@8000000", GIC, 3),
+                    controller("pic@9000000", PIC, 1),
+                    node(
+                        "pcie@10000000",
+                        &[
+                            ("device_type", b"pci\0".to_vec()),
+                            ("#address-cells", cells(&[3])),
+                            ("#size-cells", cells(&[2])),
+                            ("#interrupt-cells", cells(&[1])),
+                            ("interrupt-map-mask", cells(&[0x1800, 0, 0, 7])),
+                            (
+                                "interrupt-map",
+                                cells(&[
+                                    0x0000, 0, 0, 1, GIC, 0, 30, 4, //
+                                    0x0000, 0, 0, 2, PIC, 12, //
+                                    0x0800, 0, 0, 1, GIC, 0, 31, 4,
+                                ]),
+                            ),
+                        ],
+                        vec![],
+                    ),
+                    node(
+                        "soc",
+                        &[
+                            ("#address-cells", cells(&[1])),
+                            ("#size-cells", cells(&[1])),
+                        ],
+                        vec![
+                            device(
+                                "nexus@1000",
+                                &[
+                                    ("phandle", cells(&[NEXUS])),
+                                    ("#interrupt-cells", cells(&[1])),
+                                    ("interrupt-map-mask", cells(&[0xf000, 0x3])),
+                                    (
+                                        "interrupt-map",
+                                        cells(&[
+                                            0x2000, 1, GIC, 0, 10, 4, //
+                                            0x2000, 2, NEXUS2, 0x5, 7, //
+                                            0x3000, 1, GIC, 0, 11, 4,
+                                        ]),
+                                    ),
+                                ],
+                            ),
+                            device(
+                                "nexus@1100",
+                                &[
+                                    ("phandle", cells(&[NEXUS2])),
+                                    ("#interrupt-cells", cells(&[1])),
+                                    ("#address-cells", cells(&[1])),
+                                    ("interrupt-map", cells(&[0x5, 7, PIC, 9])),
+                                ],
+                            ),
+                            device(
+                                "dev@2000",
+                                &[
+                                    ("reg", cells(&[0x2abc, 0x100])),
+                                    ("interrupt-parent", cells(&[NEXUS])),
+                                    ("interrupts", cells(&[5, 6])),
+                                ],
+                            ),
+                            device(
+                                "dev@3000",
+                                &[
+                                    ("reg", cells(&[0x3000, 0x100])),
+                                    ("interrupt-parent", cells(&[NEXUS])),
+                                    ("interrupts", cells(&[1])),
+                                ],
+                            ),
+                            device(
+                                "dev@4000",
+                                &[("interrupts-extended", cells(&[GIC, 0, 20, 1, PIC, 4]))],
+                            ),
+                            device(
+                                "dev@5000",
+                                &[
+                                    ("reg", cells(&[0x5000, 0x100])),
+                                    ("interrupt-parent", cells(&[NEXUS])),
+                                    ("interrupts", cells(&[1])),
+                                ],
+                            ),
+                        ],
+                    ),
+                ],
+            ),
+            ..Default::default()
+        }
+        .to_bytes()
+    }
+
+    fn spec(controller: u32, cells: &[u32]) -> IrqSpec {
+        IrqSpec {
+            controller: controller.into(),
+            cells: cells.to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_resolve() {
+        let raw = blob();
+        let fdt = Fdt::from_bytes(&raw).unwrap();
+        let table = [
+            // the mask keeps the unit address bits 12..16 and the two low
+            // specifier bits: 5 hits the first entry, 6 the second, which
+            // cascades through the second nexus
+            (
+                "/soc/dev@2000",
+                Some(vec![spec(GIC, &[0, 10, 4]), spec(PIC, &[9])]),
+            ),
+            ("/soc/dev@3000", Some(vec![spec(GIC, &[0, 11, 4])])),
+            (
+                "/soc/dev@4000",
+                Some(vec![spec(GIC, &[0, 20, 1]), spec(PIC, &[4])]),
+            ),
+            // no entry for unit address 0x5000
+            ("/soc/dev@5000", None),
+        ];
+        for (path, want) in table {
+            let (_, node) = nodes_with_path(&fdt).find(|(p, _)| p == path).unwrap();
+            let got = resolve(&fdt, &node);
+            match want {
+                Some(want) => assert_eq!(got.as_ref(), Ok(&want), "{path}"),
+                None => assert!(got.is_err(), "{path}: {got:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_resolve_pci() {
+        let raw = blob();
+        let fdt = Fdt::from_bytes(&raw).unwrap();
+        let (_, host) = nodes_with_path(&fdt)
+            .find(|(p, _)| p == "/pcie@10000000")
+            .unwrap();
+        let table = [
+            ((0, 0, 0), 1, Some(spec(GIC, &[0, 30, 4]))),
+            ((0, 0, 0), 2, Some(spec(PIC, &[12]))),
+            ((0, 1, 0), 1, Some(spec(GIC, &[0, 31, 4]))),
+            // the mask drops the bus and function
+            ((3, 0, 5), 1, Some(spec(GIC, &[0, 30, 4]))),
+            ((0, 2, 0), 1, None),
+            ((0, 1, 0), 3, None),
+        ];
+        for ((bus, device, function), pin, want) in table {
+            let address = PciAddress::new(0, bus, device, function);
+            let got = resolve_pci(&fdt, &host, address, pin);
+            match want {
+                Some(want) => assert_eq!(got, Ok(want), "{address} pin {pin}"),
+                None => assert!(got.is_err(), "{address} pin {pin}: {got:?}"),
+            }
+        }
+    }
+}
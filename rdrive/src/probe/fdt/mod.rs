@@ -1,17 +1,30 @@
 use alloc::{
-    collections::{BTreeMap, btree_set::BTreeSet},
+    collections::{BTreeMap, btree_map::Entry, btree_set::BTreeSet},
+    string::{String, ToString},
     vec::Vec,
 };
-use core::ptr::NonNull;
+use core::{ops::Range, ptr::NonNull};
+use rdif_intc::Intc;
+use rdif_pcie::PciAddress;
 use spin::{Mutex, Once};
 
 pub use fdt_parser::*;
 
+mod irq;
+mod overlay;
+mod translate;
+
+pub use irq::FdtIrq;
+use irq::IrqSpec;
+pub use overlay::OverlayError;
+pub use translate::DmaRange;
+
 use crate::{
     Descriptor, DeviceId, PlatformDevice,
     error::DriverError,
-    probe::OnProbeError,
-    register::{DriverRegister, ProbeKind},
+    policy::ProbeTarget,
+    probe::{OnProbeError, ProbeFuture, task},
+    register::{DriverParam, DriverRegister, ProbeKind},
 };
 
 use super::ProbeError;
@@ -28,7 +41,32 @@ pub fn probe_register(
     register: &DriverRegister,
 ) -> Result<Vec<Result<(), OnProbeError>>, ProbeError> {
     let sys = system();
-    sys.probe_register(register)
+    let (mut out, pending) = sys.probe_register(register)?;
+    out.extend(sys.run_async(pending));
+    Ok(out.into_iter().map(|(_, res)| res).collect())
+}
+
+/// Run the sync probes of `register`, and return its async probes unstarted.
+pub(crate) fn probe_register_split(register: &DriverRegister) -> Result<ProbeResults, ProbeError> {
+    system().probe_register(register)
+}
+
+/// Run async probes concurrently through [`Osal::run_tasks`](crate::Osal::run_tasks).
+pub(crate) fn run_async(pending: Vec<AsyncProbe>) -> Vec<(&'static str, Result<(), OnProbeError>)> {
+    system().run_async(pending)
+}
+
+pub(crate) fn bind(register: &DriverRegister, path: &str) -> Result<(), ProbeError> {
+    system().bind(register, path)
+}
+
+/// Compatibles of the node at `path`, `None` if there is no such node.
+pub(crate) fn compatibles_of(path: &str) -> Option<Vec<&'static str>> {
+    system().compatibles_of(path)
+}
+
+pub(crate) fn apply_overlay(dtbo: &[u8]) -> Result<(), OverlayError> {
+    system().apply_overlay(dtbo)
 }
 
 pub(crate) fn system() -> &'static System {
@@ -38,10 +76,16 @@ pub(crate) fn system() -> &'static System {
 #[derive(Clone)]
 pub struct FdtInfo<'a> {
     pub node: Node<'a>,
+    path: String,
     phandle_2_device_id: BTreeMap<Phandle, DeviceId>,
 }
 
 impl<'a> FdtInfo<'a> {
+    /// Full path of the node, e.g. `/soc/serial@9000000`.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
     pub fn phandle_to_device_id(&self, phandle: Phandle) -> Option<DeviceId> {
         self.phandle_2_device_id.get(&phandle).copied()
     }
@@ -50,6 +94,39 @@ impl<'a> FdtInfo<'a> {
         self.node.clocks().find(|clock| clock.name == Some(name))
     }
 
+    /// CPU physical MMIO ranges of `reg`, translated through the `ranges`
+    /// of every parent bus.
+    ///
+    /// Unlike [`Node::reg`], this walks all levels of nested buses.
+    pub fn mmio_ranges(&self) -> Result<Vec<Range<u64>>, OnProbeError> {
+        let chain = translate::ancestors(&self.node.fdt(), &self.path);
+        translate::reg_ranges(&chain).map_err(OnProbeError::Fdt)
+    }
+
+    /// DMA windows of the device from the nearest `dma-ranges` of the node
+    /// or its parents.
+    ///
+    /// An empty list means no translation: bus and CPU addresses are equal.
+    pub fn dma_ranges(&self) -> Result<Vec<DmaRange>, OnProbeError> {
+        let chain = translate::ancestors(&self.node.fdt(), &self.path);
+        translate::dma_ranges(&chain).map_err(OnProbeError::Fdt)
+    }
+
+    /// Interrupts of the node resolved through `interrupt-parent`,
+    /// `interrupts-extended` and any `interrupt-map` nexus, each decoded by
+    /// its owning controller.
+    ///
+    /// The controllers must already be probed, which [`ProbePriority::INTC`](crate::register::ProbePriority::INTC) ensures.
+    pub fn irqs(&self) -> Result<Vec<FdtIrq>, OnProbeError> {
+        let fdt = self.node.fdt();
+        let specs = irq::resolve(&fdt, &self.node).map_err(OnProbeError::Fdt)?;
+
+        specs
+            .into_iter()
+            .map(|spec| decode_irq(self.phandle_to_device_id(spec.controller), spec))
+            .collect()
+    }
+
     pub fn interrupts(&self) -> Vec<Vec<u32>> {
         let mut out = Vec::new();
         if let Some(raws) = self.node.interrupts() {
@@ -61,24 +138,66 @@ impl<'a> FdtInfo<'a> {
     }
 }
 
+/// Decode `spec` by its controller, registered as `irq_parent`.
+fn decode_irq(irq_parent: Option<DeviceId>, spec: IrqSpec) -> Result<FdtIrq, OnProbeError> {
+    let irq_parent = irq_parent
+        .ok_or_else(|| OnProbeError::Fdt(format!("no device for {:?}", spec.controller)))?;
+    let intc =
+        crate::get::<Intc>(irq_parent).map_err(|e| OnProbeError::IrqParent(irq_parent, e))?;
+    let config = intc
+        .lock()
+        .map_err(|e| OnProbeError::IrqParent(irq_parent, e))?
+        .irq_config_by_fdt(&spec.cells)?;
+    Ok(FdtIrq { irq_parent, config })
+}
+
+/// The interrupt `pin`, 1 for INTA to 4 for INTD, of the PCI function at
+/// `address` as routed by the `interrupt-map` of the host bridge node at
+/// `path`.
+pub(crate) fn pci_intx(path: &str, address: PciAddress, pin: u8) -> Result<FdtIrq, OnProbeError> {
+    let system = system();
+    let fdt = Fdt::from_ptr(system.fdt_addr())?;
+    let (_, host) =
+        find_by_path(&fdt, path).ok_or_else(|| OnProbeError::Fdt(format!("[{path}] not found")))?;
+    let spec = irq::resolve_pci(&fdt, &host, address, pin).map_err(OnProbeError::Fdt)?;
+    decode_irq(system.phandle_to_device_id(spec.controller), spec)
+}
+
 pub type FnOnProbe = fn(fdt: FdtInfo<'_>, plat_dev: PlatformDevice) -> Result<(), OnProbeError>;
 
+pub type FnOnProbeAsync = fn(fdt: FdtInfo<'static>, plat_dev: PlatformDevice) -> ProbeFuture;
+
 pub struct System {
-    phandle_2_device_id: BTreeMap<Phandle, DeviceId>,
-    fdt_addr: usize,
+    phandle_2_device_id: Mutex<BTreeMap<Phandle, DeviceId>>,
+    // ids of nodes without phandle, created on first probe or lookup
+    path_2_device_id: Mutex<BTreeMap<String, DeviceId>>,
+    // replaced by the merged tree when an overlay is applied
+    fdt_addr: Mutex<usize>,
     // keep unique by driver register name in FDT mode
     probed_names: Mutex<BTreeSet<&'static str>>,
+    // nodes added or enabled by overlays, probed once per driver regardless of `probed_names`
+    overlaid: Mutex<BTreeSet<String>>,
+    probed_overlaid: Mutex<BTreeSet<(&'static str, String)>>,
+    // node paths a driver has been probed or manually bound against
+    bound: Mutex<BTreeSet<String>>,
+    // node paths owned by secure firmware or a hypervisor
+    reserved: Mutex<BTreeSet<String>>,
 }
 
 unsafe impl Send for System {}
 
 impl System {
     pub fn fdt_addr(&self) -> NonNull<u8> {
-        unsafe { NonNull::new_unchecked(self.fdt_addr as *mut u8) }
+        unsafe { NonNull::new_unchecked(*self.fdt_addr.lock() as *mut u8) }
     }
 
     pub fn phandle_to_device_id(&self, phandle: Phandle) -> Option<DeviceId> {
-        self.phandle_2_device_id.get(&phandle).copied()
+        self.phandle_2_device_id.lock().get(&phandle).copied()
+    }
+
+    /// Keep the node at `path` and its subtree away from probing.
+    pub fn reserve(&self, path: &str) {
+        self.reserved.lock().insert(path.to_string());
     }
 }
 
@@ -92,18 +211,119 @@ impl System {
             }
         }
         Ok(Self {
-            phandle_2_device_id,
-            fdt_addr: fdt_addr.as_ptr() as usize,
+            phandle_2_device_id: Mutex::new(phandle_2_device_id),
+            path_2_device_id: Mutex::new(BTreeMap::new()),
+            fdt_addr: Mutex::new(fdt_addr.as_ptr() as usize),
             probed_names: Mutex::new(BTreeSet::new()),
+            overlaid: Mutex::new(BTreeSet::new()),
+            probed_overlaid: Mutex::new(BTreeSet::new()),
+            bound: Mutex::new(BTreeSet::new()),
+            reserved: Mutex::new(BTreeSet::new()),
+        })
+    }
+
+    /// Merge the overlay blob `dtbo` into the current tree.
+    ///
+    /// The merged tree is written to a newly allocated blob which is never
+    /// freed: nodes handed to drivers keep pointing into the previous one.
+    pub fn apply_overlay(&self, dtbo: &[u8]) -> Result<(), OverlayError> {
+        // `fdt_parser` reads the header in place, which needs alignment.
+        let mut aligned = vec![0u64; dtbo.len().div_ceil(8)];
+        let aligned_bytes =
+            unsafe { core::slice::from_raw_parts_mut(aligned.as_mut_ptr() as *mut u8, dtbo.len()) };
+        aligned_bytes.copy_from_slice(dtbo);
+        let overlay = overlay::Tree::from_fdt(&Fdt::from_bytes(aligned_bytes)?);
+
+        let mut fdt_addr = self.fdt_addr.lock();
+        let base = Fdt::from_ptr(unsafe { NonNull::new_unchecked(*fdt_addr as *mut u8) })?;
+        let mut tree = overlay::Tree::from_fdt(&base);
+        let touched = overlay::apply(&mut tree, overlay)?;
+
+        let bytes = tree.to_bytes();
+        let blob: &'static mut [u64] = vec![0u64; bytes.len().div_ceil(8)].leak();
+        let ptr = blob.as_mut_ptr() as *mut u8;
+        unsafe { core::ptr::copy_nonoverlapping(bytes.as_ptr(), ptr, bytes.len()) };
+
+        let merged = Fdt::from_ptr(unsafe { NonNull::new_unchecked(ptr) })?;
+        {
+            let mut map = self.phandle_2_device_id.lock();
+            for node in merged.all_nodes() {
+                if let Some(phandle) = node.phandle()
+                    && let Entry::Vacant(entry) = map.entry(phandle)
+                {
+                    entry.insert(DeviceId::new());
+                }
+            }
+        }
+        *fdt_addr = ptr as usize;
+
+        debug!("Overlay applied, {} nodes to probe", touched.len());
+        self.overlaid.lock().extend(touched);
+        Ok(())
+    }
+
+    /// Id of the node at `path`, stable across probes and lookups.
+    fn node_device_id(&self, phandle: Option<Phandle>, path: &str) -> DeviceId {
+        if let Some(id) = phandle.and_then(|p| self.phandle_to_device_id(p)) {
+            return id;
+        }
+        match self.path_2_device_id.lock().entry(path.to_string()) {
+            Entry::Occupied(entry) => *entry.get(),
+            Entry::Vacant(entry) => *entry.insert(DeviceId::new()),
+        }
+    }
+
+    /// Resolve `path` to the id of its node.
+    ///
+    /// `path` is either absolute or starts with an `/aliases` entry, e.g.
+    /// `serial0` or `i2c1/eeprom@50`. Anything after `:` is ignored.
+    pub fn path_to_device_id(&self, path: &str) -> Option<DeviceId> {
+        let fdt = Fdt::from_ptr(self.fdt_addr()).ok()?;
+        let (path, _) = split_options(path);
+        let (path, node) = find_by_path(&fdt, path)?;
+        Some(self.node_device_id(node.phandle(), &path))
+    }
+
+    /// Resolve a path-valued `/chosen` property such as `stdout-path`.
+    pub fn chosen(&self, property: &str) -> Option<ChosenDevice> {
+        let fdt = Fdt::from_ptr(self.fdt_addr()).ok()?;
+        let value = fdt
+            .all_nodes()
+            .find(|node| node.level == 2 && node.name() == "chosen")?
+            .find_property(property)?
+            .str_list()
+            .next()?;
+        let (path, options) = split_options(value);
+        let (path, node) = find_by_path(&fdt, path)?;
+        Some(ChosenDevice {
+            device_id: self.node_device_id(node.phandle(), &path),
+            path,
+            options: options.map(String::from),
         })
     }
 
-    fn new_device_id(&self, phandle: Option<Phandle>) -> DeviceId {
-        if let Some(phandle) = phandle {
-            self.phandle_2_device_id[&phandle]
+    fn compatibles_of(&self, path: &str) -> Option<Vec<&'static str>> {
+        let fdt: Fdt<'static> = Fdt::from_ptr(self.fdt_addr()).ok()?;
+        let (_, node) = find_by_path(&fdt, path)?;
+        Some(node.compatibles().collect())
+    }
+
+    fn is_probed(&self, name: &'static str, path: &str) -> bool {
+        if self.overlaid.lock().contains(path) {
+            self.probed_overlaid
+                .lock()
+                .contains(&(name, path.to_string()))
         } else {
-            DeviceId::new()
+            self.probed_names.lock().contains(name)
+        }
+    }
+
+    fn mark_probed(&self, name: &'static str, path: &str) {
+        if self.overlaid.lock().contains(path) {
+            self.probed_overlaid.lock().insert((name, path.to_string()));
         }
+        self.probed_names.lock().insert(name);
+        self.bound.lock().insert(path.to_string());
     }
 
     fn get_fdt_match_nodes(
@@ -112,88 +332,766 @@ impl System {
         fdt: &Fdt<'static>,
     ) -> Vec<ProbeFdtInfo> {
         let mut out = Vec::new();
-        for node in fdt.all_nodes() {
-            if matches!(node.status(), Some(Status::Disabled)) {
-                continue;
-            }
-
+        for (path, node) in self.available_nodes(fdt) {
             let node_compatibles = node.compatibles().collect::<Vec<_>>();
 
             for probe in register.probe_kinds {
-                let &ProbeKind::Fdt {
-                    compatibles,
-                    on_probe,
-                } = probe
-                else {
+                let Some((compatibles, on_probe)) = OnProbe::of(probe) else {
                     continue;
                 };
 
-                for campatible in &node_compatibles {
-                    if compatibles.contains(campatible) {
-                        out.push(ProbeFdtInfo {
-                            name: register.name,
-                            node: node.clone(),
-                            on_probe,
-                        });
-                    }
+                if node_compatibles.iter().any(|c| compatibles.contains(c)) {
+                    out.push(ProbeFdtInfo {
+                        name: register.name,
+                        params: register.params,
+                        path: path.clone(),
+                        node: node.clone(),
+                        on_probe,
+                    });
                 }
             }
         }
         out
     }
 
-    fn probe_register(
-        &self,
-        register: &DriverRegister,
-    ) -> Result<Vec<Result<(), OnProbeError>>, ProbeError> {
+    /// Nodes that may be probed: skips unavailable `status`, reserved paths
+    /// and everything below them.
+    fn available_nodes<'a>(&self, fdt: &Fdt<'a>) -> impl Iterator<Item = (String, Node<'a>)> {
+        let reserved = self.reserved.lock().clone();
+        let mut skip_below = None;
+        nodes_with_path(fdt).filter(move |(path, node)| {
+            if let Some(level) = skip_below {
+                if node.level > level {
+                    return false;
+                }
+                skip_below = None;
+            }
+            if !is_available(node) || reserved.contains(path) {
+                skip_below = Some(node.level);
+                return false;
+            }
+            true
+        })
+    }
+
+    fn probe_register(&self, register: &DriverRegister) -> Result<ProbeResults, ProbeError> {
+        let mut out = self.probe_forced(register);
         let fdt: Fdt<'static> = Fdt::from_ptr(self.fdt_addr())?;
-        let node_ls = self.get_fdt_match_nodes(register, &fdt);
-        let mut out = Vec::new();
-        for node_info in node_ls {
-            if self.probed_names.lock().contains(node_info.name) {
-                // skip duplicated register name in FDT system
+        let (overlaid, plain): (Vec<_>, Vec<_>) = self
+            .get_fdt_match_nodes(register, &fdt)
+            .into_iter()
+            .partition(|node_info| self.overlaid.lock().contains(&node_info.path));
+
+        // nodes from overlays are each probed, of the others the driver
+        // takes the first one it probes successfully
+        let mut pending = Vec::new();
+        for node_info in overlaid {
+            pending.extend(self.probe_first(vec![node_info], &mut out));
+        }
+        pending.extend(self.probe_first(plain, &mut out));
+
+        Ok((out, pending))
+    }
+
+    /// Probe `candidates` in turn until one succeeds. An async probe ends the
+    /// walk and is returned unstarted, with the candidates left to try
+    /// should it fail.
+    fn probe_first(
+        &self,
+        candidates: Vec<ProbeFdtInfo>,
+        out: &mut Vec<(&'static str, Result<(), OnProbeError>)>,
+    ) -> Option<AsyncProbe> {
+        let mut candidates = candidates.into_iter();
+        while let Some(node_info) = candidates.next() {
+            if self.is_probed(node_info.name, &node_info.path)
+                || self.bound.lock().contains(&node_info.path)
+            {
                 continue;
             }
-            let id = self.new_device_id(node_info.node.phandle());
 
-            let irq_parent = node_info
-                .node
-                .interrupt_parent()
-                .filter(|p| p.node.phandle() != node_info.node.phandle())
-                .and_then(|n| n.node.phandle())
-                .and_then(|p| self.phandle_2_device_id.get(&p).copied());
+            let compatibles = node_info.node.compatibles().collect::<Vec<_>>();
+            let target = ProbeTarget::Fdt {
+                path: &node_info.path,
+                compatibles: &compatibles,
+            };
+            if !crate::is_bind_allowed(node_info.name, &target) {
+                continue;
+            }
 
-            let phandle_map = self.phandle_2_device_id.clone();
+            let (info, dev) = self.probe_args(&node_info);
+            match node_info.on_probe {
+                OnProbe::Sync(on_probe) => {
+                    let res = on_probe(info, dev);
 
-            debug!("Probe [{}]->[{}]", node_info.node.name, node_info.name);
+                    if res.is_ok() {
+                        self.mark_probed(node_info.name, &node_info.path);
+                    }
+
+                    out.push((node_info.name, res));
+                }
+                OnProbe::Async(on_probe) => {
+                    return Some(AsyncProbe {
+                        name: node_info.name,
+                        path: node_info.path,
+                        future: on_probe(info, dev),
+                        rest: candidates.collect(),
+                    });
+                }
+            }
+        }
+        None
+    }
 
-            let descriptor = Descriptor {
-                name: node_info.name,
-                device_id: id,
-                irq_parent,
+    /// Bind `register` to the nodes the bind policy forces it onto, unless
+    /// bound already or denied.
+    fn probe_forced(
+        &self,
+        register: &DriverRegister,
+    ) -> Vec<(&'static str, Result<(), OnProbeError>)> {
+        let mut out = Vec::new();
+        for path in crate::forced_paths(register.name) {
+            let Some(compatibles) = self.compatibles_of(&path) else {
+                warn!(
+                    "Bind [{}] -> [{path}] forced by policy: not found",
+                    register.name
+                );
+                continue;
+            };
+            let target = ProbeTarget::Fdt {
+                path: &path,
+                compatibles: &compatibles,
             };
+            if self.bound.lock().contains(&path) || !crate::is_bind_allowed(register.name, &target)
+            {
+                continue;
+            }
+            match self.bind(register, &path) {
+                Ok(()) => out.push((register.name, Ok(()))),
+                Err(ProbeError::OnProbe(e)) => out.push((register.name, Err(e))),
+                Err(e) => warn!("Bind [{}] -> [{path}] forced by policy: {e}", register.name),
+            }
+        }
+        out
+    }
 
-            let res = (node_info.on_probe)(
-                FdtInfo {
-                    node: node_info.node.clone(),
-                    phandle_2_device_id: phandle_map,
-                },
-                PlatformDevice::new(descriptor),
-            );
+    /// Run `pending` concurrently. A failed probe moves on to the next
+    /// candidate of its driver, as sync probes do, until none is left.
+    fn run_async(&self, pending: Vec<AsyncProbe>) -> Vec<(&'static str, Result<(), OnProbeError>)> {
+        let mut out = Vec::new();
+        let mut pending = pending;
+        while !pending.is_empty() {
+            let futures = pending
+                .into_iter()
+                .map(|probe| ((probe.name, probe.path, probe.rest), probe.future))
+                .collect();
 
-            if res.is_ok() {
-                self.probed_names.lock().insert(node_info.name);
+            let mut retry = Vec::new();
+            for ((name, path, rest), res) in task::run(futures) {
+                if res.is_ok() {
+                    self.mark_probed(name, &path);
+                } else {
+                    retry.push(rest);
+                }
+                out.push((name, res));
             }
+            pending = retry
+                .into_iter()
+                .filter_map(|rest| self.probe_first(rest, &mut out))
+                .collect();
+        }
+        out
+    }
+
+    /// Run `register`'s FDT probe against the node at `path`, ignoring compatibles.
+    fn bind(&self, register: &DriverRegister, path: &str) -> Result<(), ProbeError> {
+        let on_probe = register
+            .probe_kinds
+            .iter()
+            .find_map(|probe| OnProbe::of(probe).map(|(_, on_probe)| on_probe))
+            .ok_or_else(|| ProbeError::ProbeKindMissing {
+                name: register.name.into(),
+                kind: "FDT",
+            })?;
 
-            out.push(res);
+        let fdt: Fdt<'static> = Fdt::from_ptr(self.fdt_addr())?;
+        let (path, node) = nodes_with_path(&fdt)
+            .find(|(p, _)| p == path)
+            .ok_or_else(|| ProbeError::TargetNotFound(path.into()))?;
+        if !self.available_nodes(&fdt).any(|(p, _)| p == path) {
+            return Err(ProbeError::TargetUnavailable(path));
+        }
+        if self.bound.lock().contains(&path) {
+            return Err(ProbeError::TargetBound(path));
         }
 
-        Ok(out)
+        let (info, dev) = self.probe_args(&ProbeFdtInfo {
+            name: register.name,
+            params: register.params,
+            path: path.clone(),
+            node,
+            on_probe,
+        });
+        match on_probe {
+            OnProbe::Sync(on_probe) => on_probe(info, dev)?,
+            OnProbe::Async(on_probe) => {
+                let res = task::run(vec![((), on_probe(info, dev))]);
+                res.into_iter().next().unwrap().1?
+            }
+        }
+        // a manual bind does not count against the one node per register
+        // name the driver gets when probed by compatible
+        self.bound.lock().insert(path);
+        Ok(())
+    }
+
+    fn probe_args(&self, node_info: &ProbeFdtInfo) -> (FdtInfo<'static>, PlatformDevice) {
+        let id = self.node_device_id(node_info.node.phandle(), &node_info.path);
+
+        let irq_parent = node_info
+            .node
+            .interrupt_parent()
+            .filter(|p| p.node.phandle() != node_info.node.phandle())
+            .and_then(|n| n.node.phandle())
+            .and_then(|p| self.phandle_to_device_id(p));
+
+        let phandle_map = self.phandle_2_device_id.lock().clone();
+
+        debug!("Probe [{}]->[{}]", node_info.node.name, node_info.name);
+
+        let descriptor = Descriptor {
+            name: node_info.name,
+            device_id: id,
+            irq_parent,
+        };
+
+        (
+            FdtInfo {
+                node: node_info.node.clone(),
+                path: node_info.path.clone(),
+                phandle_2_device_id: phandle_map,
+            },
+            PlatformDevice::new(
+                descriptor,
+                crate::driver_params(node_info.name, node_info.params),
+            ),
+        )
+    }
+}
+
+/// The device named by a `/chosen` property.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChosenDevice {
+    pub device_id: DeviceId,
+    /// Full path of the node, aliases resolved.
+    pub path: String,
+    /// The `:options` suffix, e.g. `115200n8`.
+    pub options: Option<String>,
+}
+
+#[derive(Clone, Copy)]
+enum OnProbe {
+    Sync(FnOnProbe),
+    Async(FnOnProbeAsync),
+}
+
+impl OnProbe {
+    fn of(kind: &ProbeKind) -> Option<(&'static [&'static str], Self)> {
+        match *kind {
+            ProbeKind::Fdt {
+                compatibles,
+                on_probe,
+            } => Some((compatibles, Self::Sync(on_probe))),
+            ProbeKind::FdtAsync {
+                compatibles,
+                on_probe,
+            } => Some((compatibles, Self::Async(on_probe))),
+            _ => None,
+        }
     }
 }
 
+/// An async probe started but not yet polled.
+pub(crate) struct AsyncProbe {
+    name: &'static str,
+    path: String,
+    future: ProbeFuture,
+    /// Nodes of the driver to try next if this probe fails.
+    rest: Vec<ProbeFdtInfo>,
+}
+
+/// Results of sync probes by driver name, and the async probes still to run.
+pub(crate) type ProbeResults = (
+    Vec<(&'static str, Result<(), OnProbeError>)>,
+    Vec<AsyncProbe>,
+);
+
 struct ProbeFdtInfo {
     name: &'static str,
+    params: &'static [DriverParam],
+    path: String,
     node: Node<'static>,
-    on_probe: FnOnProbe,
+    on_probe: OnProbe,
+}
+
+/// Whether `status` lets the node be probed, as Linux's `of_device_is_available`.
+///
+/// `disabled`, `reserved`, `fail` and `fail-sss` nodes are left alone.
+fn is_available(node: &Node<'_>) -> bool {
+    match node
+        .find_property("status")
+        .and_then(|prop| prop.str_list().next())
+    {
+        Some(status) => matches!(status, "okay" | "ok"),
+        None => true,
+    }
+}
+
+/// Split `serial0:115200n8` into the path and its options, as Linux does.
+fn split_options(path: &str) -> (&str, Option<&str>) {
+    match path.split_once(':') {
+        Some((path, options)) => (path, Some(options)),
+        None => (path, None),
+    }
+}
+
+/// Find the node at an absolute or alias-relative `path`.
+fn find_by_path<'a>(fdt: &Fdt<'a>, path: &str) -> Option<(String, Node<'a>)> {
+    let full;
+    let path = if path.starts_with('/') {
+        path
+    } else {
+        let (alias, rest) = path.split_once('/').unwrap_or((path, ""));
+        let base = nodes_with_path(fdt)
+            .find(|(p, _)| p == "/aliases")?
+            .1
+            .find_property(alias)?
+            .str_list()
+            .next()?;
+        full = if rest.is_empty() {
+            base.to_string()
+        } else {
+            format!("{}/{rest}", base.trim_end_matches('/'))
+        };
+        full.as_str()
+    };
+    let path = match path.trim_end_matches('/') {
+        "" => "/",
+        trimmed => trimmed,
+    };
+    nodes_with_path(fdt).find(|(p, _)| p == path)
+}
+
+/// Iterate all nodes together with their full path, e.g. `/soc/serial@9000000`.
+pub(crate) fn nodes_with_path<'a>(fdt: &Fdt<'a>) -> impl Iterator<Item = (String, Node<'a>)> {
+    let mut names: Vec<&'a str> = Vec::new();
+    fdt.all_nodes().map(move |node| {
+        names.truncate(node.level.saturating_sub(1));
+        names.push(node.name());
+
+        let mut path = String::new();
+        for name in names.iter().skip(1) {
+            path.push('/');
+            path.push_str(name);
+        }
+        if path.is_empty() {
+            path.push('/');
+        }
+        (path, node)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::boxed::Box;
+
+    use super::{overlay::*, *};
+    use crate::register::ProbePriority;
+
+    fn string(s: &str) -> Vec<u8> {
+        let mut v = s.as_bytes().to_vec();
+        v.push(0);
+        v
+    }
+
+    fn node(name: &str, props: &[(&str, &str)], children: Vec<TreeNode>) -> TreeNode {
+        TreeNode {
+            name: name.into(),
+            props: props
+                .iter()
+                .map(|(n, v)| (n.to_string(), string(v)))
+                .collect(),
+            children,
+        }
+    }
+
+    fn blob() -> Vec<u8> {
+        Tree {
+            root: node(
+                "",
+                &[],
+                vec![
+                    node(
+                        "aliases",
+                        &[
+                            ("serial0", "/soc/serial@9000000"),
+                            ("i2c1", "/soc/i2c@9010000"),
+                        ],
+                        vec![],
+                    ),
+                    node("chosen", &[("stdout-path", "serial0:115200n8")], vec![]),
+                    node(
+                        "soc",
+                        &[],
+                        vec![
+                            node("serial@9000000", &[], vec![]),
+                            node("i2c@9010000", &[], vec![node("eeprom@50", &[], vec![])]),
+                        ],
+                    ),
+                ],
+            ),
+            ..Default::default()
+        }
+        .to_bytes()
+    }
+
+    /// A system over `tree`, its blob leaked as nodes handed to probes are `'static`.
+    fn system(tree: Tree) -> System {
+        crate::init_test_manager();
+        let bytes = tree.to_bytes();
+        let blob: &'static mut [u64] = vec![0u64; bytes.len().div_ceil(8)].leak();
+        let ptr = blob.as_mut_ptr() as *mut u8;
+        unsafe { core::ptr::copy_nonoverlapping(bytes.as_ptr(), ptr, bytes.len()) };
+        System::new(NonNull::new(ptr).unwrap()).unwrap()
+    }
+
+    fn uarts() -> Tree {
+        Tree {
+            root: node(
+                "",
+                &[],
+                vec![node(
+                    "soc",
+                    &[],
+                    vec![
+                        node("uart@1000", &[("compatible", "test,uart")], vec![]),
+                        node("uart@2000", &[("compatible", "test,uart")], vec![]),
+                        node(
+                            "uart@3000",
+                            &[("compatible", "test,uart"), ("status", "disabled")],
+                            vec![],
+                        ),
+                    ],
+                )],
+            ),
+            ..Default::default()
+        }
+    }
+
+    fn find<'a>(fdt: &Fdt<'a>, path: &str) -> Option<String> {
+        find_by_path(fdt, path).map(|(p, _)| p)
+    }
+
+    #[test]
+    fn test_split_options() {
+        assert_eq!(
+            split_options("serial0:115200n8"),
+            ("serial0", Some("115200n8"))
+        );
+        assert_eq!(
+            split_options("/soc/serial@9000000"),
+            ("/soc/serial@9000000", None)
+        );
+    }
+
+    #[test]
+    fn test_find_by_path() {
+        let raw = blob();
+        let fdt = Fdt::from_bytes(&raw).unwrap();
+        assert_eq!(find(&fdt, "/").as_deref(), Some("/"));
+        assert_eq!(
+            find(&fdt, "/soc/serial@9000000").as_deref(),
+            Some("/soc/serial@9000000")
+        );
+        assert_eq!(
+            find(&fdt, "serial0").as_deref(),
+            Some("/soc/serial@9000000")
+        );
+        assert_eq!(
+            find(&fdt, "i2c1/eeprom@50/").as_deref(),
+            Some("/soc/i2c@9010000/eeprom@50")
+        );
+        assert_eq!(find(&fdt, "/soc/serial"), None);
+        assert_eq!(find(&fdt, "serial1"), None);
+    }
+
+    #[test]
+    fn test_bind() {
+        static PROBED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+        fn probe_uart(fdt: FdtInfo<'_>, _dev: PlatformDevice) -> Result<(), OnProbeError> {
+            PROBED.lock().push(format!("uart {}", fdt.path()));
+            Ok(())
+        }
+        fn probe_other(fdt: FdtInfo<'_>, _dev: PlatformDevice) -> Result<(), OnProbeError> {
+            PROBED.lock().push(format!("other {}", fdt.path()));
+            Ok(())
+        }
+
+        let uart = DriverRegister {
+            name: "uart",
+            probe_kinds: &[ProbeKind::Fdt {
+                compatibles: &["test,uart"],
+                on_probe: probe_uart,
+            }],
+            ..DriverRegister::EMPTY
+        };
+        let other = DriverRegister {
+            name: "other",
+            probe_kinds: &[ProbeKind::Fdt {
+                compatibles: &["test,other"],
+                on_probe: probe_other,
+            }],
+            ..DriverRegister::EMPTY
+        };
+        let sys = system(uarts());
+
+        sys.bind(&other, "/soc/uart@1000").unwrap();
+        assert!(matches!(
+            sys.bind(&uart, "/soc/uart@1000"),
+            Err(ProbeError::TargetBound(_))
+        ));
+        assert!(matches!(
+            sys.bind(&uart, "/soc/uart@3000"),
+            Err(ProbeError::TargetUnavailable(_))
+        ));
+        assert!(matches!(
+            sys.bind(&uart, "/soc/uart@4000"),
+            Err(ProbeError::TargetNotFound(_))
+        ));
+
+        // the bound node is skipped, the driver takes the next compatible one
+        let (res, pending) = sys.probe_register(&uart).unwrap();
+        assert_eq!(res.len(), 1);
+        assert!(pending.is_empty());
+        let (res, _) = sys.probe_register(&uart).unwrap();
+        assert!(res.is_empty());
+
+        assert_eq!(
+            *PROBED.lock(),
+            ["other /soc/uart@1000", "uart /soc/uart@2000"]
+        );
+    }
+
+    #[test]
+    fn test_bind_keeps_compatible_probe() {
+        static PROBED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+        fn probe_uart(fdt: FdtInfo<'_>, _dev: PlatformDevice) -> Result<(), OnProbeError> {
+            PROBED.lock().push(fdt.path().into());
+            Ok(())
+        }
+
+        let uart = DriverRegister {
+            name: "uart",
+            probe_kinds: &[ProbeKind::Fdt {
+                compatibles: &["test,uart"],
+                on_probe: probe_uart,
+            }],
+            ..DriverRegister::EMPTY
+        };
+        let sys = system(uarts());
+
+        sys.bind(&uart, "/soc/uart@2000").unwrap();
+        sys.probe_register(&uart).unwrap();
+
+        assert_eq!(*PROBED.lock(), ["/soc/uart@2000", "/soc/uart@1000"]);
+    }
+
+    #[test]
+    fn test_policy_forces_driver() {
+        static PROBED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+        fn probe_rtc(fdt: FdtInfo<'_>, _dev: PlatformDevice) -> Result<(), OnProbeError> {
+            PROBED.lock().push(fdt.path().into());
+            Ok(())
+        }
+
+        let rtc = DriverRegister {
+            name: "forced rtc",
+            probe_kinds: &[ProbeKind::Fdt {
+                compatibles: &["test,other-rtc"],
+                on_probe: probe_rtc,
+            }],
+            ..DriverRegister::EMPTY
+        };
+        let sys = system(Tree {
+            root: node(
+                "",
+                &[],
+                vec![node(
+                    "soc",
+                    &[],
+                    vec![node("rtc@6000", &[("compatible", "test,rtc")], vec![])],
+                )],
+            ),
+            ..Default::default()
+        });
+        crate::set_bind_policy(
+            crate::policy::BindPolicy::parse(
+                r#"rdrive.allow="driver:forced rtc+path:/soc/rtc@6000""#,
+            )
+            .unwrap(),
+        );
+
+        let (res, _) = sys.probe_register(&rtc).unwrap();
+        assert!(matches!(res[..], [(_, Ok(()))]));
+        let (res, _) = sys.probe_register(&rtc).unwrap();
+        assert!(res.is_empty());
+
+        assert_eq!(*PROBED.lock(), ["/soc/rtc@6000"]);
+    }
+
+    /// Pending on its first poll.
+    fn yield_now() -> impl Future<Output = ()> {
+        let mut yielded = false;
+        core::future::poll_fn(move |_| {
+            if yielded {
+                return core::task::Poll::Ready(());
+            }
+            yielded = true;
+            core::task::Poll::Pending
+        })
+    }
+
+    #[test]
+    fn test_async_binds_first_node() {
+        static PROBED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+        fn probe_uart(fdt: FdtInfo<'static>, _dev: PlatformDevice) -> ProbeFuture {
+            Box::pin(async move {
+                PROBED.lock().push(format!("uart {}", fdt.path()));
+                Ok(())
+            })
+        }
+        fn probe_picky(fdt: FdtInfo<'static>, _dev: PlatformDevice) -> ProbeFuture {
+            Box::pin(async move {
+                PROBED.lock().push(format!("picky {}", fdt.path()));
+                if fdt.path().ends_with("@1000") {
+                    return Err(OnProbeError::NotMatch);
+                }
+                Ok(())
+            })
+        }
+
+        for (name, on_probe) in [
+            ("uart", probe_uart as FnOnProbeAsync),
+            ("picky", probe_picky),
+        ] {
+            let kinds = vec![ProbeKind::FdtAsync {
+                compatibles: &["test,uart"],
+                on_probe,
+            }]
+            .leak();
+            let register = DriverRegister {
+                name,
+                probe_kinds: kinds,
+                ..DriverRegister::EMPTY
+            };
+            let sys = system(uarts());
+            let (res, pending) = sys.probe_register(&register).unwrap();
+            assert!(res.is_empty());
+            assert_eq!(pending.len(), 1);
+            sys.run_async(pending);
+            let (res, pending) = sys.probe_register(&register).unwrap();
+            assert!(res.is_empty() && pending.is_empty());
+        }
+
+        assert_eq!(
+            *PROBED.lock(),
+            [
+                "uart /soc/uart@1000",
+                "picky /soc/uart@1000",
+                "picky /soc/uart@2000"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_probe_system_batches_priorities() {
+        static EVENTS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+        fn probe_sync(fdt: FdtInfo<'_>, _dev: PlatformDevice) -> Result<(), OnProbeError> {
+            EVENTS.lock().push(fdt.node.name().into());
+            Ok(())
+        }
+        fn probe_async(fdt: FdtInfo<'static>, _dev: PlatformDevice) -> ProbeFuture {
+            Box::pin(async move {
+                EVENTS.lock().push(fdt.node.name().into());
+                yield_now().await;
+                EVENTS.lock().push("done".into());
+                Ok(())
+            })
+        }
+
+        let tree = Tree {
+            root: node(
+                "",
+                &[],
+                vec![
+                    node("a1", &[("compatible", "batch,a1")], vec![]),
+                    node("a2", &[("compatible", "batch,a2")], vec![]),
+                    node("b", &[("compatible", "batch,b")], vec![]),
+                    node("c", &[("compatible", "batch,c")], vec![]),
+                ],
+            ),
+            ..Default::default()
+        };
+        SYSTEM.call_once(|| system(tree));
+
+        let register = |name, priority, kind| DriverRegister {
+            name,
+            priority: ProbePriority(priority),
+            probe_kinds: vec![kind].leak(),
+            ..DriverRegister::EMPTY
+        };
+        let registers = [
+            register(
+                "a1",
+                1,
+                ProbeKind::FdtAsync {
+                    compatibles: &["batch,a1"],
+                    on_probe: probe_async,
+                },
+            ),
+            register(
+                "a2",
+                1,
+                ProbeKind::FdtAsync {
+                    compatibles: &["batch,a2"],
+                    on_probe: probe_async,
+                },
+            ),
+            register(
+                "b",
+                1,
+                ProbeKind::Fdt {
+                    compatibles: &["batch,b"],
+                    on_probe: probe_sync,
+                },
+            ),
+            register(
+                "c",
+                2,
+                ProbeKind::Fdt {
+                    compatibles: &["batch,c"],
+                    on_probe: probe_sync,
+                },
+            ),
+        ];
+        crate::probe_system(registers.iter(), true).unwrap();
+
+        // the async probes of priority 1 run together once its sync ones
+        // are done, before priority 2 starts
+        assert_eq!(*EVENTS.lock(), ["b", "a1", "a2", "done", "done", "c"]);
+    }
 }
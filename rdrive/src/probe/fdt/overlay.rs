@@ -0,0 +1,715 @@
+//! Device tree overlay application.
+//!
+//! The base tree and the overlay are copied into an owned [`Tree`], merged the
+//! way libfdt's `fdt_overlay_apply` does, and written back into a new DTB:
+//!
+//! 1. every phandle of the overlay is shifted above the largest base phandle,
+//!    and the references listed in `__local_fixups__` follow;
+//! 2. references to base labels listed in `__fixups__` are resolved through
+//!    the base `__symbols__`;
+//! 3. the `__overlay__` node of each fragment is merged into its `target` or
+//!    `target-path`;
+//! 4. labels of the overlay are added to the base `__symbols__`.
+
+use alloc::{
+    collections::btree_map::BTreeMap,
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+
+use fdt_parser::Fdt;
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_END: u32 = 9;
+const HEADER_SIZE: usize = 40;
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum OverlayError {
+    #[error("fdt parse error: {0}")]
+    Fdt(String),
+    #[error("overlay has no fragment")]
+    NoFragment,
+    #[error("fragment `{0}` has no `target` or `target-path`")]
+    NoTarget(String),
+    #[error("fragment target `{0}` not found")]
+    TargetNotFound(String),
+    #[error("label `{0}` not found in base `__symbols__`")]
+    SymbolNotFound(String),
+    #[error("node `{0}` has no phandle")]
+    NoPhandle(String),
+    #[error("bad fixup `{0}`")]
+    BadFixup(String),
+    #[error("phandle {0:#x} overflows when relocated")]
+    PhandleOverflow(u32),
+}
+
+impl From<fdt_parser::FdtError<'_>> for OverlayError {
+    fn from(value: fdt_parser::FdtError<'_>) -> Self {
+        Self::Fdt(format!("{value:?}"))
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct TreeNode {
+    pub name: String,
+    pub props: Vec<(String, Vec<u8>)>,
+    pub children: Vec<TreeNode>,
+}
+
+impl TreeNode {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn prop(&self, name: &str) -> Option<&[u8]> {
+        self.props
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_slice())
+    }
+
+    fn prop_mut(&mut self, name: &str) -> Option<&mut Vec<u8>> {
+        self.props
+            .iter_mut()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v)
+    }
+
+    pub fn set_prop(&mut self, name: &str, value: Vec<u8>) {
+        match self.prop_mut(name) {
+            Some(old) => *old = value,
+            None => self.props.push((name.to_string(), value)),
+        }
+    }
+
+    fn prop_str(&self, name: &str) -> Option<&str> {
+        let raw = self.prop(name)?;
+        let end = raw.iter().position(|&b| b == 0).unwrap_or(raw.len());
+        core::str::from_utf8(&raw[..end]).ok()
+    }
+
+    fn phandle(&self) -> Option<u32> {
+        self.prop("phandle")
+            .or_else(|| self.prop("linux,phandle"))
+            .and_then(|raw| read_cell(raw, 0))
+    }
+
+    /// Whether `status` is absent, `okay` or `ok`.
+    fn is_available(&self) -> bool {
+        self.prop("status").is_none() || matches!(self.prop_str("status"), Some("okay" | "ok"))
+    }
+
+    fn child(&self, name: &str) -> Option<&TreeNode> {
+        self.children.iter().find(|c| c.name == name)
+    }
+
+    fn child_mut_or_insert(&mut self, name: &str) -> &mut TreeNode {
+        match self.children.iter().position(|c| c.name == name) {
+            Some(i) => &mut self.children[i],
+            None => {
+                self.children.push(TreeNode::new(name));
+                self.children.last_mut().unwrap()
+            }
+        }
+    }
+
+    fn find(&self, path: &str) -> Option<&TreeNode> {
+        path.split('/')
+            .filter(|s| !s.is_empty())
+            .try_fold(self, |node, name| node.child(name))
+    }
+
+    fn find_mut(&mut self, path: &str) -> Option<&mut TreeNode> {
+        let mut node = self;
+        for name in path.split('/').filter(|s| !s.is_empty()) {
+            node = node.children.iter_mut().find(|c| c.name == name)?;
+        }
+        Some(node)
+    }
+
+    /// Visit every node with its path, parents first.
+    fn walk<'a>(&'a self, path: &str, f: &mut impl FnMut(&str, &'a TreeNode)) {
+        f(path, self);
+        for child in &self.children {
+            child.walk(&join(path, &child.name), f);
+        }
+    }
+
+    fn walk_mut(&mut self, f: &mut impl FnMut(&mut TreeNode)) {
+        f(self);
+        for child in &mut self.children {
+            child.walk_mut(f);
+        }
+    }
+}
+
+fn join(parent: &str, name: &str) -> String {
+    if parent == "/" {
+        format!("/{name}")
+    } else {
+        format!("{parent}/{name}")
+    }
+}
+
+fn read_cell(raw: &[u8], offset: usize) -> Option<u32> {
+    let bytes = raw.get(offset..offset + 4)?;
+    Some(u32::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+fn write_cell(raw: &mut [u8], offset: usize, value: u32) -> Option<()> {
+    raw.get_mut(offset..offset + 4)?
+        .copy_from_slice(&value.to_be_bytes());
+    Some(())
+}
+
+fn str_list(raw: &[u8]) -> impl Iterator<Item = &str> {
+    raw.split(|&b| b == 0)
+        .filter(|s| !s.is_empty())
+        .filter_map(|s| core::str::from_utf8(s).ok())
+}
+
+/// An owned, editable copy of a flattened device tree.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Tree {
+    pub boot_cpuid_phys: u32,
+    /// Memory reservation block, `(address, size)`.
+    pub reserved: Vec<(u64, u64)>,
+    pub root: TreeNode,
+}
+
+impl Tree {
+    pub fn from_fdt(fdt: &Fdt<'_>) -> Self {
+        let mut stack: Vec<TreeNode> = Vec::new();
+        let mut root = None;
+
+        let mut close = |stack: &mut Vec<TreeNode>| {
+            let node = stack.pop().unwrap();
+            match stack.last_mut() {
+                Some(parent) => parent.children.push(node),
+                None => root = Some(node),
+            }
+        };
+
+        for node in fdt.all_nodes() {
+            while stack.len() >= node.level.max(1) {
+                close(&mut stack);
+            }
+            // the parser names the root `/`, the blob stores it empty
+            let name = if stack.is_empty() { "" } else { node.name() };
+            let mut owned = TreeNode::new(name);
+            for prop in node.propertys() {
+                owned
+                    .props
+                    .push((prop.name.to_string(), prop.raw_value().to_vec()));
+            }
+            stack.push(owned);
+        }
+        while !stack.is_empty() {
+            close(&mut stack);
+        }
+
+        Self {
+            boot_cpuid_phys: fdt.boot_cpuid_phys(),
+            reserved: fdt
+                .memory_reservation_block()
+                .map(|r| (r.address as u64, r.size as u64))
+                .collect(),
+            root: root.unwrap_or_default(),
+        }
+    }
+
+    fn max_phandle(&self) -> u32 {
+        let mut max = 0;
+        self.root.walk("/", &mut |_, node| {
+            if let Some(phandle) = node.phandle() {
+                max = max.max(phandle);
+            }
+        });
+        max
+    }
+
+    fn path_of_phandle(&self, phandle: u32) -> Option<String> {
+        let mut found = None;
+        self.root.walk("/", &mut |path, node| {
+            if found.is_none() && node.phandle() == Some(phandle) {
+                found = Some(path.to_string());
+            }
+        });
+        found
+    }
+
+    /// Resolve `path`, which may start with an alias instead of `/`.
+    fn resolve_path(&self, path: &str) -> Option<String> {
+        if path.starts_with('/') {
+            return self.root.find(path).map(|_| path.to_string());
+        }
+        let (alias, rest) = path.split_once('/').unwrap_or((path, ""));
+        let base = self.root.find("/aliases")?.prop_str(alias)?;
+        let full = if rest.is_empty() {
+            base.to_string()
+        } else {
+            join(base, rest)
+        };
+        self.root.find(&full).map(|_| full)
+    }
+
+    /// Serialize into a version 17 DTB.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut strings = Vec::new();
+        let mut string_offsets = BTreeMap::new();
+        let mut structure = Vec::new();
+        write_node(
+            &self.root,
+            &mut structure,
+            &mut strings,
+            &mut string_offsets,
+        );
+        push_u32(&mut structure, FDT_END);
+
+        let off_mem_rsvmap = HEADER_SIZE.next_multiple_of(8);
+        let off_dt_struct = off_mem_rsvmap + (self.reserved.len() + 1) * 16;
+        let off_dt_strings = off_dt_struct + structure.len();
+        let total = off_dt_strings + strings.len();
+
+        let mut out = Vec::with_capacity(total);
+        for v in [
+            FDT_MAGIC,
+            total as u32,
+            off_dt_struct as u32,
+            off_dt_strings as u32,
+            off_mem_rsvmap as u32,
+            17,
+            16,
+            self.boot_cpuid_phys,
+            strings.len() as u32,
+            structure.len() as u32,
+        ] {
+            push_u32(&mut out, v);
+        }
+        out.resize(off_mem_rsvmap, 0);
+        for &(address, size) in self.reserved.iter().chain([(0, 0)].iter()) {
+            out.extend_from_slice(&address.to_be_bytes());
+            out.extend_from_slice(&size.to_be_bytes());
+        }
+        out.extend_from_slice(&structure);
+        out.extend_from_slice(&strings);
+        out
+    }
+}
+
+fn push_u32(out: &mut Vec<u8>, v: u32) {
+    out.extend_from_slice(&v.to_be_bytes());
+}
+
+fn pad4(out: &mut Vec<u8>) {
+    out.resize(out.len().next_multiple_of(4), 0);
+}
+
+fn write_node(
+    node: &TreeNode,
+    out: &mut Vec<u8>,
+    strings: &mut Vec<u8>,
+    string_offsets: &mut BTreeMap<String, u32>,
+) {
+    push_u32(out, FDT_BEGIN_NODE);
+    out.extend_from_slice(node.name.as_bytes());
+    out.push(0);
+    pad4(out);
+
+    for (name, value) in &node.props {
+        let name_off = *string_offsets.entry(name.clone()).or_insert_with(|| {
+            let off = strings.len() as u32;
+            strings.extend_from_slice(name.as_bytes());
+            strings.push(0);
+            off
+        });
+        push_u32(out, FDT_PROP);
+        push_u32(out, value.len() as u32);
+        push_u32(out, name_off);
+        out.extend_from_slice(value);
+        pad4(out);
+    }
+
+    for child in &node.children {
+        write_node(child, out, strings, string_offsets);
+    }
+    push_u32(out, FDT_END_NODE);
+}
+
+/// Shift phandle definitions of the overlay and their local references by `delta`.
+fn relocate_phandles(overlay: &mut TreeNode, delta: u32) -> Result<(), OverlayError> {
+    let mut res = Ok(());
+    overlay.walk_mut(&mut |node| {
+        for name in ["phandle", "linux,phandle"] {
+            if let Some(raw) = node.prop_mut(name)
+                && let Some(v) = read_cell(raw, 0)
+            {
+                match v.checked_add(delta) {
+                    Some(relocated) => {
+                        write_cell(raw, 0, relocated);
+                    }
+                    None => res = Err(OverlayError::PhandleOverflow(v)),
+                }
+            }
+        }
+    });
+    res?;
+
+    let Some(fixups) = overlay.child("__local_fixups__").cloned() else {
+        return Ok(());
+    };
+    apply_local_fixups(overlay, &fixups, "/", delta)
+}
+
+fn apply_local_fixups(
+    node: &mut TreeNode,
+    fixups: &TreeNode,
+    path: &str,
+    delta: u32,
+) -> Result<(), OverlayError> {
+    for (prop, offsets) in &fixups.props {
+        let raw = node
+            .prop_mut(prop)
+            .ok_or_else(|| OverlayError::BadFixup(format!("{path}:{prop}")))?;
+        for off in offsets.as_chunks::<4>().0 {
+            let off = u32::from_be_bytes(*off) as usize;
+            let v = read_cell(raw, off)
+                .ok_or_else(|| OverlayError::BadFixup(format!("{path}:{prop}:{off}")))?;
+            let relocated = v
+                .checked_add(delta)
+                .ok_or(OverlayError::PhandleOverflow(v))?;
+            write_cell(raw, off, relocated);
+        }
+    }
+    for child_fixups in &fixups.children {
+        let child_path = join(path, &child_fixups.name);
+        let child = node
+            .children
+            .iter_mut()
+            .find(|c| c.name == child_fixups.name)
+            .ok_or_else(|| OverlayError::BadFixup(child_path.clone()))?;
+        apply_local_fixups(child, child_fixups, &child_path, delta)?;
+    }
+    Ok(())
+}
+
+/// Patch references to base labels listed in `__fixups__`.
+fn apply_external_fixups(base: &Tree, overlay: &mut TreeNode) -> Result<(), OverlayError> {
+    let Some(fixups) = overlay.child("__fixups__").cloned() else {
+        return Ok(());
+    };
+    for (label, locations) in &fixups.props {
+        let target = base
+            .root
+            .find("/__symbols__")
+            .and_then(|symbols| symbols.prop_str(label))
+            .ok_or_else(|| OverlayError::SymbolNotFound(label.clone()))?;
+        let phandle = base
+            .root
+            .find(target)
+            .and_then(TreeNode::phandle)
+            .ok_or_else(|| OverlayError::NoPhandle(target.to_string()))?;
+
+        for location in str_list(locations) {
+            let bad = || OverlayError::BadFixup(location.to_string());
+            let mut parts = location.rsplitn(3, ':');
+            let offset = parts
+                .next()
+                .and_then(|s| s.parse::<usize>().ok())
+                .ok_or_else(bad)?;
+            let prop = parts.next().ok_or_else(bad)?;
+            let path = parts.next().ok_or_else(bad)?;
+            let raw = overlay
+                .find_mut(path)
+                .and_then(|node| node.prop_mut(prop))
+                .ok_or_else(bad)?;
+            write_cell(raw, offset, phandle).ok_or_else(bad)?;
+        }
+    }
+    Ok(())
+}
+
+/// Merge `from` into `into`, recording the path of every node that needs a
+/// probe: nodes the overlay creates and nodes it turns `okay`.
+///
+/// A node that only gains properties or children keeps its driver and is not
+/// recorded.
+fn merge(
+    into: &mut TreeNode,
+    from: &TreeNode,
+    path: &str,
+    created: bool,
+    touched: &mut Vec<String>,
+) {
+    let was_available = into.is_available();
+    for (name, value) in &from.props {
+        into.set_prop(name, value.clone());
+    }
+    if created || (!was_available && into.is_available()) {
+        touched.push(path.to_string());
+    }
+    for child in &from.children {
+        let child_path = join(path, &child.name);
+        let created = into.child(&child.name).is_none();
+        merge(
+            into.child_mut_or_insert(&child.name),
+            child,
+            &child_path,
+            created,
+            touched,
+        );
+    }
+}
+
+/// Apply `overlay` to `base`.
+///
+/// Returns the paths of the base nodes added or enabled.
+pub(crate) fn apply(base: &mut Tree, overlay: Tree) -> Result<Vec<String>, OverlayError> {
+    let mut overlay = overlay.root;
+    relocate_phandles(&mut overlay, base.max_phandle())?;
+    apply_external_fixups(base, &mut overlay)?;
+
+    let mut targets = BTreeMap::new();
+    let mut touched = Vec::new();
+    for fragment in &overlay.children {
+        let Some(content) = fragment.child("__overlay__") else {
+            continue;
+        };
+        let target = if let Some(phandle) = fragment.prop("target").and_then(|r| read_cell(r, 0)) {
+            base.path_of_phandle(phandle)
+                .ok_or_else(|| OverlayError::TargetNotFound(format!("<{phandle:#x}>")))?
+        } else if let Some(path) = fragment.prop_str("target-path") {
+            base.resolve_path(path)
+                .ok_or_else(|| OverlayError::TargetNotFound(path.to_string()))?
+        } else {
+            return Err(OverlayError::NoTarget(fragment.name.clone()));
+        };
+
+        let node = base
+            .root
+            .find_mut(&target)
+            .ok_or_else(|| OverlayError::TargetNotFound(target.clone()))?;
+        merge(node, content, &target, false, &mut touched);
+        targets.insert(fragment.name.clone(), target);
+    }
+    if targets.is_empty() {
+        return Err(OverlayError::NoFragment);
+    }
+
+    if let Some(symbols) = overlay.child("__symbols__") {
+        for (label, value) in &symbols.props {
+            let Some(path) = str_list(value).next() else {
+                continue;
+            };
+            // `/fragment@0/__overlay__/node` lands at `<target>/node`
+            let mut parts = path.trim_start_matches('/').splitn(3, '/');
+            let (Some(fragment), Some("__overlay__")) = (parts.next(), parts.next()) else {
+                continue;
+            };
+            let Some(target) = targets.get(fragment) else {
+                continue;
+            };
+            let resolved = match parts.next() {
+                Some(rest) => join(target, rest),
+                None => target.clone(),
+            };
+            let mut value = resolved.into_bytes();
+            value.push(0);
+            base.root
+                .child_mut_or_insert("__symbols__")
+                .set_prop(label, value);
+        }
+    }
+
+    Ok(touched)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cell(v: u32) -> Vec<u8> {
+        v.to_be_bytes().to_vec()
+    }
+
+    fn string(s: &str) -> Vec<u8> {
+        let mut v = s.as_bytes().to_vec();
+        v.push(0);
+        v
+    }
+
+    fn node(name: &str, props: &[(&str, Vec<u8>)], children: Vec<TreeNode>) -> TreeNode {
+        TreeNode {
+            name: name.to_string(),
+            props: props
+                .iter()
+                .map(|(n, v)| (n.to_string(), v.clone()))
+                .collect(),
+            children,
+        }
+    }
+
+    fn base() -> Tree {
+        Tree {
+            boot_cpuid_phys: 0,
+            reserved: vec![(0x4000_0000, 0x1000)],
+            root: node(
+                "",
+                &[("#address-cells", cell(2))],
+                vec![
+                    node(
+                        "soc",
+                        &[],
+                        vec![
+                            node("intc@8000000", &[("phandle", cell(1))], vec![]),
+                            node(
+                                "i2c@9000000",
+                                &[("phandle", cell(2)), ("status", string("disabled"))],
+                                vec![],
+                            ),
+                        ],
+                    ),
+                    node(
+                        "__symbols__",
+                        &[("i2c0", string("/soc/i2c@9000000"))],
+                        vec![],
+                    ),
+                ],
+            ),
+        }
+    }
+
+    /// ```dts
+    /// &i2c0 {
+    ///     status = "okay";
+    ///     eeprom: eeprom@50 { phandle = <1>; };
+    ///     rtc@68 { wakeup-source = <&eeprom>; };
+    /// };
+    /// ```
+    fn overlay() -> Tree {
+        Tree {
+            root: node(
+                "",
+                &[],
+                vec![
+                    node(
+                        "fragment@0",
+                        &[("target", cell(0xffff_ffff))],
+                        vec![node(
+                            "__overlay__",
+                            &[("status", string("okay"))],
+                            vec![
+                                node("eeprom@50", &[("phandle", cell(1))], vec![]),
+                                node("rtc@68", &[("wakeup-source", cell(1))], vec![]),
+                            ],
+                        )],
+                    ),
+                    node(
+                        "__symbols__",
+                        &[("eeprom", string("/fragment@0/__overlay__/eeprom@50"))],
+                        vec![],
+                    ),
+                    node(
+                        "__fixups__",
+                        &[("i2c0", string("/fragment@0:target:0"))],
+                        vec![],
+                    ),
+                    node(
+                        "__local_fixups__",
+                        &[],
+                        vec![node(
+                            "fragment@0",
+                            &[],
+                            vec![node(
+                                "__overlay__",
+                                &[],
+                                vec![node("rtc@68", &[("wakeup-source", cell(0))], vec![])],
+                            )],
+                        )],
+                    ),
+                ],
+            ),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let tree = base();
+        let bytes = tree.to_bytes();
+        let fdt = Fdt::from_bytes(&bytes).unwrap();
+        assert_eq!(Tree::from_fdt(&fdt), tree);
+    }
+
+    #[test]
+    fn test_apply() {
+        let mut tree = base();
+        let touched = apply(&mut tree, overlay()).unwrap();
+        assert_eq!(
+            touched,
+            [
+                "/soc/i2c@9000000",
+                "/soc/i2c@9000000/eeprom@50",
+                "/soc/i2c@9000000/rtc@68",
+            ]
+        );
+
+        let i2c = tree.root.find("/soc/i2c@9000000").unwrap();
+        assert_eq!(i2c.prop_str("status"), Some("okay"));
+        assert_eq!(i2c.phandle(), Some(2));
+
+        let eeprom = tree.root.find("/soc/i2c@9000000/eeprom@50").unwrap();
+        assert_eq!(eeprom.phandle(), Some(3));
+        let rtc = tree.root.find("/soc/i2c@9000000/rtc@68").unwrap();
+        assert_eq!(rtc.prop("wakeup-source"), Some(cell(3).as_slice()));
+
+        assert_eq!(
+            tree.root.find("/__symbols__").unwrap().prop_str("eeprom"),
+            Some("/soc/i2c@9000000/eeprom@50")
+        );
+    }
+
+    #[test]
+    fn test_apply_to_bound_target() {
+        let mut tree = base();
+        tree.root
+            .find_mut("/soc/i2c@9000000")
+            .unwrap()
+            .set_prop("status", string("okay"));
+        let touched = apply(&mut tree, overlay()).unwrap();
+        assert_eq!(
+            touched,
+            ["/soc/i2c@9000000/eeprom@50", "/soc/i2c@9000000/rtc@68"]
+        );
+    }
+
+    #[test]
+    fn test_phandle_overflow() {
+        let mut tree = base();
+        tree.root
+            .find_mut("/soc/intc@8000000")
+            .unwrap()
+            .set_prop("phandle", cell(0xffff_ffff));
+        assert_eq!(
+            apply(&mut tree, overlay()),
+            Err(OverlayError::PhandleOverflow(1))
+        );
+    }
+
+    #[test]
+    fn test_missing_symbol() {
+        let mut tree = base();
+        tree.root.children.retain(|c| c.name != "__symbols__");
+        assert_eq!(
+            apply(&mut tree, overlay()),
+            Err(OverlayError::SymbolNotFound("i2c0".into()))
+        );
+    }
+}
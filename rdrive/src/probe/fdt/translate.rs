@@ -0,0 +1,244 @@
+//! Address translation through `ranges` and `dma-ranges`.
+
+use alloc::{format, string::String, vec::Vec};
+use core::ops::Range;
+
+use fdt_parser::{Fdt, Node};
+
+use super::nodes_with_path;
+
+/// A window through which a device reaches memory by DMA.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DmaRange {
+    /// Address the device puts on its bus.
+    pub bus_address: u64,
+    /// CPU physical address reached through `bus_address`.
+    pub cpu_address: u64,
+    pub size: u64,
+}
+
+impl DmaRange {
+    pub fn bus_to_cpu(&self, bus_address: u64) -> Option<u64> {
+        let offset = bus_address.checked_sub(self.bus_address)?;
+        (offset < self.size).then(|| self.cpu_address + offset)
+    }
+
+    pub fn cpu_to_bus(&self, cpu_address: u64) -> Option<u64> {
+        let offset = cpu_address.checked_sub(self.cpu_address)?;
+        (offset < self.size).then(|| self.bus_address + offset)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Cells {
+    address: usize,
+    size: usize,
+}
+
+fn cells(node: &Node<'_>) -> Cells {
+    let read = |name: &str, default: usize| {
+        node.find_property(name)
+            .and_then(|prop| prop.u32_list().next())
+            .map_or(default, |v| v as usize)
+    };
+    Cells {
+        address: read("#address-cells", 2),
+        size: read("#size-cells", 1),
+    }
+}
+
+/// Fold big-endian cells into a number.
+///
+/// PCI addresses carry a `phys.hi` flags cell in front, only the low 64 bits
+/// form the address.
+fn read_number(cells: &[u32]) -> u64 {
+    let skip = cells.len().saturating_sub(2);
+    cells[skip..]
+        .iter()
+        .fold(0, |acc, &cell| (acc << 32) | cell as u64)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Mapping {
+    child: u64,
+    parent: u64,
+    size: u64,
+}
+
+/// Entries of a `ranges`-like property of `bus`.
+///
+/// `None` if the property is missing, an empty list for an identity mapping.
+fn mappings(bus: &Node<'_>, parent: &Node<'_>, name: &str) -> Option<Vec<Mapping>> {
+    let prop = bus.find_property(name)?;
+    let child_cells = cells(bus);
+    let parent_address = cells(parent).address;
+    let raw = prop.u32_list().collect::<Vec<_>>();
+    let entry_len = child_cells.address + parent_address + child_cells.size;
+    if entry_len == 0 {
+        return Some(Vec::new());
+    }
+
+    Some(
+        raw.chunks_exact(entry_len)
+            .map(|entry| {
+                let (child, rest) = entry.split_at(child_cells.address);
+                let (parent, size) = rest.split_at(parent_address);
+                Mapping {
+                    child: read_number(child),
+                    parent: read_number(parent),
+                    size: read_number(size),
+                }
+            })
+            .collect(),
+    )
+}
+
+fn translate(address: u64, mappings: &[Mapping]) -> Option<u64> {
+    if mappings.is_empty() {
+        return Some(address);
+    }
+    mappings.iter().find_map(|m| {
+        let offset = address.checked_sub(m.child)?;
+        (offset < m.size).then(|| m.parent + offset)
+    })
+}
+
+/// Nodes from `/` down to the node at `path`, inclusive.
+pub(crate) fn ancestors<'a>(fdt: &Fdt<'a>, path: &str) -> Vec<Node<'a>> {
+    nodes_with_path(fdt)
+        .filter(|(p, _)| {
+            p == "/"
+                || path == p
+                || (path.starts_with(p.as_str()) && path[p.len()..].starts_with('/'))
+        })
+        .map(|(_, node)| node)
+        .collect()
+}
+
+/// Translate `address` from the bus of `chain[bus]` up to the CPU physical
+/// address space, following `property` at each level.
+fn translate_up(
+    chain: &[Node<'_>],
+    bus: usize,
+    address: u64,
+    property: &str,
+    missing_is_identity: bool,
+) -> Result<u64, String> {
+    let mut address = address;
+    for i in (1..=bus).rev() {
+        let node = &chain[i];
+        match mappings(node, &chain[i - 1], property) {
+            Some(ls) => {
+                address = translate(address, &ls).ok_or_else(|| {
+                    format!("{address:#x} is outside `{property}` of [{}]", node.name())
+                })?;
+            }
+            None if missing_is_identity => {}
+            None => return Err(format!("[{}] has no `{property}`", node.name())),
+        }
+    }
+    Ok(address)
+}
+
+/// CPU physical ranges of the `reg` entries of the last node in `chain`.
+pub(crate) fn reg_ranges(chain: &[Node<'_>]) -> Result<Vec<Range<u64>>, String> {
+    let Some((node, parents)) = chain.split_last() else {
+        return Ok(Vec::new());
+    };
+    let Some(reg) = node.find_property("reg") else {
+        return Ok(Vec::new());
+    };
+    let cell = parents.last().map(cells).unwrap_or(Cells {
+        address: 2,
+        size: 1,
+    });
+    let raw = reg.u32_list().collect::<Vec<_>>();
+    let entry_len = cell.address + cell.size;
+    if entry_len == 0 {
+        return Ok(Vec::new());
+    }
+
+    let mut out = Vec::new();
+    for entry in raw.chunks_exact(entry_len) {
+        let (address, size) = entry.split_at(cell.address);
+        let address = translate_up(
+            chain,
+            parents.len().saturating_sub(1),
+            read_number(address),
+            "ranges",
+            false,
+        )?;
+        out.push(address..address + read_number(size));
+    }
+    Ok(out)
+}
+
+/// DMA windows of the last node in `chain`, from the nearest non-empty
+/// `dma-ranges` of the node or its parents.
+pub(crate) fn dma_ranges(chain: &[Node<'_>]) -> Result<Vec<DmaRange>, String> {
+    for i in (1..chain.len()).rev() {
+        let Some(ls) = mappings(&chain[i], &chain[i - 1], "dma-ranges") else {
+            continue;
+        };
+        if ls.is_empty() {
+            continue;
+        }
+
+        let mut out = Vec::new();
+        for m in ls {
+            out.push(DmaRange {
+                bus_address: m.child,
+                cpu_address: translate_up(chain, i - 1, m.parent, "dma-ranges", true)?,
+                size: m.size,
+            });
+        }
+        return Ok(out);
+    }
+    Ok(Vec::new())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_number() {
+        assert_eq!(read_number(&[]), 0);
+        assert_eq!(read_number(&[0x1000]), 0x1000);
+        assert_eq!(read_number(&[0x1, 0x2000]), 0x1_0000_2000);
+        assert_eq!(read_number(&[0x0200_0000, 0x0, 0x1000_0000]), 0x1000_0000);
+    }
+
+    #[test]
+    fn test_translate() {
+        let ls = [
+            Mapping {
+                child: 0x0,
+                parent: 0xc00_0000,
+                size: 0x200_0000,
+            },
+            Mapping {
+                child: 0x1000_0000,
+                parent: 0x4_0000_0000,
+                size: 0x1000,
+            },
+        ];
+        assert_eq!(translate(0x100, &ls), Some(0xc00_0100));
+        assert_eq!(translate(0x1000_0fff, &ls), Some(0x4_0000_0fff));
+        assert_eq!(translate(0x200_0000, &ls), None);
+        assert_eq!(translate(0x1234, &[]), Some(0x1234));
+    }
+
+    #[test]
+    fn test_dma_range() {
+        let r = DmaRange {
+            bus_address: 0x0,
+            cpu_address: 0x4000_0000,
+            size: 0x4000_0000,
+        };
+        assert_eq!(r.cpu_to_bus(0x4000_1000), Some(0x1000));
+        assert_eq!(r.bus_to_cpu(0x1000), Some(0x4000_1000));
+        assert_eq!(r.cpu_to_bus(0x1000), None);
+        assert_eq!(r.bus_to_cpu(0x4000_0000), None);
+    }
+}
@@ -6,8 +6,34 @@ use core::error::Error;
 
 use fdt_parser::FdtError;
 
+use crate::{DeviceId, GetDeviceError};
+
 pub mod fdt;
 pub mod pci;
+mod task;
+
+pub use pci::PciAddress;
+pub use task::{ProbeFuture, ProbeTask};
+
+/// Device a driver is bound to by [`bind`](crate::bind).
+#[derive(Debug, Clone, Copy)]
+pub enum BindTarget<'a> {
+    /// Full FDT node path, e.g. `/soc/serial@9000000`.
+    FdtPath(&'a str),
+    Pci(PciAddress),
+}
+
+impl<'a> From<&'a str> for BindTarget<'a> {
+    fn from(value: &'a str) -> Self {
+        Self::FdtPath(value)
+    }
+}
+
+impl From<PciAddress> for BindTarget<'_> {
+    fn from(value: PciAddress) -> Self {
+        Self::Pci(value)
+    }
+}
 
 #[derive(thiserror::Error, Debug)]
 pub enum ProbeError {
@@ -19,6 +45,21 @@ pub enum ProbeError {
     OnProbe(#[from] OnProbeError),
     #[error("open device fail")]
     OpenFail(#[from] rdif_base::KError),
+    #[error("driver `{0}` not registered")]
+    DriverNotFound(String),
+    #[error("driver `{name}` has no {kind} probe")]
+    ProbeKindMissing { name: String, kind: &'static str },
+    #[error("bind target `{0}` not found")]
+    TargetNotFound(String),
+    #[error("bind target `{0}` is disabled or reserved")]
+    TargetUnavailable(String),
+    #[error("bind target `{0}` is already bound")]
+    TargetBound(String),
+    #[error("PCIe function {address}: {source}")]
+    PciBar {
+        address: rdif_pcie::PciAddress,
+        source: pcie::BarAllocError,
+    },
 }
 
 impl From<FdtError<'_>> for ProbeError {
@@ -34,9 +75,15 @@ pub enum OnProbeError {
     #[error("kerror: {0}")]
     KError(#[from] rdif_base::KError),
     #[error("other error: {0}")]
-    Other(#[from] Box<dyn Error>),
+    Other(#[from] Box<dyn Error + Send + Sync>),
     #[error("fdt parse error: {0}")]
     Fdt(String),
+    #[error("irq parent {0:?}: {1}")]
+    IrqParent(DeviceId, GetDeviceError),
+    #[error("MSI: {0}")]
+    Msi(#[from] pcie::MsiError),
+    #[error("SR-IOV: {0}")]
+    Sriov(#[from] pcie::SriovError),
 }
 
 impl From<FdtError<'_>> for OnProbeError {
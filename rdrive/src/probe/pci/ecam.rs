@@ -0,0 +1,225 @@
+//! Built-in host driver for `pci-host-ecam-generic` controllers.
+//!
+//! Not registered by default, add it with
+//! `rdrive::register_add(rdrive::probe::pci::ecam::register())`.
+//!
+//! Memory windows of `ranges` must have the same CPU and PCI bus address;
+//! controllers that translate them are refused. The I/O window may be
+//! translated, see [`EndpointRc::bar_io_cpu`](super::EndpointRc::bar_io_cpu).
+//!
+//! Config space is mapped through [`Osal::ioremap`](crate::Osal::ioremap),
+//! the probe fails if it maps nothing.
+
+use core::{
+    ops::Range,
+    ptr::NonNull,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use alloc::vec::Vec;
+use fdt_parser::{Pci, PciSpace};
+
+use super::{
+    MsiMap, MsiMaps, PciIo, PciMem32, PciMem64, new_driver_generic, set_intx_node, set_msi_maps,
+};
+use crate::{
+    Phandle, PlatformDevice,
+    probe::OnProbeError,
+    register::{DriverRegister, FdtInfo, ProbeKind, ProbeLevel, ProbePriority},
+};
+
+/// Size of the ECAM config space of one bus.
+const BUS_SIZE: u64 = 1 << 20;
+
+pub fn register() -> DriverRegister {
+    DriverRegister {
+        name: "PCIe ECAM",
+        level: ProbeLevel::PostKernel,
+        priority: ProbePriority::DEFAULT,
+        probe_kinds: &[ProbeKind::Fdt {
+            compatibles: &["pci-host-ecam-generic"],
+            on_probe: probe,
+        }],
+        params: &[],
+    }
+}
+
+fn probe(info: FdtInfo<'_>, plat_dev: PlatformDevice) -> Result<(), OnProbeError> {
+    let reg = info
+        .mmio_ranges()?
+        .into_iter()
+        .next()
+        .ok_or_else(|| OnProbeError::other(format!("[{}] has no reg", info.path())))?;
+    let pci = Pci {
+        node: info.node.clone(),
+    };
+    let bus_range = bus_range(&pci, reg.end - reg.start);
+    if bus_range.is_empty() {
+        return Err(OnProbeError::other(format!(
+            "[{}] reg covers no bus of bus-range",
+            info.path()
+        )));
+    }
+
+    // `reg` starts at the first bus of `bus-range`, the generic driver
+    // computes offsets from bus 0.
+    let size = (reg.end - reg.start) as usize;
+    let base = crate::osal::ioremap(reg.start, size)
+        .and_then(|base| {
+            NonNull::new(
+                base.as_ptr()
+                    .wrapping_sub(bus_range.start * BUS_SIZE as usize),
+            )
+        })
+        .ok_or_else(|| OnProbeError::other(format!("map ECAM {:#x} fail", reg.start)))?;
+
+    let mut ctrl = new_driver_generic(base);
+    ctrl.set_bus_range(bus_range)?;
+    ctrl.set_segment(segment(&pci)?);
+
+    for range in pci.ranges()? {
+        let too_large = || {
+            OnProbeError::other(format!(
+                "[{}] window {:#x} size {:#x} does not fit {:?}",
+                info.path(),
+                range.cpu_address,
+                range.size,
+                range.space
+            ))
+        };
+        // BARs hold bus addresses while drivers map them as CPU addresses,
+        // translated memory windows are not supported
+        if matches!(range.space, PciSpace::Memory32 | PciSpace::Memory64)
+            && range.cpu_address != range.bus_address
+        {
+            return Err(OnProbeError::other(format!(
+                "[{}] window {:#x} is at bus address {:#x}, translated windows are not supported",
+                info.path(),
+                range.cpu_address,
+                range.bus_address
+            )));
+        }
+        match range.space {
+            PciSpace::IO => ctrl.set_io(PciIo {
+                address: range.bus_address.try_into().map_err(|_| too_large())?,
+                size: range.size.try_into().map_err(|_| too_large())?,
+                cpu_address: range.cpu_address,
+            }),
+            PciSpace::Memory32 => ctrl.set_mem32(
+                PciMem32 {
+                    address: range.bus_address.try_into().map_err(|_| too_large())?,
+                    size: range.size.try_into().map_err(|_| too_large())?,
+                },
+                range.prefetchable,
+            ),
+            PciSpace::Memory64 => ctrl.set_mem64(
+                PciMem64 {
+                    address: range.bus_address,
+                    size: range.size,
+                },
+                range.prefetchable,
+            ),
+            PciSpace::Configuration => {}
+        }
+    }
+
+    if let Some(maps) = msi_maps(&info)? {
+        set_msi_maps(plat_dev.descriptor.device_id, maps);
+    }
+    if info.node.find_property("interrupt-map").is_some() {
+        set_intx_node(plat_dev.descriptor.device_id, info.path());
+    }
+
+    plat_dev.register_pcie(ctrl);
+    Ok(())
+}
+
+/// MSI routing from `msi-map` and `msi-map-mask`, or from `msi-parent` for
+/// all requester IDs. The MSI controllers must already be probed, entries
+/// whose controller is not are dropped.
+fn msi_maps(info: &FdtInfo<'_>) -> Result<Option<MsiMaps>, OnProbeError> {
+    let parent = |phandle: u32| {
+        let id = info.phandle_to_device_id(Phandle::from(phandle));
+        if id.is_none() {
+            warn!("[{}] MSI controller {phandle:#x} not probed", info.path());
+        }
+        id
+    };
+
+    let Some(prop) = info.node.find_property("msi-map") else {
+        let Some(phandle) = info
+            .node
+            .find_property("msi-parent")
+            .and_then(|prop| prop.u32_list().next())
+        else {
+            return Ok(None);
+        };
+        let maps = parent(phandle)
+            .map(|parent| MsiMap {
+                rid_base: 0,
+                parent,
+                msi_base: 0,
+                len: 0x10000,
+            })
+            .into_iter()
+            .collect();
+        return Ok(Some(MsiMaps { mask: 0xffff, maps }));
+    };
+
+    let cells: Vec<u32> = prop.u32_list().collect();
+    let (entries, rest) = cells.as_chunks::<4>();
+    if !rest.is_empty() {
+        return Err(OnProbeError::Fdt(format!(
+            "[{}] msi-map has {} cells, not a multiple of 4",
+            info.path(),
+            cells.len()
+        )));
+    }
+    let maps = entries
+        .iter()
+        .filter_map(|&[rid_base, phandle, msi_base, len]| {
+            Some(MsiMap {
+                rid_base,
+                parent: parent(phandle)?,
+                msi_base,
+                len,
+            })
+        })
+        .collect();
+    let mask = info
+        .node
+        .find_property("msi-map-mask")
+        .and_then(|prop| prop.u32_list().next())
+        .unwrap_or(0xffff);
+    Ok(Some(MsiMaps { mask, maps }))
+}
+
+/// Segment from `linux,pci-domain`, host bridges without it are numbered
+/// after the highest segment seen so far, like Linux does.
+fn segment(pci: &Pci<'_>) -> Result<u16, OnProbeError> {
+    static NEXT: AtomicU32 = AtomicU32::new(0);
+
+    let Some(prop) = pci.node.find_property("linux,pci-domain") else {
+        let segment = NEXT.fetch_add(1, Ordering::SeqCst);
+        return u16::try_from(segment).map_err(|_| OnProbeError::other("out of PCI segments"));
+    };
+    let segment = prop
+        .u32_list()
+        .next()
+        .ok_or_else(|| OnProbeError::other("empty linux,pci-domain"))?;
+    NEXT.fetch_max(segment.saturating_add(1), Ordering::SeqCst);
+    u16::try_from(segment)
+        .map_err(|_| OnProbeError::other(format!("linux,pci-domain {segment} out of range")))
+}
+
+/// Buses to enumerate, from `bus-range` and clamped to the buses `reg` covers.
+fn bus_range(pci: &Pci<'_>, reg_size: u64) -> Range<usize> {
+    // `bus-range` is inclusive
+    let range = pci
+        .bus_range()
+        .map(|range| range.start..range.end + 1)
+        .unwrap_or(0..0x100);
+
+    let covered = (reg_size / BUS_SIZE) as usize;
+    range.start..range.end.min(range.start + covered).min(0x100)
+}
@@ -0,0 +1,171 @@
+//! Built-in MSI controller driver for `arm,gic-v2m-frame`.
+//!
+//! A frame turns writes to its doorbell into SPIs of the GIC it sits next
+//! to, the data written being the SPI number. The [`IrqConfig`]s it hands out
+//! are therefore interrupts of that GIC, to be enabled there.
+//!
+//! Not registered by default, add it with
+//! `rdrive::register_add(rdrive::probe::pci::gicv2m::register())`.
+
+use alloc::{vec, vec::Vec};
+use core::ptr::NonNull;
+
+use rdif_intc::{Intc, Interface, IrqConfig, IrqId, KError, MsiMessage, MsiVector, Trigger};
+
+use crate::{
+    DriverGeneric, PlatformDevice,
+    probe::OnProbeError,
+    register::{DriverRegister, FdtInfo, ProbeKind, ProbeLevel, ProbePriority},
+};
+
+/// Offset of `MSI_TYPER`: base SPI in bits 16..26, SPI count in bits 0..10.
+const MSI_TYPER: usize = 0x008;
+/// Offset of `MSI_SETSPI_NS`, the doorbell.
+const MSI_SETSPI_NS: u64 = 0x040;
+/// Size of a frame.
+const FRAME_SIZE: usize = 0x1000;
+
+pub fn register() -> DriverRegister {
+    DriverRegister {
+        name: "GICv2m",
+        level: ProbeLevel::PostKernel,
+        priority: ProbePriority::INTC,
+        probe_kinds: &[ProbeKind::Fdt {
+            compatibles: &["arm,gic-v2m-frame"],
+            on_probe: probe,
+        }],
+        params: &[],
+    }
+}
+
+/// An MSI frame with SPIs `base_spi..base_spi + used.len()`.
+pub struct Gicv2m {
+    doorbell: u64,
+    base_spi: u32,
+    used: Vec<bool>,
+}
+
+impl Gicv2m {
+    fn new(doorbell: u64, base_spi: u32, count: u32) -> Self {
+        Self {
+            doorbell,
+            base_spi,
+            used: vec![false; count as usize],
+        }
+    }
+
+    /// Slots of `count` free SPIs. With `contiguous` they are one block
+    /// aligned to `count` rounded up to a power of two.
+    fn find(&self, count: usize, contiguous: bool) -> Option<Vec<usize>> {
+        if contiguous {
+            let align = count.next_power_of_two();
+            return (0..self.used.len())
+                .step_by(align)
+                .find(|&start| {
+                    self.used
+                        .get(start..start + count)
+                        .is_some_and(|block| block.iter().all(|used| !used))
+                })
+                .map(|start| (start..start + count).collect());
+        }
+        let free: Vec<usize> = (0..self.used.len())
+            .filter(|&slot| !self.used[slot])
+            .take(count)
+            .collect();
+        (free.len() == count).then_some(free)
+    }
+}
+
+impl DriverGeneric for Gicv2m {
+    fn open(&mut self) -> Result<(), KError> {
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<(), KError> {
+        Ok(())
+    }
+}
+
+impl Interface for Gicv2m {
+    fn alloc_msi(
+        &mut self,
+        device_id: u32,
+        count: usize,
+        contiguous: bool,
+    ) -> Result<Vec<MsiVector>, KError> {
+        let slots = self.find(count, contiguous).ok_or(KError::NoMem)?;
+        let vectors = slots
+            .into_iter()
+            .map(|slot| {
+                self.used[slot] = true;
+                let spi = self.base_spi + slot as u32;
+                MsiVector {
+                    config: IrqConfig {
+                        irq: (spi as usize).into(),
+                        trigger: Trigger::EdgeRising,
+                        is_private: false,
+                    },
+                    message: MsiMessage {
+                        address: self.doorbell,
+                        data: spi,
+                    },
+                }
+            })
+            .collect();
+        debug!("GICv2m: {count} vectors for device {device_id:#x}");
+        Ok(vectors)
+    }
+
+    fn free_msi(&mut self, device_id: u32, irq: IrqId) {
+        let spi: usize = irq.into();
+        match spi
+            .checked_sub(self.base_spi as usize)
+            .and_then(|slot| self.used.get_mut(slot))
+        {
+            Some(used) => *used = false,
+            None => warn!("GICv2m: SPI {spi} of device {device_id:#x} is not ours"),
+        }
+    }
+}
+
+fn probe(info: FdtInfo<'_>, plat_dev: PlatformDevice) -> Result<(), OnProbeError> {
+    let reg = info
+        .mmio_ranges()?
+        .into_iter()
+        .next()
+        .ok_or_else(|| OnProbeError::other(format!("[{}] has no reg", info.path())))?;
+    let base = crate::osal::ioremap(reg.start, FRAME_SIZE)
+        .ok_or_else(|| OnProbeError::other(format!("map GICv2m {:#x} fail", reg.start)))?;
+    let typer = read(base, MSI_TYPER);
+
+    // The properties override a `MSI_TYPER` some implementations get wrong.
+    let prop = |name| {
+        info.node
+            .find_property(name)
+            .and_then(|prop| prop.u32_list().next())
+    };
+    let base_spi = prop("arm,msi-base-spi").unwrap_or((typer >> 16) & 0x3ff);
+    let count = prop("arm,msi-num-spis").unwrap_or(typer & 0x3ff);
+    if count == 0 {
+        return Err(OnProbeError::other(format!(
+            "[{}] has no SPIs",
+            info.path()
+        )));
+    }
+    debug!(
+        "GICv2m {:#x}: SPIs {base_spi}..{}",
+        reg.start,
+        base_spi + count
+    );
+
+    plat_dev.register(Intc::new(Gicv2m::new(
+        reg.start + MSI_SETSPI_NS,
+        base_spi,
+        count,
+    )));
+    Ok(())
+}
+
+fn read(base: NonNull<u8>, offset: usize) -> u32 {
+    unsafe { base.add(offset).cast::<u32>().read_volatile() }
+}
@@ -1,73 +1,298 @@
 use core::{
-    ops::{Deref, DerefMut},
+    ops::{Deref, DerefMut, Range},
     ptr::NonNull,
 };
 
 use ::pcie::*;
-use alloc::{collections::btree_set::BTreeSet, vec::Vec};
-use spin::{Mutex, Once};
+use alloc::{
+    collections::{btree_map::BTreeMap, btree_set::BTreeSet},
+    string::String,
+    vec::Vec,
+};
+use rdif_intc::Intc;
+use spin::{Mutex, MutexGuard};
+
+pub mod ecam;
+pub mod gicv2m;
 
-pub use ::pcie::{Endpoint, PciCapability, PcieGeneric};
-pub use rdif_pcie::{DriverGeneric, PciAddress, PciMem32, PciMem64, PcieController};
+pub use ::pcie::{
+    Endpoint, MsiError, PciCapability, PcieGeneric, PowerState, PowerStateError, ResetError,
+    SriovError,
+};
+pub use rdif_pcie::{
+    BarPolicy, DriverGeneric, PciAddress, PciIo, PciMem32, PciMem64, PcieController,
+};
 
 use crate::{
-    Descriptor, Device, PlatformDevice, ProbeError, get_list,
-    probe::OnProbeError,
+    Descriptor, Device, DeviceId, IrqConfig, PlatformDevice, ProbeError, get_list,
+    policy::ProbeTarget,
+    probe::{OnProbeError, ProbeFuture, fdt::FdtIrq, task},
     register::{DriverRegister, ProbeKind},
 };
 
-static PCIE: Once<Mutex<Vec<PcieEnumterator>>> = Once::new();
+static PCIE: Mutex<Vec<PcieEnumterator>> = Mutex::new(Vec::new());
+static MSI_MAPS: Mutex<BTreeMap<DeviceId, MsiMaps>> = Mutex::new(BTreeMap::new());
+static INTX_NODES: Mutex<BTreeMap<DeviceId, String>> = Mutex::new(BTreeMap::new());
 
 pub type FnOnProbe = fn(ep: &mut EndpointRc, plat_dev: PlatformDevice) -> Result<(), OnProbeError>;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
-struct Id {
-    vendor: u16,
-    device: u16,
+/// Async PCI probe. The endpoint moves into the future, which owns it until
+/// the probe completes.
+pub type FnOnProbeAsync = fn(ep: EndpointRc, plat_dev: PlatformDevice) -> ProbeFuture;
+
+/// One entry of `msi-map`: requester IDs `rid_base..rid_base + len` are
+/// sent to the MSI controller `parent` as device IDs from `msi_base`.
+#[derive(Debug, Clone, Copy)]
+pub struct MsiMap {
+    pub rid_base: u32,
+    pub parent: DeviceId,
+    pub msi_base: u32,
+    pub len: u32,
+}
+
+/// How the functions behind a host bridge reach their MSI controllers.
+#[derive(Debug, Clone)]
+pub struct MsiMaps {
+    /// Applied to the requester ID before lookup, `msi-map-mask`.
+    pub mask: u32,
+    pub maps: Vec<MsiMap>,
+}
+
+impl MsiMaps {
+    /// MSI controller and device ID for requester ID `rid`.
+    fn translate(&self, rid: u32) -> Option<(DeviceId, u32)> {
+        let rid = rid & self.mask;
+        self.maps
+            .iter()
+            .find(|map| rid.wrapping_sub(map.rid_base) < map.len)
+            .map(|map| (map.parent, rid - map.rid_base + map.msi_base))
+    }
+}
+
+/// Set the MSI routing of the functions behind the host bridge registered
+/// as `ctrl`. Host drivers call it from their probe.
+pub fn set_msi_maps(ctrl: DeviceId, maps: MsiMaps) {
+    MSI_MAPS.lock().insert(ctrl, maps);
+}
+
+/// Route the INTx of the functions behind the host bridge registered as
+/// `ctrl` through the `interrupt-map` of the FDT node at `path`. Host
+/// drivers call it from their probe.
+pub fn set_intx_node(ctrl: DeviceId, path: &str) {
+    INTX_NODES.lock().insert(ctrl, path.into());
 }
 
 pub fn new_driver_generic(mmio_base: NonNull<u8>) -> PcieController {
     PcieController::new(PcieGeneric::new(mmio_base))
 }
 
-fn pcie() -> &'static Mutex<Vec<PcieEnumterator>> {
-    PCIE.call_once(|| {
-        let ctrl_ls = get_list::<PcieController>();
-        let mut vec = Vec::new();
-        for ctrl in ctrl_ls.into_iter() {
-            {
-                let mut g = ctrl.lock().unwrap();
-                g.open().unwrap();
-            }
+/// The enumerators of all registered controllers. Controllers registered
+/// since the last call, e.g. by a later-probing FDT driver, are opened and
+/// added, those unregistered since are dropped.
+fn pcie() -> Result<MutexGuard<'static, Vec<PcieEnumterator>>, ProbeError> {
+    let mut pcie_ls = PCIE.lock();
+    let ctrl_ls = get_list::<PcieController>();
 
-            vec.push(PcieEnumterator {
-                ctrl,
-                probed: BTreeSet::new(),
-            });
+    pcie_ls.retain(|one| {
+        ctrl_ls
+            .iter()
+            .any(|ctrl| ctrl.descriptor().device_id() == one.ctrl.descriptor().device_id())
+    });
+
+    for ctrl in ctrl_ls {
+        let id = ctrl.descriptor().device_id();
+        if pcie_ls
+            .iter()
+            .any(|one| one.ctrl.descriptor().device_id() == id)
+        {
+            continue;
+        }
+        {
+            let mut g = ctrl.lock().unwrap();
+            g.open()?;
         }
-        Mutex::new(vec)
-    })
+        debug!("PCIe controller {id:?} added");
+
+        pcie_ls.push(PcieEnumterator {
+            ctrl,
+            functions: BTreeMap::new(),
+            probed: BTreeSet::new(),
+        });
+    }
+    Ok(pcie_ls)
 }
+
 pub(crate) fn probe_with(
     registers: &[DriverRegister],
     stop_if_fail: bool,
 ) -> Result<(), ProbeError> {
-    let mut pcie_ls = pcie().lock();
+    let mut pcie_ls = pcie()?;
+    for ctrl in pcie_ls.iter_mut() {
+        ctrl.probe(registers, stop_if_fail)?;
+    }
+    Ok(())
+}
+
+/// Drop the functions that no longer respond, then probe the ones that
+/// appeared since the last walk.
+pub(crate) fn rescan(registers: &[DriverRegister], stop_if_fail: bool) -> Result<(), ProbeError> {
+    let mut pcie_ls = pcie()?;
     for ctrl in pcie_ls.iter_mut() {
+        ctrl.remove_gone();
         ctrl.probe(registers, stop_if_fail)?;
     }
     Ok(())
 }
 
-pub struct EndpointRc(Option<Endpoint>);
+pub(crate) fn bind(register: &DriverRegister, address: PciAddress) -> Result<(), ProbeError> {
+    let on_probe = register
+        .probe_kinds
+        .iter()
+        .find_map(OnProbe::of)
+        .ok_or_else(|| ProbeError::ProbeKindMissing {
+            name: register.name.into(),
+            kind: "PCI",
+        })?;
+
+    let mut pcie_ls = pcie()?;
+    for ctrl in pcie_ls.iter_mut() {
+        if let Some(res) = ctrl.bind(register, on_probe, address) {
+            return res;
+        }
+    }
+    Err(ProbeError::TargetNotFound(format!("{address}")))
+}
+
+pub struct EndpointRc {
+    ep: Option<Endpoint>,
+    /// The controller the function is behind.
+    ctrl: DeviceId,
+    /// MSI controller and device ID the function's messages go to.
+    msi: Option<(DeviceId, u32)>,
+    intx: Option<FdtIrq>,
+}
 
 impl EndpointRc {
-    fn new(ep: Endpoint) -> Self {
-        Self(Some(ep))
+    fn new(ep: Endpoint, ctrl: DeviceId) -> Self {
+        let address = ep.address();
+        let rid = (address.bus() as u32) << 8
+            | (address.device() as u32) << 3
+            | address.function() as u32;
+        let msi = MSI_MAPS
+            .lock()
+            .get(&ctrl)
+            .and_then(|maps| maps.translate(rid));
+        let intx = intx(&ep, ctrl);
+        Self {
+            ep: Some(ep),
+            ctrl,
+            msi,
+            intx,
+        }
+    }
+
+    /// The function's INTx, swizzled up to the host bridge and resolved
+    /// through its `interrupt-map`. `None` if the function uses no pin or
+    /// the host bridge has no map.
+    pub fn intx(&self) -> Option<&FdtIrq> {
+        self.intx.as_ref()
+    }
+
+    /// CPU physical addresses of the ports of the I/O BAR at `slot`, through
+    /// the I/O window of the controller. On architectures without port I/O
+    /// they are device memory to map.
+    pub fn bar_io_cpu(&self, slot: u8) -> Option<Range<u64>> {
+        let ports = self.bar_io(slot)?;
+        let ctrl = crate::get::<PcieController>(self.ctrl).ok()?;
+        let start = ctrl.lock().ok()?.io_cpu_address(ports.start)?;
+        Some(start..start + (ports.end - ports.start) as u64)
     }
 
     pub fn take(&mut self) -> Endpoint {
-        self.0.take().unwrap()
+        self.ep.take().unwrap()
+    }
+
+    /// The MSI controller of the function, from the `msi-map` of its host
+    /// bridge.
+    pub fn msi_parent(&self) -> Option<DeviceId> {
+        self.msi.map(|(parent, _)| parent)
+    }
+
+    /// Enable MSI with `count` vectors from the function's MSI controller.
+    pub fn enable_msi(&mut self, count: usize) -> Result<Vec<IrqConfig>, OnProbeError> {
+        self.with_msi_parent(|ep, intc, device_id| ep.enable_msi(intc, device_id, count))
+    }
+
+    /// Enable MSI-X with `count` vectors from the function's MSI controller.
+    /// The vector table is mapped once per function through
+    /// [`Osal::ioremap`](crate::Osal::ioremap), which must map it.
+    pub fn enable_msix(&mut self, count: usize) -> Result<Vec<IrqConfig>, OnProbeError> {
+        self.with_msi_parent(|ep, intc, device_id| {
+            ep.enable_msix(intc, device_id, count, crate::osal::ioremap)
+        })
+    }
+
+    /// Disable MSI or MSI-X and return the vectors to the MSI controller.
+    pub fn disable_msi(&mut self) -> Result<(), OnProbeError> {
+        self.with_msi_parent(|ep, intc, _| ep.disable_msi(intc))
+    }
+
+    /// Reset the function with a Function Level Reset, sleeping through
+    /// [`Osal::sleep`](crate::Osal::sleep), [`ResetError::NoSleep`] if it
+    /// cannot.
+    pub fn function_level_reset(&mut self) -> Result<(), ResetError> {
+        self.deref_mut().function_level_reset(crate::osal::sleep)
+    }
+
+    /// Reset the function through the bridge above it, sleeping through
+    /// [`Osal::sleep`](crate::Osal::sleep), [`ResetError::NoSleep`] if it
+    /// cannot.
+    pub fn secondary_bus_reset(&mut self) -> Result<(), ResetError> {
+        self.deref_mut().secondary_bus_reset(crate::osal::sleep)
+    }
+
+    /// Move the function to `state`, sleeping through
+    /// [`Osal::sleep`](crate::Osal::sleep), [`PowerStateError::NoSleep`] if
+    /// it cannot.
+    pub fn set_power_state(&mut self, state: PowerState) -> Result<(), PowerStateError> {
+        self.deref_mut().set_power_state(state, crate::osal::sleep)
+    }
+
+    /// Enable `num_vfs` SR-IOV virtual functions, their BARs assigned from
+    /// the bus of the function. They are probed as endpoints of their own
+    /// once the current probe pass has gone through the bus.
+    pub fn enable_sriov(&mut self, num_vfs: u16) -> Result<(), OnProbeError> {
+        self.with_controller(|ctrl, ep| enable_vfs(ctrl, ep, num_vfs, crate::osal::sleep))
+    }
+
+    /// Disable the virtual functions. Their devices are removed by the next
+    /// [`pci_rescan`](crate::pci_rescan).
+    pub fn disable_sriov(&mut self) -> Result<(), OnProbeError> {
+        self.with_controller(|ctrl, ep| disable_vfs(ctrl, ep, crate::osal::sleep))
+    }
+
+    fn with_controller<T>(
+        &mut self,
+        f: impl FnOnce(&mut PcieController, &Endpoint) -> Result<T, SriovError>,
+    ) -> Result<T, OnProbeError> {
+        let mut ctrl = crate::get::<PcieController>(self.ctrl)
+            .and_then(|ctrl| ctrl.lock())
+            .map_err(|e| OnProbeError::other(format!("PCIe controller {:?}: {e}", self.ctrl)))?;
+        Ok(f(&mut ctrl, self)?)
+    }
+
+    fn with_msi_parent<T>(
+        &mut self,
+        f: impl FnOnce(&mut Endpoint, &mut dyn rdif_intc::Interface, u32) -> Result<T, MsiError>,
+    ) -> Result<T, OnProbeError> {
+        let (parent, device_id) = self.msi.ok_or_else(|| {
+            OnProbeError::other(format!("{} has no MSI controller", self.address()))
+        })?;
+        let intc = crate::get::<Intc>(parent).map_err(|e| OnProbeError::IrqParent(parent, e))?;
+        let mut intc = intc
+            .lock()
+            .map_err(|e| OnProbeError::IrqParent(parent, e))?;
+        Ok(f(self, &mut **intc, device_id)?)
     }
 }
 
@@ -75,82 +300,385 @@ impl Deref for EndpointRc {
     type Target = Endpoint;
 
     fn deref(&self) -> &Self::Target {
-        self.0.as_ref().unwrap()
+        self.ep.as_ref().unwrap()
     }
 }
 
 impl DerefMut for EndpointRc {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        self.0.as_mut().unwrap()
+        self.ep.as_mut().unwrap()
+    }
+}
+
+fn intx(ep: &Endpoint, ctrl: DeviceId) -> Option<FdtIrq> {
+    let (address, pin) = ep.intx_route()?;
+    let path = INTX_NODES.lock().get(&ctrl).cloned()?;
+    match crate::probe::fdt::pci_intx(&path, address, pin) {
+        Ok(irq) => Some(irq),
+        Err(e) => {
+            warn!("INTx of {}: {e}", ep.address());
+            None
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum OnProbe {
+    Sync(FnOnProbe),
+    Async(FnOnProbeAsync),
+}
+
+impl OnProbe {
+    fn of(kind: &ProbeKind) -> Option<Self> {
+        match *kind {
+            ProbeKind::Pci { on_probe } => Some(Self::Sync(on_probe)),
+            ProbeKind::PciAsync { on_probe } => Some(Self::Async(on_probe)),
+            _ => None,
+        }
     }
 }
 
+/// An async probe started but not yet polled.
+struct AsyncProbe {
+    address: PciAddress,
+    name: &'static str,
+    device_id: DeviceId,
+    /// Index of the register to try next if this one does not take the function.
+    next: usize,
+    future: ProbeFuture,
+}
+
+/// Outcome of trying the registers against one function.
+enum Probed {
+    Bound(DeviceId),
+    Pending(AsyncProbe),
+    Unbound,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+struct Id {
+    vendor: u16,
+    device: u16,
+}
+
 struct PcieEnumterator {
     ctrl: Device<PcieController>,
+    functions: BTreeMap<PciAddress, Function>,
+    /// IDs a driver was probed against, further functions with the same ID
+    /// are left alone. Virtual functions are not counted.
     probed: BTreeSet<Id>,
 }
 
+/// A function found by an earlier walk of the bus.
+struct Function {
+    id: Id,
+    /// Device registered by the driver bound to the function.
+    device_id: Option<DeviceId>,
+    /// Memory BARs assigned by us, as `(address, size)`.
+    bars: Vec<(u64, u64)>,
+    /// I/O BARs assigned by us, as `(port, size)`.
+    io_bars: Vec<(u32, u32)>,
+    /// The allocator the BARs were taken from.
+    owner: BarOwner,
+    /// The physical function of a virtual function. VFs read `0xffff`, they
+    /// are present while their PF has them enabled.
+    pf: Option<PciAddress>,
+}
+
+impl Function {
+    fn new(ep: &Endpoint, owner: BarOwner, bars_assigned: bool) -> Self {
+        let mut bars = Vec::new();
+        let mut io_bars = Vec::new();
+        if bars_assigned {
+            for (slot, bar) in ep.bars().into_iter().enumerate() {
+                match bar {
+                    Some(Bar::Memory32 { address, size, .. }) => {
+                        bars.push((address as _, size as _))
+                    }
+                    Some(Bar::Memory64 { address, size, .. }) => bars.push((address, size)),
+                    Some(Bar::Io { .. }) => {
+                        if let Some(ports) = ep.bar_io(slot as _) {
+                            io_bars.push((ports.start, ports.end - ports.start));
+                        }
+                    }
+                    None => {}
+                }
+            }
+        }
+        Self {
+            id: Id {
+                vendor: ep.vendor_id(),
+                device: ep.device_id(),
+            },
+            device_id: None,
+            bars,
+            io_bars,
+            owner,
+            pf: ep.physical_function(),
+        }
+    }
+}
+
 impl PcieEnumterator {
+    /// Probe the functions on the bus, then the virtual functions their
+    /// drivers enabled.
     fn probe(
         &mut self,
         registers: &[DriverRegister],
         stop_if_fail: bool,
     ) -> Result<(), ProbeError> {
-        let mut g = self.ctrl.lock().unwrap();
+        let eps = self.walk();
+        self.probe_all(eps, registers, stop_if_fail)?;
+        let vfs = self.walk_vfs();
+        self.probe_all(vfs, registers, stop_if_fail)
+    }
 
-        for ep in enumerate_by_controller(&mut g, None) {
-            debug!("PCIe endpiont: {}", ep);
-            match self.probe_one(ep, registers, stop_if_fail) {
-                Ok(_) => {} // Successfully probed, move to the next
-                Err(e) => {
+    /// Probe `eps`. Sync probes run in turn; async ones are collected and
+    /// run together, and the functions none of them took are tried against
+    /// the remaining registers.
+    fn probe_all(
+        &mut self,
+        eps: Vec<Endpoint>,
+        registers: &[DriverRegister],
+        stop_if_fail: bool,
+    ) -> Result<(), ProbeError> {
+        let mut eps: Vec<(Endpoint, usize)> = eps.into_iter().map(|ep| (ep, 0)).collect();
+        while !eps.is_empty() {
+            let mut pending = Vec::new();
+            for (ep, from) in eps {
+                debug!("PCIe endpiont: {}", ep);
+                let address = ep.address();
+                if let Some(source) = ep.bar_error() {
+                    let e = ProbeError::PciBar { address, source };
                     if stop_if_fail {
                         return Err(e);
-                    } else {
-                        warn!("Probe failed: {e}");
+                    }
+                    warn!("{e}");
+                    continue;
+                }
+                let Some(func) = self.functions.get(&address) else {
+                    continue;
+                };
+                if func.device_id.is_some() || (func.pf.is_none() && self.probed.contains(&func.id))
+                {
+                    continue;
+                }
+                match self.probe_one(ep, registers, from, stop_if_fail) {
+                    Ok(Probed::Bound(device_id)) => self.mark_probed(address, device_id),
+                    Ok(Probed::Pending(probe)) => pending.push(probe),
+                    Ok(Probed::Unbound) => {}
+                    Err(e) => {
+                        if stop_if_fail {
+                            return Err(e);
+                        } else {
+                            warn!("Probe failed: {e}");
+                        }
                     }
                 }
             }
+            eps = self.run_async(pending, stop_if_fail)?;
         }
 
         Ok(())
     }
 
+    /// Run `pending` concurrently through [`Osal::run_tasks`](crate::Osal::run_tasks)
+    /// and return the functions left unbound, each with the register to try next.
+    fn run_async(
+        &mut self,
+        pending: Vec<AsyncProbe>,
+        stop_if_fail: bool,
+    ) -> Result<Vec<(Endpoint, usize)>, ProbeError> {
+        let futures = pending
+            .into_iter()
+            .map(|probe| {
+                let meta = (probe.address, probe.name, probe.device_id, probe.next);
+                (meta, probe.future)
+            })
+            .collect();
+
+        let mut retry = BTreeMap::new();
+        for ((address, name, device_id, next), res) in task::run(futures) {
+            match res {
+                Ok(()) => {
+                    self.mark_probed(address, device_id);
+                    continue;
+                }
+                Err(OnProbeError::NotMatch) => {}
+                Err(e) => {
+                    if stop_if_fail {
+                        return Err(e.into());
+                    }
+                    warn!("Probe failed for [{name}]: {e}");
+                }
+            }
+            retry.insert(address, next);
+        }
+        if retry.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        // the endpoints were moved into the futures, walk again for new ones
+        let eps = self.walk();
+        let vfs = self.walk_vfs();
+        Ok(eps
+            .into_iter()
+            .chain(vfs)
+            .filter_map(|ep| {
+                let next = retry.remove(&ep.address())?;
+                Some((ep, next))
+            })
+            .collect())
+    }
+
+    /// Record the device a probe registered for the function at `address`.
+    fn mark_probed(&mut self, address: PciAddress, device_id: DeviceId) {
+        if let Some(func) = self.functions.get_mut(&address) {
+            func.device_id = Some(device_id);
+            if func.pf.is_none() {
+                self.probed.insert(func.id);
+            }
+        }
+    }
+
+    /// Enumerate the bus. Only functions not seen before get BARs assigned,
+    /// those already known keep theirs. Functions whose BARs could not be
+    /// placed are not remembered, so the next walk tries them again.
+    fn walk(&mut self) -> Vec<Endpoint> {
+        let mut g = self.ctrl.lock().unwrap();
+
+        let functions = &self.functions;
+        let eps: Vec<Endpoint> =
+            enumerate_by_controller_with(&mut g, None, |address| !functions.contains_key(&address))
+                .collect();
+
+        for ep in eps.iter().filter(|ep| ep.bar_error().is_none()) {
+            let owner = g.bar_owner(ep.address().bus());
+            // Behind a CardBus bridge, which gets no window, BARs are left as
+            // the firmware set them and owned by no allocator.
+            let bars_assigned = g.allocator(owner).is_some();
+            self.functions
+                .entry(ep.address())
+                .or_insert_with(|| Function::new(ep, owner, bars_assigned));
+        }
+        eps
+    }
+
+    /// The virtual functions of the physical functions found so far.
+    fn walk_vfs(&mut self) -> Vec<Endpoint> {
+        let mut g = self.ctrl.lock().unwrap();
+        let pfs: Vec<PciAddress> = self
+            .functions
+            .iter()
+            .filter(|(_, func)| func.pf.is_none())
+            .map(|(&address, _)| address)
+            .collect();
+
+        let vfs: Vec<Endpoint> = pfs
+            .into_iter()
+            .flat_map(|pf| virtual_functions(&mut g, pf))
+            .collect();
+        for vf in &vfs {
+            let owner = g.bar_owner(vf.address().bus());
+            self.functions
+                .entry(vf.address())
+                .or_insert_with(|| Function::new(vf, owner, false));
+        }
+        vfs
+    }
+
+    /// Forget functions whose config space reads `0xffff`, or VFs no longer
+    /// enabled, unregister their devices and return their BARs to the
+    /// allocator.
+    fn remove_gone(&mut self) {
+        let mut g = self.ctrl.lock().unwrap();
+
+        let mut vfs = BTreeMap::<PciAddress, Vec<PciAddress>>::new();
+        let mut gone = Vec::new();
+        for (&address, func) in &self.functions {
+            let present = match func.pf {
+                Some(pf) => vfs
+                    .entry(pf)
+                    .or_insert_with(|| {
+                        virtual_functions(&mut g, pf)
+                            .iter()
+                            .map(|vf| vf.address())
+                            .collect()
+                    })
+                    .contains(&address),
+                None => function_present(&mut g, address),
+            };
+            if !present {
+                gone.push(address);
+            }
+        }
+
+        for address in gone {
+            let Some(func) = self.functions.remove(&address) else {
+                continue;
+            };
+            info!("PCIe function {address} removed");
+            if func.pf.is_none() {
+                release_vfs(&mut g, address);
+            }
+
+            for (bar, size) in func.bars {
+                if !g.free_bar(func.owner, bar, size) {
+                    warn!("BAR {bar:#x} of {address} was not allocated");
+                }
+            }
+            for (port, size) in func.io_bars {
+                if !g.free_io_bar(func.owner, port, size) {
+                    warn!("I/O BAR {port:#x} of {address} was not allocated");
+                }
+            }
+            if let Some(id) = func.device_id {
+                self.probed.remove(&func.id);
+                crate::edit(|manager| manager.dev_container.remove(id));
+            }
+        }
+    }
+
+    /// Try `registers` in order from `from`, returning the id of the device
+    /// registered by the first driver that accepts the endpoint. An async
+    /// driver takes the endpoint, its probe is returned to be run later.
     fn probe_one(
         &mut self,
         endpoint: Endpoint,
         registers: &[DriverRegister],
+        from: usize,
         stop_if_fail: bool,
-    ) -> Result<(), ProbeError> {
-        let id = Id {
+    ) -> Result<Probed, ProbeError> {
+        let target = ProbeTarget::Pci {
+            address: endpoint.address(),
             vendor: endpoint.vendor_id(),
             device: endpoint.device_id(),
         };
-        if self.probed.contains(&id) {
-            return Ok(());
-        }
-
-        let mut endpoint = EndpointRc::new(endpoint);
+        let mut endpoint = EndpointRc::new(endpoint, self.ctrl.descriptor().device_id());
 
-        for register in registers {
-            let Some(pci_probe) = register.probe_kinds.iter().find_map(|probe| {
-                if let ProbeKind::Pci { on_probe } = probe {
-                    Some(on_probe)
-                } else {
-                    None
-                }
-            }) else {
+        for (i, register) in registers.iter().enumerate().skip(from) {
+            let Some(on_probe) = register.probe_kinds.iter().find_map(OnProbe::of) else {
                 continue;
             };
-            let mut desc = Descriptor::new();
-            desc.name = register.name;
-            desc.irq_parent = self.ctrl.descriptor().irq_parent;
-
-            let plat_dev = PlatformDevice::new(desc);
-            match (pci_probe)(&mut endpoint, plat_dev) {
-                Ok(_) => {
-                    self.probed.insert(id);
-                    return Ok(());
+            if !crate::is_bind_allowed(register.name, &target) {
+                continue;
+            }
+            let pci_probe = match on_probe {
+                OnProbe::Sync(on_probe) => on_probe,
+                OnProbe::Async(on_probe) => {
+                    let address = endpoint.address();
+                    let (device_id, future) = self.start_async(endpoint, register, on_probe);
+                    return Ok(Probed::Pending(AsyncProbe {
+                        address,
+                        name: register.name,
+                        device_id,
+                        next: i + 1,
+                        future,
+                    }));
                 }
+            };
+            match self.probe_endpoint(&mut endpoint, register, pci_probe) {
+                Ok(device_id) => return Ok(Probed::Bound(device_id)),
                 Err(e) => match e {
                     OnProbeError::NotMatch => continue,
                     e => {
@@ -163,6 +691,327 @@ impl PcieEnumterator {
             }
         }
 
-        Ok(())
+        Ok(Probed::Unbound)
+    }
+
+    /// Run `on_probe` against the endpoint at `address`, if it is behind this controller.
+    fn bind(
+        &mut self,
+        register: &DriverRegister,
+        on_probe: OnProbe,
+        address: PciAddress,
+    ) -> Option<Result<(), ProbeError>> {
+        if self
+            .functions
+            .get(&address)
+            .is_some_and(|func| func.device_id.is_some())
+        {
+            return Some(Err(ProbeError::TargetBound(format!("{address}"))));
+        }
+        let eps = self.walk();
+        let ep = eps
+            .into_iter()
+            .chain(self.walk_vfs())
+            .find(|ep| ep.address() == address)?;
+        if let Some(source) = ep.bar_error() {
+            return Some(Err(ProbeError::PciBar { address, source }));
+        }
+        let mut endpoint = EndpointRc::new(ep, self.ctrl.descriptor().device_id());
+        let res = match on_probe {
+            OnProbe::Sync(on_probe) => self.probe_endpoint(&mut endpoint, register, on_probe),
+            OnProbe::Async(on_probe) => {
+                let (device_id, future) = self.start_async(endpoint, register, on_probe);
+                let res = task::run(vec![((), future)]);
+                res.into_iter().next().unwrap().1.map(|_| device_id)
+            }
+        };
+        if let (Ok(device_id), Some(func)) = (&res, self.functions.get_mut(&address)) {
+            func.device_id = Some(*device_id);
+        }
+        Some(res.map(|_| ()).map_err(ProbeError::from))
+    }
+
+    fn probe_endpoint(
+        &self,
+        endpoint: &mut EndpointRc,
+        register: &DriverRegister,
+        on_probe: FnOnProbe,
+    ) -> Result<DeviceId, OnProbeError> {
+        let (device_id, plat_dev) = self.platform_device(endpoint, register);
+        (on_probe)(endpoint, plat_dev)?;
+        Ok(device_id)
+    }
+
+    /// Hand the endpoint to an async probe, returning the id of the device it
+    /// registers and the future to run.
+    fn start_async(
+        &self,
+        endpoint: EndpointRc,
+        register: &DriverRegister,
+        on_probe: FnOnProbeAsync,
+    ) -> (DeviceId, ProbeFuture) {
+        let (device_id, plat_dev) = self.platform_device(&endpoint, register);
+        (device_id, on_probe(endpoint, plat_dev))
+    }
+
+    fn platform_device(
+        &self,
+        endpoint: &EndpointRc,
+        register: &DriverRegister,
+    ) -> (DeviceId, PlatformDevice) {
+        let mut desc = Descriptor::new();
+        desc.name = register.name;
+        desc.irq_parent = match endpoint.intx() {
+            Some(intx) => Some(intx.irq_parent),
+            None => self.ctrl.descriptor().irq_parent,
+        };
+
+        let params = crate::driver_params(register.name, register.params);
+        let device_id = desc.device_id;
+        (device_id, PlatformDevice::new(desc, params))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use alloc::sync::Arc;
+    use std::sync::Mutex as StdMutex;
+
+    use rdif_pcie::{Interface, KError};
+
+    use super::*;
+    use crate::manager::DeviceContainer;
+
+    const DWORDS: usize = 0x40 / 4;
+
+    /// A type 0 header. Registers keep the bits not set in their writable
+    /// mask, everything past the header reads 0.
+    struct Header {
+        regs: [u32; DWORDS],
+        writable: [u32; DWORDS],
+    }
+
+    /// Headers by address.
+    #[derive(Clone, Default)]
+    struct ConfigSpace(Arc<StdMutex<BTreeMap<PciAddress, Header>>>);
+
+    impl ConfigSpace {
+        fn add(&self, device: u8, vendor_id: u16, device_id: u16) -> PciAddress {
+            self.add_on(0, device, vendor_id, device_id)
+        }
+
+        fn add_on(&self, bus: u8, device: u8, vendor_id: u16, device_id: u16) -> PciAddress {
+            let address = PciAddress::new(0, bus, device, 0);
+            let mut header = Header {
+                regs: [0; DWORDS],
+                writable: [0; DWORDS],
+            };
+            header.regs[0] = (device_id as u32) << 16 | vendor_id as u32;
+            header.regs[2] = 0x0200_0000;
+            // command
+            header.writable[1] = 0xffff;
+            self.0.lock().unwrap().insert(address, header);
+            address
+        }
+
+        /// Make the function at `address` a CardBus bridge, with writable bus
+        /// numbers.
+        fn cardbus(&self, address: PciAddress) {
+            let mut space = self.0.lock().unwrap();
+            let header = space.get_mut(&address).unwrap();
+            header.regs[2] = 0x0607_0000;
+            header.regs[3] = 2 << 16;
+            header.writable[6] = 0x00ff_ffff;
+        }
+
+        /// Give the function at `address` a 32-bit memory BAR of `size` at `slot`.
+        fn bar32(&self, address: PciAddress, slot: usize, size: u32) {
+            let mut space = self.0.lock().unwrap();
+            let header = space.get_mut(&address).unwrap();
+            header.writable[4 + slot] = !(size - 1);
+        }
+
+        fn bar(&self, address: PciAddress, slot: usize) -> Option<u32> {
+            let space = self.0.lock().unwrap();
+            Some(space.get(&address)?.regs[4 + slot])
+        }
+
+        fn remove(&self, address: PciAddress) {
+            self.0.lock().unwrap().remove(&address);
+        }
+    }
+
+    impl DriverGeneric for ConfigSpace {
+        fn open(&mut self) -> Result<(), KError> {
+            Ok(())
+        }
+
+        fn close(&mut self) -> Result<(), KError> {
+            Ok(())
+        }
+    }
+
+    impl Interface for ConfigSpace {
+        fn read(&mut self, address: PciAddress, offset: u16) -> u32 {
+            match self.0.lock().unwrap().get(&address) {
+                Some(header) => header.regs.get(offset as usize / 4).copied().unwrap_or(0),
+                None => !0,
+            }
+        }
+
+        fn write(&mut self, address: PciAddress, offset: u16, value: u32) {
+            let dw = offset as usize / 4;
+            if let Some(header) = self.0.lock().unwrap().get_mut(&address)
+                && dw < DWORDS
+            {
+                let mask = header.writable[dw];
+                header.regs[dw] = (header.regs[dw] & !mask) | (value & mask);
+            }
+        }
+    }
+
+    /// An enumerator over `ctrl`, kept in `devices`.
+    fn enumerator(ctrl: PcieController, devices: &mut DeviceContainer) -> PcieEnumterator {
+        crate::init_test_manager();
+        let desc = Descriptor::new();
+        let id = desc.device_id;
+        devices.insert(desc, ctrl);
+        PcieEnumterator {
+            ctrl: devices.get_typed(id).unwrap(),
+            functions: BTreeMap::new(),
+            probed: BTreeSet::new(),
+        }
+    }
+
+    #[test]
+    fn test_bind() {
+        static PROBED: Mutex<Vec<(&str, PciAddress)>> = Mutex::new(Vec::new());
+
+        fn probe_nic(ep: &mut EndpointRc, _dev: PlatformDevice) -> Result<(), OnProbeError> {
+            PROBED.lock().push(("nic", ep.address()));
+            Ok(())
+        }
+        fn probe_other(ep: &mut EndpointRc, _dev: PlatformDevice) -> Result<(), OnProbeError> {
+            PROBED.lock().push(("other", ep.address()));
+            Ok(())
+        }
+
+        let nic = DriverRegister {
+            name: "nic",
+            probe_kinds: &[ProbeKind::Pci {
+                on_probe: probe_nic,
+            }],
+            ..DriverRegister::EMPTY
+        };
+        let other = DriverRegister {
+            name: "other",
+            probe_kinds: &[ProbeKind::Pci {
+                on_probe: probe_other,
+            }],
+            ..DriverRegister::EMPTY
+        };
+        let space = ConfigSpace::default();
+        let a = space.add(1, 0x8086, 0x100e);
+        let b = space.add(2, 0x8086, 0x10d3);
+        let mut devices = DeviceContainer::default();
+        let mut ctrl = enumerator(PcieController::new(space.clone()), &mut devices);
+        let on_probe =
+            |register: &DriverRegister| register.probe_kinds.iter().find_map(OnProbe::of).unwrap();
+
+        assert!(matches!(
+            ctrl.bind(&other, on_probe(&other), a),
+            Some(Ok(()))
+        ));
+        assert!(matches!(
+            ctrl.bind(&nic, on_probe(&nic), a),
+            Some(Err(ProbeError::TargetBound(_)))
+        ));
+        let gone = PciAddress::new(0, 0, 3, 0);
+        assert!(ctrl.bind(&nic, on_probe(&nic), gone).is_none());
+
+        // the bound function is skipped, the other one is probed once
+        ctrl.probe(core::slice::from_ref(&nic), true).unwrap();
+        ctrl.probe(core::slice::from_ref(&nic), true).unwrap();
+
+        assert_eq!(*PROBED.lock(), [("other", a), ("nic", b)]);
+    }
+
+    #[test]
+    fn test_rescan() {
+        static PROBED: Mutex<Vec<PciAddress>> = Mutex::new(Vec::new());
+
+        fn probe_nic(ep: &mut EndpointRc, _dev: PlatformDevice) -> Result<(), OnProbeError> {
+            PROBED.lock().push(ep.address());
+            Ok(())
+        }
+
+        let nic = DriverRegister {
+            name: "rescan nic",
+            probe_kinds: &[ProbeKind::Pci {
+                on_probe: probe_nic,
+            }],
+            ..DriverRegister::EMPTY
+        };
+        let registers = core::slice::from_ref(&nic);
+        let space = ConfigSpace::default();
+        let a = space.add(1, 0x8086, 0x100e);
+        space.bar32(a, 0, 0x1000);
+        // same ID as the first function, left alone while that one is bound
+        let b = space.add(2, 0x8086, 0x100e);
+        space.bar32(b, 0, 0x1000);
+        let mut controller = PcieController::new(space.clone());
+        controller.set_mem32(
+            PciMem32 {
+                address: 0x1000_0000,
+                size: 0x2000,
+            },
+            false,
+        );
+        let mut devices = DeviceContainer::default();
+        let mut ctrl = enumerator(controller, &mut devices);
+
+        ctrl.probe(registers, true).unwrap();
+        assert_eq!(*PROBED.lock(), [a]);
+        assert_eq!(space.bar(a, 0), Some(0x1000_0000));
+        assert_eq!(space.bar(b, 0), Some(0x1000_1000));
+
+        // unplugged: the window is full until its BAR is freed
+        space.remove(a);
+        ctrl.remove_gone();
+        assert!(!ctrl.functions.contains_key(&a));
+        let c = space.add(3, 0x8086, 0x10d3);
+        space.bar32(c, 0, 0x1000);
+        ctrl.probe(registers, true).unwrap();
+
+        // its ID is free again, the second function binds
+        assert_eq!(*PROBED.lock(), [a, b, c]);
+        assert_eq!(space.bar(c, 0), Some(0x1000_0000));
+    }
+
+    #[test]
+    fn test_cardbus_bars_not_owned() {
+        let space = ConfigSpace::default();
+        let a = space.add(1, 0x8086, 0x100e);
+        space.bar32(a, 0, 0x1000);
+        let bridge = space.add(2, 0x104c, 0xac56);
+        space.cardbus(bridge);
+        let b = space.add_on(1, 0, 0x8086, 0x10d3);
+        space.bar32(b, 0, 0x1000);
+        let mut controller = PcieController::new(space.clone());
+        controller.set_mem32(
+            PciMem32 {
+                address: 0x1000_0000,
+                size: 0x2000,
+            },
+            false,
+        );
+        let mut devices = DeviceContainer::default();
+        let mut ctrl = enumerator(controller, &mut devices);
+
+        ctrl.walk();
+        assert_eq!(ctrl.functions[&a].bars, [(0x1000_0000, 0x1000)]);
+        assert!(ctrl.functions[&b].bars.is_empty());
     }
 }
@@ -0,0 +1,153 @@
+//! Async probe tasks, run concurrently through [`Osal::run_tasks`](crate::Osal::run_tasks).
+
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use core::{
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+
+use spin::Mutex;
+
+use super::OnProbeError;
+
+/// Future returned by an async `on_probe`.
+pub type ProbeFuture = Pin<Box<dyn Future<Output = Result<(), OnProbeError>> + Send>>;
+
+struct Slot(Option<Result<(), OnProbeError>>);
+
+/// One async probe. Completes with `()`, its result is collected by rdrive.
+pub struct ProbeTask {
+    future: ProbeFuture,
+    slot: Arc<Mutex<Slot>>,
+}
+
+impl ProbeTask {
+    /// Poll every task on the calling CPU until all of them are done.
+    pub fn block_on_all(tasks: Vec<ProbeTask>) {
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut pending = tasks;
+        while !pending.is_empty() {
+            pending.retain_mut(|task| Pin::new(task).poll(&mut cx).is_pending());
+            if !pending.is_empty() {
+                core::hint::spin_loop();
+            }
+        }
+    }
+}
+
+impl Future for ProbeTask {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        match self.future.as_mut().poll(cx) {
+            Poll::Ready(res) => {
+                self.slot.lock().0 = Some(res);
+                Poll::Ready(())
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// Run `futures` through the OSAL and return their results in the same order,
+/// each paired with its `meta`.
+pub(crate) fn run<M>(futures: Vec<(M, ProbeFuture)>) -> Vec<(M, Result<(), OnProbeError>)> {
+    if futures.is_empty() {
+        return Vec::new();
+    }
+
+    let mut metas = Vec::with_capacity(futures.len());
+    let mut slots = Vec::with_capacity(futures.len());
+    let mut tasks = Vec::with_capacity(futures.len());
+    for (meta, future) in futures {
+        let slot = Arc::new(Mutex::new(Slot(None)));
+        metas.push(meta);
+        slots.push(slot.clone());
+        tasks.push(ProbeTask { future, slot });
+    }
+
+    crate::osal::run_tasks(tasks);
+
+    metas
+        .into_iter()
+        .zip(slots)
+        .map(|(meta, slot)| {
+            let res =
+                slot.lock().0.take().unwrap_or_else(|| {
+                    Err(OnProbeError::other("probe task not run to completion"))
+                });
+            (meta, res)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    /// Pending `n` times before completing, with `NotMatch` if `not_match`.
+    struct Yield {
+        n: usize,
+        not_match: bool,
+    }
+
+    impl Future for Yield {
+        type Output = Result<(), OnProbeError>;
+
+        fn poll(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Self::Output> {
+            if self.n == 0 {
+                return Poll::Ready(if self.not_match {
+                    Err(OnProbeError::NotMatch)
+                } else {
+                    Ok(())
+                });
+            }
+            self.n -= 1;
+            Poll::Pending
+        }
+    }
+
+    #[test]
+    fn test_task_is_send() {
+        fn assert_send<T: Send>() {}
+        assert_send::<ProbeTask>();
+    }
+
+    #[test]
+    fn test_run_collects_in_order() {
+        static POLLED: AtomicUsize = AtomicUsize::new(0);
+
+        let futures: Vec<(usize, ProbeFuture)> = vec![
+            (
+                0,
+                Box::pin(Yield {
+                    n: 3,
+                    not_match: false,
+                }),
+            ),
+            (
+                1,
+                Box::pin(Yield {
+                    n: 0,
+                    not_match: true,
+                }),
+            ),
+            (
+                2,
+                Box::pin(async {
+                    POLLED.fetch_add(1, Ordering::SeqCst);
+                    Err(OnProbeError::other("fail"))
+                }),
+            ),
+        ];
+        let res = run(futures);
+
+        assert_eq!(POLLED.load(Ordering::SeqCst), 1);
+        assert_eq!(res.len(), 3);
+        assert!(matches!(res[0], (0, Ok(()))));
+        assert!(matches!(res[1], (1, Err(OnProbeError::NotMatch))));
+        assert!(matches!(res[2], (2, Err(OnProbeError::Other(_)))));
+    }
+}
@@ -1,6 +1,7 @@
 use alloc::vec::Vec;
 use core::ops::Deref;
 
+pub use crate::param::DriverParam;
 pub use crate::probe::fdt::FdtInfo;
 use crate::probe::{fdt, pci};
 pub use fdt_parser::Node;
@@ -45,6 +46,19 @@ pub struct DriverRegister {
     pub level: ProbeLevel,
     pub priority: ProbePriority,
     pub probe_kinds: &'static [ProbeKind],
+    /// Parameters accepted by the driver, with their defaults.
+    pub params: &'static [DriverParam],
+}
+
+impl DriverRegister {
+    /// Base value for struct update syntax, fields not set fall back to these.
+    pub const EMPTY: DriverRegister = DriverRegister {
+        name: "",
+        level: ProbeLevel::new(),
+        priority: ProbePriority::DEFAULT,
+        probe_kinds: &[],
+        params: &[],
+    };
 }
 
 unsafe impl Send for DriverRegister {}
@@ -55,9 +69,20 @@ pub enum ProbeKind {
         compatibles: &'static [&'static str],
         on_probe: fdt::FnOnProbe,
     },
+    /// FDT probe returning a future. Probes of drivers with the same
+    /// [`ProbePriority`] run concurrently through [`Osal::run_tasks`](crate::Osal::run_tasks).
+    FdtAsync {
+        compatibles: &'static [&'static str],
+        on_probe: fdt::FnOnProbeAsync,
+    },
     Pci {
         on_probe: pci::FnOnProbe,
     },
+    /// PCI probe returning a future. Probes of the functions behind one
+    /// controller run concurrently through [`Osal::run_tasks`](crate::Osal::run_tasks).
+    PciAsync {
+        on_probe: pci::FnOnProbeAsync,
+    },
 }
 
 #[repr(C)]
@@ -120,6 +145,10 @@ impl RegisterContainer {
         }
     }
 
+    pub fn find(&self, name: &str) -> Option<&DriverRegister> {
+        self.registers.iter().find(|one| one.name == name)
+    }
+
     pub fn unregistered(&self) -> Vec<DriverRegister> {
         self.registers.to_vec()
     }